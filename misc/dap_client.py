@@ -0,0 +1,104 @@
+#!/usr/bin/env python3
+"""
+Minimal DAP client to check `lameboy --dap <port>` without an editor.
+
+    lameboy --dap 4711 game.gb &
+    misc/dap_client.py 4711 --break Main --steps 3
+
+Connects, sets function breakpoints on labels (or source breakpoints with --source file.asm:line), continues to the
+first stop, then prints the stack, the registers and steps over a few instructions before disconnecting.
+"""
+import argparse
+import json
+import socket
+
+
+class Client:
+    def __init__(self, port):
+        self.sock = socket.create_connection(("127.0.0.1", port))
+        self.buf = b""
+        self.seq = 0
+        # Events read while waiting for a response.
+        self.events = []
+
+    def send(self, command, **arguments):
+        self.seq += 1
+        body = json.dumps({"seq": self.seq, "type": "request", "command": command, "arguments": arguments})
+        self.sock.sendall(b"Content-Length: %d\r\n\r\n%s" % (len(body), body.encode()))
+        return self.seq
+
+    def read(self):
+        while b"\r\n\r\n" not in self.buf:
+            self.buf += self.recv()
+        header, self.buf = self.buf.split(b"\r\n\r\n", 1)
+        length = int(header.split(b":")[1])
+        while len(self.buf) < length:
+            self.buf += self.recv()
+        body, self.buf = self.buf[:length], self.buf[length:]
+        message = json.loads(body)
+        print("<-", json.dumps(message)[:200])
+        return message
+
+    def recv(self):
+        data = self.sock.recv(4096)
+        if not data:
+            raise SystemExit("Connection closed")
+        return data
+
+    def request(self, command, **arguments):
+        seq = self.send(command, **arguments)
+        while True:
+            message = self.read()
+            if message["type"] == "response" and message["request_seq"] == seq:
+                if not message["success"]:
+                    raise SystemExit("%s failed: %s" % (command, message.get("message")))
+                return message.get("body")
+            if message["type"] == "event":
+                self.events.append(message)
+
+    def wait_event(self, event):
+        while True:
+            message = self.events.pop(0) if self.events else self.read()
+            if message["type"] == "event" and message["event"] == event:
+                return message["body"]
+
+
+def main():
+    parser = argparse.ArgumentParser()
+    parser.add_argument("port", type=int)
+    parser.add_argument("--break", dest="breaks", action="append", default=[], help="label")
+    parser.add_argument("--source", action="append", default=[], help="file.asm:line")
+    parser.add_argument("--steps", type=int, default=3)
+    args = parser.parse_args()
+
+    client = Client(args.port)
+    client.request("initialize", adapterID="lameboy")
+    client.request("launch", stopOnEntry=True)
+    client.wait_event("initialized")
+    client.request("setFunctionBreakpoints", breakpoints=[{"name": name} for name in args.breaks])
+    for source in args.source:
+        path, line = source.rsplit(":", 1)
+        client.request("setBreakpoints", source={"path": path}, breakpoints=[{"line": int(line)}])
+    client.request("configurationDone")
+    client.wait_event("stopped")
+
+    if args.breaks or args.source:
+        client.request("continue", threadId=1)
+        client.wait_event("stopped")
+
+    for _ in range(args.steps):
+        frames = client.request("stackTrace", threadId=1)["stackFrames"]
+        print("   ", " <- ".join(frame["name"] for frame in frames))
+        client.request("next", threadId=1)
+        client.wait_event("stopped")
+
+    scopes = client.request("scopes", frameId=0)["scopes"]
+    for variable in client.request("variables", variablesReference=scopes[0]["variablesReference"])["variables"]:
+        print("   ", variable["name"], variable["value"])
+    print("   ", client.request("evaluate", expression="[0xFF44]")["result"])
+
+    client.request("disconnect")
+
+
+if __name__ == "__main__":
+    main()
@@ -1,9 +1,12 @@
 use sdl2::audio::AudioCallback;
 use sdl2::audio::AudioDevice;
+use sdl2::audio::AudioDeviceLockGuard;
 use sdl2::audio::AudioFormatNum;
 use sdl2::audio::AudioSpecDesired;
 
 use crate::conf::*;
+use crate::error::EmulatorError;
+use crate::state::*;
 use crate::util::*;
 
 const NOISE_CHANNEL_DIVISORS: [u8; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
@@ -370,6 +373,126 @@ impl DmgChannels {
 
         new_ch1_period
     }
+
+    /**
+     * Only the parts the CPU can observe (NR52 flags, length timers and the CH1 sweep) are kept. Phase and envelope
+     * progress are audio-thread details and restart from wherever they are.
+     */
+    fn save_state(&self, w: &mut StateWriter) {
+        self.clock.save_state(w);
+
+        w.write_bool(self.ch1_pulse.active);
+        w.write_u8(self.ch1_pulse.length);
+        w.write_bool(self.ch1_pulse.length_enable);
+        w.write_u16(self.ch1_pulse.period);
+        w.write_f32(self.ch1_pulse.freq);
+        w.write_bool(self.ch1_pulse.sweep_direction_sub);
+        w.write_u8(self.ch1_pulse.sweep_step);
+        w.write_bool(self.ch1_pulse.sweep_counter.is_some());
+        if let Some(sweep_counter) = self.ch1_pulse.sweep_counter.as_ref() {
+            sweep_counter.save_state(w);
+        }
+
+        w.write_bool(self.ch2_pulse.active);
+        w.write_u8(self.ch2_pulse.length);
+        w.write_bool(self.ch2_pulse.length_enable);
+
+        w.write_bool(self.ch3_wave.active);
+        w.write_u8(self.ch3_wave.length);
+        w.write_bool(self.ch3_wave.length_enable);
+
+        w.write_bool(self.ch4_noise.active);
+        w.write_u8(self.ch4_noise.length);
+        w.write_bool(self.ch4_noise.length_enable);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.clock.load_state(r)?;
+
+        self.ch1_pulse.active = r.read_bool()?;
+        self.ch1_pulse.length = r.read_u8()?;
+        self.ch1_pulse.length_enable = r.read_bool()?;
+        self.ch1_pulse.period = r.read_u16()?;
+        self.ch1_pulse.freq = r.read_f32()?;
+        self.ch1_pulse.sweep_direction_sub = r.read_bool()?;
+        self.ch1_pulse.sweep_step = r.read_u8()?;
+        self.ch1_pulse.sweep_counter = if r.read_bool()? {
+            let mut sweep_counter = Counter::new(1);
+            sweep_counter.load_state(r)?;
+            Some(sweep_counter)
+        } else {
+            None
+        };
+
+        self.ch2_pulse.active = r.read_bool()?;
+        self.ch2_pulse.length = r.read_u8()?;
+        self.ch2_pulse.length_enable = r.read_bool()?;
+
+        self.ch3_wave.active = r.read_bool()?;
+        self.ch3_wave.length = r.read_u8()?;
+        self.ch3_wave.length_enable = r.read_bool()?;
+
+        self.ch4_noise.active = r.read_bool()?;
+        self.ch4_noise.length = r.read_u8()?;
+        self.ch4_noise.length_enable = r.read_bool()?;
+
+        Ok(())
+    }
+}
+
+/**
+ * Where the channels live: behind the SDL playback device, or owned directly when sound is disabled so no audio
+ * device (nor SDL) is needed.
+ */
+enum SoundOutput {
+    Device(AudioDevice<DmgChannels>),
+    Silent(Box<DmgChannels>),
+}
+
+enum SoundLock<'a> {
+    Device(AudioDeviceLockGuard<'a, DmgChannels>),
+    Silent(&'a mut DmgChannels),
+}
+
+impl std::ops::Deref for SoundLock<'_> {
+    type Target = DmgChannels;
+
+    fn deref(&self) -> &DmgChannels {
+        match self {
+            SoundLock::Device(guard) => guard,
+            SoundLock::Silent(channels) => channels,
+        }
+    }
+}
+
+impl std::ops::DerefMut for SoundLock<'_> {
+    fn deref_mut(&mut self) -> &mut DmgChannels {
+        match self {
+            SoundLock::Device(guard) => &mut *guard,
+            SoundLock::Silent(channels) => channels,
+        }
+    }
+}
+
+impl SoundOutput {
+    fn lock(&mut self) -> SoundLock<'_> {
+        match self {
+            SoundOutput::Device(device) => SoundLock::Device(device.lock()),
+            SoundOutput::Silent(channels) => SoundLock::Silent(channels),
+        }
+    }
+
+    fn pause(&self) {
+        if let SoundOutput::Device(device) = self {
+            device.pause();
+        }
+    }
+
+    fn resume(&self) {
+        if let SoundOutput::Device(device) = self {
+            device.resume();
+        }
+    }
 }
 
 impl AudioCallback for DmgChannels {
@@ -415,25 +538,30 @@ pub struct Apu {
     nr52: u8,
 
     wave_pattern_ram: [u8; 16],
-    sound_device: AudioDevice<DmgChannels>,
+    sound_device: SoundOutput,
 }
 
 impl Apu {
     pub fn new(disable_sound: bool) -> Self {
-        let sdl_context = sdl2::init().unwrap();
+        let sound_device = if disable_sound {
+            SoundOutput::Silent(Box::new(DmgChannels::new(44_100.0)))
+        } else {
+            let sdl_context = sdl2::init().unwrap();
 
-        let desired_spec = AudioSpecDesired {
-            freq: Some(44_100),
-            channels: Some(2),
-            samples: Some(256),
-        };
+            let desired_spec = AudioSpecDesired {
+                freq: Some(44_100),
+                channels: Some(2),
+                samples: Some(256),
+            };
 
-        let sound_device = sdl_context
-            .audio()
-            .unwrap()
-            .open_playback(None, &desired_spec, |spec| DmgChannels::new(spec.freq as _))
-            .unwrap();
-        sound_device.resume();
+            let sound_device = sdl_context
+                .audio()
+                .unwrap()
+                .open_playback(None, &desired_spec, |spec| DmgChannels::new(spec.freq as _))
+                .unwrap();
+            sound_device.resume();
+            SoundOutput::Device(sound_device)
+        };
 
         Apu {
             nr10: 0,
@@ -471,7 +599,7 @@ impl Apu {
         }
     }
 
-    pub fn write(&mut self, loc: u16, byte: u8) {
+    pub fn write(&mut self, loc: u16, byte: u8) -> Result<(), Error> {
         match loc {
             // TODO: Maybe we need this:
             // "During the All Sound OFF mode, each sound mode register cannot be set.)"
@@ -595,12 +723,35 @@ impl Apu {
                     // Make sure the turn-off mechanism works. If it is - error can be ignored.
                 }
             }
-            _ => unimplemented!("Apu chip loc write: {:#06X} not implemented", loc),
+            _ => return Err(EmulatorError::OpenBus(loc)),
         };
+
+        Ok(())
     }
 
+    /**
+     * Registers as stored, unused and write-only bits are set by the VM.
+     */
     pub fn read(&mut self, loc: u16) -> Result<u8, Error> {
         match loc {
+            MEM_LOC_NR10 => Ok(self.nr10),
+            MEM_LOC_NR11 => Ok(self.nr11),
+            MEM_LOC_NR12 => Ok(self.nr12),
+            MEM_LOC_NR13 => Ok(self.nr13),
+            MEM_LOC_NR14 => Ok(self.nr14),
+            MEM_LOC_NR21 => Ok(self.nr21),
+            MEM_LOC_NR22 => Ok(self.nr22),
+            MEM_LOC_NR23 => Ok(self.nr23),
+            MEM_LOC_NR24 => Ok(self.nr24),
+            MEM_LOC_NR30 => Ok(self.nr30),
+            MEM_LOC_NR31 => Ok(self.nr31),
+            MEM_LOC_NR32 => Ok(self.nr32),
+            MEM_LOC_NR33 => Ok(self.nr33),
+            MEM_LOC_NR34 => Ok(self.nr34),
+            MEM_LOC_NR41 => Ok(self.nr41),
+            MEM_LOC_NR42 => Ok(self.nr42),
+            MEM_LOC_NR43 => Ok(self.nr43),
+            MEM_LOC_NR44 => Ok(self.nr44),
             MEM_LOC_NR50 => Ok(self.nr50),
             MEM_LOC_NR51 => Ok(self.nr51),
             MEM_LOC_NR52 => {
@@ -623,10 +774,65 @@ impl Apu {
                     Ok(0xFF)
                 }
             }
-            _ => Err(format!("Apu chip read not implemented: {:#06X}", loc).into()),
+            _ => Err(EmulatorError::OpenBus(loc)),
         }
     }
 
+    /**
+     * Silences the output device while the emulation is not running at natural speed (eg rewinding).
+     */
+    pub fn set_muted(&mut self, is_muted: bool) {
+        if is_muted {
+            self.sound_device.pause();
+        } else {
+            self.sound_device.resume();
+        }
+    }
+
+    pub fn save_state(&mut self, w: &mut StateWriter) {
+        for reg in [
+            self.nr10, self.nr11, self.nr12, self.nr13, self.nr14, self.nr21, self.nr22, self.nr23,
+            self.nr24, self.nr30, self.nr31, self.nr32, self.nr33, self.nr34, self.nr41, self.nr42,
+            self.nr43, self.nr44, self.nr50, self.nr51, self.nr52,
+        ] {
+            w.write_u8(reg);
+        }
+        w.write_bytes(&self.wave_pattern_ram);
+
+        self.sound_device.lock().save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        for reg in [
+            &mut self.nr10,
+            &mut self.nr11,
+            &mut self.nr12,
+            &mut self.nr13,
+            &mut self.nr14,
+            &mut self.nr21,
+            &mut self.nr22,
+            &mut self.nr23,
+            &mut self.nr24,
+            &mut self.nr30,
+            &mut self.nr31,
+            &mut self.nr32,
+            &mut self.nr33,
+            &mut self.nr34,
+            &mut self.nr41,
+            &mut self.nr42,
+            &mut self.nr43,
+            &mut self.nr44,
+            &mut self.nr50,
+            &mut self.nr51,
+            &mut self.nr52,
+        ] {
+            *reg = r.read_u8()?;
+        }
+        r.read_bytes_into(&mut self.wave_pattern_ram)?;
+
+        self.sound_device.lock().load_state(r)
+    }
+
     fn audio_on(&self) -> bool {
         is_bit(self.nr52, 7)
     }
@@ -0,0 +1,166 @@
+/**
+ * Shadow call stack, following CALL / RST / interrupt pushes and RET / RETI pops, for the debugger.
+ *
+ * Frames are matched by SP: a RET pops the frame its return address was pushed for. A RET with SP above the top
+ * frame (eg. `PUSH HL` + `RET` as a jump) leaves the frames in place. Frames whose return address is left behind
+ * without a RET, by `LD SP`, `ADD SP` or popping the return address, are dropped and reported as a desync.
+ */
+use crate::vm::Interrupt;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CallFrame {
+    pub return_addr: u16,
+    pub entry: u16,
+    // SP after the return address has been pushed.
+    pub sp: u16,
+    // ROM bank at call time.
+    pub rom_bank: u8,
+    // Set for frames of interrupt handlers.
+    pub interrupt: Option<Interrupt>,
+}
+
+/**
+ * Frames dropped without a matching RET.
+ */
+#[derive(Clone, PartialEq, Debug)]
+pub struct StackDesync {
+    // Instruction that moved SP.
+    pub pc: u16,
+    pub sp: u16,
+    pub frames: Vec<CallFrame>,
+}
+
+#[derive(Default)]
+pub struct CallStack {
+    frames: Vec<CallFrame>,
+    // Most recent desync.
+    desync: Option<StackDesync>,
+}
+
+// Deeper than any real program, reached by code never returning from calls.
+const MAX_CALL_DEPTH: usize = 1024;
+
+impl CallStack {
+    pub fn new() -> CallStack {
+        CallStack::default()
+    }
+
+    pub fn push(&mut self, frame: CallFrame) {
+        if self.frames.len() >= MAX_CALL_DEPTH {
+            self.frames.remove(0);
+        }
+        self.frames.push(frame);
+    }
+
+    /**
+     * RET / RETI at `pc`, `sp` is the SP before the return address is popped.
+     */
+    pub fn pop(&mut self, sp: u16, pc: u16) {
+        self.unwind(sp, pc);
+        if self.frames.last().is_some_and(|frame| frame.sp == sp) {
+            self.frames.pop();
+        }
+    }
+
+    /**
+     * Drops the frames whose return address is above `sp`, called after every instruction.
+     */
+    pub fn unwind(&mut self, sp: u16, pc: u16) {
+        if self.frames.last().is_none_or(|frame| frame.sp >= sp) {
+            return;
+        }
+
+        let keep = self
+            .frames
+            .iter()
+            .take_while(|frame| frame.sp >= sp)
+            .count();
+        let frames = self.frames.split_off(keep);
+        log::debug!(
+            "Call stack desync at {:04X}: SP {:04X} left {} frame(s) without RET",
+            pc,
+            sp,
+            frames.len()
+        );
+        self.desync = Some(StackDesync { pc, sp, frames });
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.desync = None;
+    }
+
+    pub fn desync(&self) -> Option<&StackDesync> {
+        self.desync.as_ref()
+    }
+
+    /**
+     * Innermost frame first.
+     */
+    pub fn frames(&self) -> impl DoubleEndedIterator<Item = &CallFrame> {
+        self.frames.iter().rev()
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn top(&self) -> Option<&CallFrame> {
+        self.frames.last()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::call_stack::*;
+
+    fn frame(return_addr: u16, sp: u16) -> CallFrame {
+        CallFrame {
+            return_addr,
+            entry: 0,
+            sp,
+            rom_bank: 1,
+            interrupt: None,
+        }
+    }
+
+    #[test]
+    fn test_pop_matches_sp() {
+        let mut stack = CallStack::new();
+        stack.push(frame(0x0153, 0xFFFC));
+        stack.push(frame(0x0203, 0xFFFA));
+        assert_eq!(2, stack.depth());
+
+        // PUSH HL + RET as a jump.
+        stack.pop(0xFFF8, 0x0210);
+        assert_eq!(2, stack.depth());
+
+        stack.pop(0xFFFA, 0x0220);
+        assert_eq!(1, stack.depth());
+        assert_eq!(Some(0x0153), stack.top().map(|frame| frame.return_addr));
+        assert_eq!(None, stack.desync());
+    }
+
+    #[test]
+    fn test_unwind_reports_desync() {
+        let mut stack = CallStack::new();
+        stack.push(frame(0x0153, 0xFFFC));
+        stack.push(frame(0x0203, 0xFFFA));
+
+        stack.unwind(0xFFFA, 0x0300);
+        assert_eq!(None, stack.desync());
+
+        // POP HL of the return address.
+        stack.unwind(0xFFFC, 0x0301);
+        assert_eq!(1, stack.depth());
+        let desync = stack.desync().unwrap();
+        assert_eq!((0x0301, 1), (desync.pc, desync.frames.len()));
+        assert_eq!(0x0203, desync.frames[0].return_addr);
+
+        // RET with the outer frame left behind.
+        stack.push(frame(0x0400, 0xFFF8));
+        stack.pop(0xFFFE, 0x0500);
+        assert_eq!(0, stack.depth());
+        assert_eq!(2, stack.desync().unwrap().frames.len());
+    }
+}
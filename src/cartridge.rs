@@ -1,11 +1,19 @@
 use std::{fs::File, io::Read};
 
 use crate::conf::*;
+use crate::error::EmulatorError;
+use crate::state::*;
+use crate::util::crc32;
+
+// Title, type and sizes are read from 0x0100-0x014F.
+const CARTRIDGE_HEADER_END: usize = 0x0150;
 
 trait CartridgeController {
     fn set_register(&mut self, loc: u16, byte: u8);
     fn translate_addr(&self, virtual_loc: u16) -> PhysicalAddr;
     fn rom_bank_selector(&self) -> u8;
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error>;
 }
 
 enum RamGate {
@@ -15,6 +23,15 @@ enum RamGate {
     EnableRamAccess,
 }
 
+/**
+ * Cartridge byte behind a bus address.
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PhysicalLoc {
+    Rom(u32),
+    Ram(u32),
+}
+
 enum PhysicalAddr {
     Ok(u32),
     NotAccessible,
@@ -38,16 +55,20 @@ impl CartridgeController for RomOnly {
         if (MEM_AREA_ROM_BANK_0_START..=MEM_AREA_ROM_BANK_N_END).contains(&virtual_loc) {
             PhysicalAddr::Ok(virtual_loc as u32)
         } else {
-            unimplemented!(
-                "Unimplemented RomOnly read at virtual addr: {:#06X}",
-                virtual_loc
-            );
+            // No cartridge RAM.
+            PhysicalAddr::NotAccessible
         }
     }
 
     fn rom_bank_selector(&self) -> u8 {
         0
     }
+
+    fn save_state(&self, _w: &mut StateWriter) {}
+
+    fn load_state(&mut self, _r: &mut StateReader) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
 struct MBC1 {
@@ -90,14 +111,13 @@ impl CartridgeController for MBC1 {
             self.bank_1_reg = byte;
         } else if (0x4000..=0x5FFF).contains(&loc) {
             self.bank_2_reg = byte & 0b0011;
-        } else if (0x6000..=0x7FFF).contains(&loc) {
+        } else {
+            // 0x6000-0x7FFF
             self.bank2_mode_reg = if byte & 1 == 1 {
                 Bank2Mode::Mode1
             } else {
                 Bank2Mode::Mode0
             };
-        } else {
-            unimplemented!("MBC1 reg update not implemented for addr {:#06X}", loc);
         }
     }
 
@@ -140,10 +160,7 @@ impl CartridgeController for MBC1 {
                 RamGate::DisableRamAccess => PhysicalAddr::NotAccessible,
             }
         } else {
-            unimplemented!(
-                "MBC1 addr translation not implemented: {:#06X}",
-                virtual_loc
-            );
+            PhysicalAddr::NotAccessible
         }
     }
 
@@ -154,6 +171,29 @@ impl CartridgeController for MBC1 {
             self.bank_1_reg
         }
     }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(matches!(self.ram_gate_reg, RamGate::EnableRamAccess));
+        w.write_u8(self.bank_1_reg);
+        w.write_u8(self.bank_2_reg);
+        w.write_bool(matches!(self.bank2_mode_reg, Bank2Mode::Mode1));
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.ram_gate_reg = if r.read_bool()? {
+            RamGate::EnableRamAccess
+        } else {
+            RamGate::DisableRamAccess
+        };
+        self.bank_1_reg = r.read_u8()?;
+        self.bank_2_reg = r.read_u8()?;
+        self.bank2_mode_reg = if r.read_bool()? {
+            Bank2Mode::Mode1
+        } else {
+            Bank2Mode::Mode0
+        };
+        Ok(())
+    }
 }
 
 pub struct Cartridge {
@@ -169,6 +209,13 @@ impl Cartridge {
         let mut file = File::open(filename)?;
         file.read_to_end(&mut data)?;
 
+        if data.len() < CARTRIDGE_HEADER_END {
+            return Err(EmulatorError::BadRom(format!(
+                "{} bytes, shorter than the cartridge header",
+                data.len()
+            )));
+        }
+
         let mut ram_size = 0usize;
 
         let ctrl: Box<dyn CartridgeController + Send> = match data[0x0147] {
@@ -178,7 +225,10 @@ impl Cartridge {
                 let rom_bank_size = if rom_bank_size_bit <= 8 {
                     2 << rom_bank_size_bit
                 } else {
-                    panic!("Large cartridges are not yet implemented");
+                    return Err(EmulatorError::BadRom(format!(
+                        "ROM size code {:#04X} is not supported",
+                        rom_bank_size_bit
+                    )));
                 };
 
                 let ram_bank_size_bit = data[0x0149];
@@ -188,13 +238,18 @@ impl Cartridge {
                     0x03 => 4,
                     0x04 => 16,
                     0x05 => 8,
-                    _ => panic!("RAM bank size bit not implemented"),
+                    code => {
+                        return Err(EmulatorError::BadRom(format!(
+                            "RAM size code {:#04X} is not supported",
+                            code
+                        )))
+                    }
                 };
                 ram_size = ram_bank_size * 0x2000;
 
                 Box::new(MBC1::new(rom_bank_size, ram_bank_size))
             }
-            code => unimplemented!("Unimplemented cartridge type: {}", code),
+            code => return Err(EmulatorError::UnsupportedMapper(code)),
         };
 
         Ok(Cartridge {
@@ -210,21 +265,24 @@ impl Cartridge {
         } else if (MEM_AREA_ROM_BANK_N_START..=MEM_AREA_ROM_BANK_N_END).contains(&loc) {
             match self.ctrl.translate_addr(loc) {
                 PhysicalAddr::Ok(addr) => self.data[addr as usize],
-                _ => return Err("Error when loading data from BANK N".into()),
+                PhysicalAddr::NotAccessible => return Err(EmulatorError::OpenBus(loc)),
             }
         } else if (MEM_AREA_EXTERNAL_START..=MEM_AREA_EXTERNAL_END).contains(&loc) {
             match self.ctrl.translate_addr(loc) {
                 PhysicalAddr::Ok(addr) => self.ram[addr as usize],
-                PhysicalAddr::NotAccessible => return Err("Error when reading from RAM".into()),
+                PhysicalAddr::NotAccessible => return Err(EmulatorError::OpenBus(loc)),
             }
         } else {
-            return Err(format!("Unexpected catridge addr: {:#06X}", loc).into());
+            return Err(EmulatorError::BusFault(
+                loc,
+                "not a cartridge address".to_string(),
+            ));
         };
 
         Ok(byte)
     }
 
-    pub fn write(&mut self, loc: u16, byte: u8) {
+    pub fn write(&mut self, loc: u16, byte: u8) -> Result<(), Error> {
         if (0x0000..=0x7FFF).contains(&loc) {
             self.ctrl.set_register(loc, byte);
         } else if (MEM_AREA_EXTERNAL_START..=MEM_AREA_EXTERNAL_END).contains(&loc) {
@@ -232,17 +290,83 @@ impl Cartridge {
                 PhysicalAddr::Ok(addr) => {
                     self.ram[addr as usize] = byte;
                 }
-                PhysicalAddr::NotAccessible => (),
+                PhysicalAddr::NotAccessible => return Err(EmulatorError::OpenBus(loc)),
             };
         } else {
-            unimplemented!("Unimplemented write to cartridge: {:#06X}", loc);
+            return Err(EmulatorError::BusFault(
+                loc,
+                "not a cartridge address".to_string(),
+            ));
         }
+
+        Ok(())
+    }
+
+    /**
+     * Debugger write to the ROM or RAM byte mapped at `loc`, without touching the controller registers.
+     */
+    pub fn write_raw(&mut self, loc: u16, byte: u8) -> Result<(), Error> {
+        let mem = if (MEM_AREA_ROM_BANK_0_START..=MEM_AREA_ROM_BANK_N_END).contains(&loc) {
+            &mut self.data
+        } else if (MEM_AREA_EXTERNAL_START..=MEM_AREA_EXTERNAL_END).contains(&loc) {
+            &mut self.ram
+        } else {
+            return Err(format!("Unexpected catridge addr: {:#06X}", loc).into());
+        };
+
+        match self.ctrl.translate_addr(loc) {
+            PhysicalAddr::Ok(addr) => match mem.get_mut(addr as usize) {
+                Some(physical_byte) => *physical_byte = byte,
+                None => return Err(format!("No cartridge memory at {:#06X}", loc).into()),
+            },
+            PhysicalAddr::NotAccessible => return Err("Cartridge RAM is not accessible".into()),
+        }
+
+        Ok(())
+    }
+
+    /**
+     * ROM / RAM byte currently mapped at `loc`, none outside the cartridge areas or while RAM is disabled.
+     */
+    pub fn physical_loc(&self, loc: u16) -> Option<PhysicalLoc> {
+        let is_rom = (MEM_AREA_ROM_BANK_0_START..=MEM_AREA_ROM_BANK_N_END).contains(&loc);
+        if !is_rom && !(MEM_AREA_EXTERNAL_START..=MEM_AREA_EXTERNAL_END).contains(&loc) {
+            return None;
+        }
+
+        match self.ctrl.translate_addr(loc) {
+            PhysicalAddr::Ok(addr) if is_rom => Some(PhysicalLoc::Rom(addr)),
+            PhysicalAddr::Ok(addr) => Some(PhysicalLoc::Ram(addr)),
+            PhysicalAddr::NotAccessible => None,
+        }
+    }
+
+    pub fn rom_size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn ram_size(&self) -> usize {
+        self.ram.len()
     }
 
     pub fn rom_bank_selector(&self) -> u8 {
         self.ctrl.rom_bank_selector()
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.ram);
+        self.ctrl.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        r.read_bytes_into(&mut self.ram)?;
+        self.ctrl.load_state(r)
+    }
+
+    pub fn rom_hash(&self) -> u32 {
+        crc32(&self.data)
+    }
+
     pub fn get_title(&self) -> String {
         let mut out = String::new();
 
@@ -256,3 +380,40 @@ impl Cartridge {
         out
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::*;
+
+    fn load(data: &[u8]) -> Result<Cartridge, Error> {
+        let path = std::env::temp_dir().join(format!(
+            "lameboy_cartridge_{}_{}.gb",
+            std::process::id(),
+            data.len()
+        ));
+        std::fs::write(&path, data).unwrap();
+        let cartridge = Cartridge::new(path.to_string_lossy().into_owned());
+        std::fs::remove_file(&path).unwrap();
+        cartridge
+    }
+
+    #[test]
+    fn test_load_errors_and_open_bus() {
+        assert!(matches!(load(&[0; 0x100]), Err(EmulatorError::BadRom(_))));
+
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = 0x13;
+        assert!(matches!(
+            load(&rom),
+            Err(EmulatorError::UnsupportedMapper(0x13))
+        ));
+
+        rom[0x0147] = 0x00;
+        let mut cartridge = load(&rom).unwrap();
+        assert!(matches!(
+            cartridge.read(0xA000),
+            Err(EmulatorError::OpenBus(0xA000))
+        ));
+        assert!(matches!(cartridge.write(0x2000, 0x01), Ok(())));
+    }
+}
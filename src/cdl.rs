@@ -0,0 +1,120 @@
+/**
+ * Code/Data Logger: how each physical ROM and cartridge RAM byte has been used.
+ *
+ * The file is one flag byte per ROM byte followed by one flag byte per cartridge RAM byte. The low ROM bits follow
+ * the usual CDL layout (code 0x01, data 0x02) read by disassembly tools, the higher ones refine them. An existing
+ * file of the same size is loaded and added to.
+ */
+use std::fs::{read, write};
+
+use crate::conf::*;
+
+// ROM byte executed, as opcode or operand.
+pub const CDL_ROM_CODE: u8 = 0x01;
+// ROM byte read as data, or copied by DMA.
+pub const CDL_ROM_DATA: u8 = 0x02;
+pub const CDL_ROM_OPCODE: u8 = 0x10;
+pub const CDL_ROM_OPERAND: u8 = 0x20;
+pub const CDL_ROM_DMA: u8 = 0x40;
+
+pub const CDL_RAM_READ: u8 = 0x01;
+pub const CDL_RAM_WRITTEN: u8 = 0x02;
+
+pub struct CodeDataLog {
+    path: String,
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+}
+
+impl CodeDataLog {
+    pub fn new(path: String, rom_size: usize, ram_size: usize) -> Result<CodeDataLog, Error> {
+        let mut cdl = CodeDataLog {
+            path,
+            rom: vec![0; rom_size],
+            ram: vec![0; ram_size],
+        };
+
+        match read(&cdl.path) {
+            Ok(raw) if raw.len() == rom_size + ram_size => {
+                let (rom, ram) = raw.split_at(rom_size);
+                cdl.rom.copy_from_slice(rom);
+                cdl.ram.copy_from_slice(ram);
+                log::info!("CDL loaded: {}", cdl.path);
+            }
+            Ok(raw) => {
+                return Err(format!(
+                    "CDL {} is {} bytes, expected {} for this cartridge",
+                    cdl.path,
+                    raw.len(),
+                    rom_size + ram_size
+                )
+                .into())
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err.into()),
+        }
+
+        Ok(cdl)
+    }
+
+    pub fn mark_rom(&mut self, addr: u32, flags: u8) {
+        if let Some(byte) = self.rom.get_mut(addr as usize) {
+            *byte |= flags;
+        }
+    }
+
+    pub fn mark_ram(&mut self, addr: u32, flags: u8) {
+        if let Some(byte) = self.ram.get_mut(addr as usize) {
+            *byte |= flags;
+        }
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        write(
+            &self.path,
+            [self.rom.as_slice(), self.ram.as_slice()].concat(),
+        )?;
+
+        let count = |flags: &[u8], flag: u8| flags.iter().filter(|f| *f & flag != 0).count();
+        log::info!(
+            "CDL saved: {} (ROM: {} code / {} data / {} unused bytes)",
+            self.path,
+            count(&self.rom, CDL_ROM_CODE),
+            count(&self.rom, CDL_ROM_DATA),
+            self.rom.iter().filter(|f| **f == 0).count()
+        );
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cdl::*;
+
+    #[test]
+    fn test_save_and_reload() {
+        let path = std::env::temp_dir().join(format!("lameboy_cdl_{}.cdl", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+
+        let mut cdl = CodeDataLog::new(path.clone(), 8, 2).unwrap();
+        cdl.mark_rom(0, CDL_ROM_CODE | CDL_ROM_OPCODE);
+        cdl.mark_rom(1, CDL_ROM_CODE | CDL_ROM_OPERAND);
+        cdl.mark_rom(1, CDL_ROM_DATA);
+        cdl.mark_ram(1, CDL_RAM_WRITTEN);
+        // Beyond the ROM of the cartridge.
+        cdl.mark_rom(8, CDL_ROM_DATA);
+        cdl.save().unwrap();
+
+        let mut cdl = CodeDataLog::new(path.clone(), 8, 2).unwrap();
+        cdl.mark_rom(2, CDL_ROM_DATA | CDL_ROM_DMA);
+        cdl.save().unwrap();
+        assert_eq!(
+            vec![0x11, 0x23, 0x42, 0, 0, 0, 0, 0, 0, 0x02],
+            read(&path).unwrap()
+        );
+
+        assert!(CodeDataLog::new(path.clone(), 16, 0).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
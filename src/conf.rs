@@ -17,6 +17,9 @@ pub const CYCLE_PER_MCYCLE: u32 = 4;
 // Cycles per second.
 pub const CPU_HZ: u32 = 4194304;
 
+// CPU clocks of a full frame: 154 lines x 456 dots.
+pub const CLOCKS_PER_FRAME: u32 = 70224;
+
 // How many nanoseconds a CPU clock is (approx).
 pub const CPU_CLOCK_NANOS: f64 = 1_000_000_000 as f64 / CPU_HZ as f64;
 
@@ -121,20 +124,7 @@ pub const MEM_LOC_OBP0: u16 = 0xFF48;
 pub const MEM_LOC_OBP1: u16 = 0xFF49;
 pub const MEM_LOC_WY: u16 = 0xFF4A;
 pub const MEM_LOC_WX: u16 = 0xFF4B;
-pub const MEM_LOC_KEY1: u16 = 0xFF4D;
-pub const MEM_LOC_VBK: u16 = 0xFF4F;
 pub const MEM_LOC_BOOT_LOCK_REG: u16 = 0xFF50;
-pub const MEM_LOC_HDMA1: u16 = 0xFF51;
-pub const MEM_LOC_HDMA2: u16 = 0xFF52;
-pub const MEM_LOC_HDMA3: u16 = 0xFF53;
-pub const MEM_LOC_HDMA4: u16 = 0xFF54;
-pub const MEM_LOC_HDMA5: u16 = 0xFF55;
-pub const MEM_LOC_RP: u16 = 0xFF56;
-pub const MEM_LOC_BCPS: u16 = 0xFF68;
-pub const MEM_LOC_BCPD: u16 = 0xFF69;
-pub const MEM_LOC_OCPS: u16 = 0xFF6A;
-pub const MEM_LOC_OCPD: u16 = 0xFF6B;
-pub const MEM_LOC_SVBK: u16 = 0xFF70;
 
 pub const MEM_LOC_IE: u16 = 0xFFFF;
 
@@ -367,7 +357,7 @@ pub const OPCODE_NAME: [&str; 256] = [
     "RST 18H 1 16",
     "LDH (a8),A 2 12",
     "POP HL 1 12",
-    "LD (C),A 2 8",
+    "LD (C),A 1 8",
     "Invalid",
     "Invalid",
     "PUSH HL 1 16",
@@ -383,7 +373,7 @@ pub const OPCODE_NAME: [&str; 256] = [
     "RST 28H 1 16",
     "LDH A,(a8) 2 12",
     "POP AF 1 12",
-    "LD A,(C) 2 8",
+    "LD A,(C) 1 8",
     "DI 1 4",
     "Invalid",
     "PUSH AF 1 16",
@@ -718,8 +708,22 @@ pub const OPCODE_MCYCLE_PREFIX: [u8; 256] = [
     2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2,
 ];
 
+// Bits of the IO registers (0xFF00-0xFF7F) that read as 1 on DMG: unused bits, write-only registers and registers
+// with nothing behind them.
+#[rustfmt::skip]
+pub const IO_UNUSED_BITS: [u8; 0x80] = [
+    0xC0, 0x00, 0x7E, 0xFF, 0x00, 0x00, 0x00, 0xF8, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xE0,
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, 0xFF, 0x3F, 0x00, 0xFF, 0xBF, 0x7F, 0xFF, 0x9F, 0xFF, 0xBF, 0xFF,
+    0xFF, 0x00, 0x00, 0xBF, 0x00, 0x00, 0x70, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
 pub const VRAM_SIZE: usize = (MEM_AREA_OAM_END - MEM_AREA_VRAM_START + 1) as usize;
 pub const WRAM_SIZE: usize = (MEM_AREA_WRAM_END - MEM_AREA_WRAM_START + 1) as usize;
 pub const OAM_RAM_SIZE: usize = (MEM_AREA_OAM_END - MEM_AREA_OAM_START + 1) as usize;
 
-pub type Error = Box<dyn std::error::Error + Send + Sync>;
+pub type Error = crate::error::EmulatorError;
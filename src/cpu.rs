@@ -1,3 +1,5 @@
+use crate::conf::Error;
+use crate::state::*;
 use crate::util::*;
 
 macro_rules! make_fn_set_reg_hi {
@@ -286,4 +288,25 @@ impl Cpu {
             Reg::L => self.set_l(value),
         }
     }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.af);
+        w.write_u16(self.bc);
+        w.write_u16(self.de);
+        w.write_u16(self.hl);
+        w.write_u16(self.sp);
+        w.write_u16(self.pc);
+        w.write_u64(self.mcycle);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.af = r.read_u16()?;
+        self.bc = r.read_u16()?;
+        self.de = r.read_u16()?;
+        self.hl = r.read_u16()?;
+        self.sp = r.read_u16()?;
+        self.pc = r.read_u16()?;
+        self.mcycle = r.read_u64()?;
+        Ok(())
+    }
 }
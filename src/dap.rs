@@ -0,0 +1,786 @@
+/**
+ * Debug adapter protocol server for editors, with `--dap <port>`. Like the GDB server it replaces the stdin REPL,
+ * the emulation waiting stopped for a client.
+ *
+ * Launch and attach both attach to the running emulator, `stopOnEntry` (launch) keeps it stopped after
+ * configuration. Sources come from the assembly files under `sourceRoot` (defaults to the cartridge directory),
+ * mapped to addresses by label, see `SourceMap`. Requests made while running are served once per frame.
+ */
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::thread::spawn;
+
+use serde_json::{json, Value};
+
+use crate::conf::*;
+use crate::debugger::*;
+use crate::expr::{Expr, ExprContext, Variable, IO_REGISTERS};
+use crate::source_map::SourceMap;
+
+const THREAD_ID: i64 = 1;
+
+const SCOPE_REGISTERS: i64 = 1;
+const SCOPE_IO_REGISTERS: i64 = 2;
+const SCOPE_MEMORY: i64 = 3;
+// Plus the index in MEMORY_REGIONS.
+const SCOPE_MEMORY_REGION: i64 = 100;
+
+const MEMORY_REGIONS: [(&str, u16, u16); 8] = [
+    ("ROM0", MEM_AREA_ROM_BANK_0_START, MEM_AREA_ROM_BANK_0_END),
+    ("ROMX", MEM_AREA_ROM_BANK_N_START, MEM_AREA_ROM_BANK_N_END),
+    ("VRAM", MEM_AREA_VRAM_START, MEM_AREA_VRAM_END),
+    ("SRAM", MEM_AREA_EXTERNAL_START, MEM_AREA_EXTERNAL_END),
+    ("WRAM", MEM_AREA_WRAM_START, MEM_AREA_WRAM_END),
+    ("OAM", MEM_AREA_OAM_START, MEM_AREA_OAM_END),
+    ("IO", MEM_AREA_IO_START, MEM_AREA_IO_END),
+    ("HRAM", MEM_AREA_HRAM_START, MEM_AREA_HRAM_END),
+];
+
+const REGISTERS: [(&str, Variable); 6] = [
+    ("AF", Variable::AF),
+    ("BC", Variable::BC),
+    ("DE", Variable::DE),
+    ("HL", Variable::HL),
+    ("SP", Variable::SP),
+    ("PC", Variable::PC),
+];
+
+const FLAGS: [(&str, Variable); 4] = [
+    ("Z", Variable::FlagZ),
+    ("N", Variable::FlagN),
+    ("H", Variable::FlagH),
+    ("C", Variable::FlagC),
+];
+
+/**
+ * Access to the emulated machine. Registers and memory are read as expression variables.
+ */
+pub trait DapTarget: ExprContext {
+    /**
+     * Address + ROM bank of each frame, innermost (PC) first.
+     */
+    fn stack(&self) -> Vec<(u16, u8)>;
+    fn call_depth(&self) -> usize;
+    fn debugger(&mut self) -> &mut Debugger;
+}
+
+/**
+ * Takes the first complete `Content-Length` framed message out of `buf`.
+ */
+fn take_message(buf: &mut Vec<u8>) -> Option<Result<Value, Error>> {
+    let header_end = buf.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let header = String::from_utf8_lossy(&buf[..header_end]).into_owned();
+    let len = header.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.eq_ignore_ascii_case("Content-Length")
+            .then(|| value.trim().parse::<usize>().ok())
+            .flatten()
+    });
+
+    let len = match len {
+        Some(len) => len,
+        None => {
+            buf.drain(..header_end + 4);
+            return Some(Err("Missing Content-Length".into()));
+        }
+    };
+    if buf.len() < header_end + 4 + len {
+        return None;
+    }
+
+    let body = buf
+        .drain(..header_end + 4 + len)
+        .skip(header_end + 4)
+        .collect::<Vec<u8>>();
+    Some(serde_json::from_slice(&body).map_err(Error::from))
+}
+
+fn hex_value(value: i64, digits: usize) -> Value {
+    format!("0x{:0width$X}", value, width = digits).into()
+}
+
+fn variable(name: &str, value: Value, variables_reference: i64) -> Value {
+    json!({
+        "name": name,
+        "value": value,
+        "variablesReference": variables_reference,
+    })
+}
+
+struct Connection {
+    stream: TcpStream,
+    inputs: Receiver<Value>,
+}
+
+pub struct DapServer {
+    listener: TcpListener,
+    connection: Option<Connection>,
+    breakpoint_flag: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+    seq: i64,
+    source_root: PathBuf,
+    source_map: SourceMap,
+    // Breakpoint addresses set by source path, replaced on each `setBreakpoints`.
+    source_breakpoints: HashMap<String, Vec<u16>>,
+    function_breakpoints: Vec<u16>,
+    stop_on_entry: bool,
+    // Reason of the stopped event owed for the last resume.
+    resume_reason: Option<&'static str>,
+}
+
+impl DapServer {
+    /**
+     * `breakpoint_flag` is the debugger's, raised on `pause`.
+     */
+    pub fn new(
+        port: u16,
+        breakpoint_flag: Arc<AtomicBool>,
+        source_root: PathBuf,
+    ) -> Result<DapServer, Error> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        Ok(DapServer {
+            listener,
+            connection: None,
+            breakpoint_flag,
+            paused: Arc::new(AtomicBool::new(false)),
+            seq: 0,
+            source_root,
+            source_map: SourceMap::new(),
+            source_breakpoints: HashMap::new(),
+            function_breakpoints: vec![],
+            stop_on_entry: true,
+            resume_reason: None,
+        })
+    }
+
+    fn accept(&mut self) -> Result<(), Error> {
+        log::info!("Waiting for DAP client on {}", self.listener.local_addr()?);
+        let (stream, peer) = self.listener.accept()?;
+        stream.set_nodelay(true)?;
+        log::info!("DAP client connected from {}", peer);
+
+        let (sender, inputs) = channel();
+        let mut reader = stream.try_clone()?;
+        let breakpoint_flag = self.breakpoint_flag.clone();
+        let paused = self.paused.clone();
+        spawn(move || {
+            let mut buf = vec![];
+            let mut chunk = [0u8; 4096];
+            while let Ok(len @ 1..) = reader.read(&mut chunk) {
+                buf.extend_from_slice(&chunk[..len]);
+                while let Some(message) = take_message(&mut buf) {
+                    match message {
+                        Ok(message) => {
+                            if message["command"].as_str() == Some("pause") {
+                                paused.store(true, Ordering::Relaxed);
+                                breakpoint_flag.store(true, Ordering::Relaxed);
+                            }
+                            if sender.send(message).is_err() {
+                                return;
+                            }
+                        }
+                        Err(err) => log::warn!("Invalid DAP message: {}", err),
+                    }
+                }
+            }
+        });
+
+        self.connection = Some(Connection { stream, inputs });
+        self.resume_reason = None;
+        Ok(())
+    }
+
+    fn send(&mut self, mut message: Value) -> Result<(), Error> {
+        self.seq += 1;
+        message["seq"] = self.seq.into();
+        let body = message.to_string();
+
+        if let Some(connection) = self.connection.as_mut() {
+            let packet = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+            connection.stream.write_all(packet.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn send_event(&mut self, event: &str, body: Value) -> Result<(), Error> {
+        self.send(json!({
+            "type": "event",
+            "event": event,
+            "body": body,
+        }))
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) -> Result<(), Error> {
+        let mut message = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => message["body"] = body,
+            Err(err) => message["message"] = err.into(),
+        }
+        self.send(message)
+    }
+
+    fn send_stopped(&mut self, reason: &str) -> Result<(), Error> {
+        self.send_event(
+            "stopped",
+            json!({
+                "reason": reason,
+                "threadId": THREAD_ID,
+                "allThreadsStopped": true,
+            }),
+        )
+    }
+
+    /**
+     * Serves the client while the VM is stopped, until it resumes or disconnects. Waits for a client if needed.
+     */
+    pub fn serve(&mut self, target: &mut dyn DapTarget) -> Result<DebugCmd, Error> {
+        if self.connection.is_none() {
+            self.accept()?;
+        }
+
+        if let Some(reason) = self.resume_reason.take() {
+            let reason = if self.paused.swap(false, Ordering::Relaxed) {
+                "pause"
+            } else if target.debugger().take_watchpoint_hit().is_some() {
+                "data breakpoint"
+            } else if let Some(event) = target.debugger().take_event_hit() {
+                match event {
+                    DebugEvent::IllegalOpcode(_) => "exception",
+                    _ => "breakpoint",
+                }
+            } else {
+                reason
+            };
+            self.send_stopped(reason)?;
+        }
+
+        loop {
+            let input = self
+                .connection
+                .as_ref()
+                .map(|connection| connection.inputs.recv());
+            match input {
+                Some(Ok(request)) => {
+                    if let Some(cmd) = self.handle(&request, target)? {
+                        return Ok(cmd);
+                    }
+                }
+                _ => {
+                    log::info!("DAP client disconnected");
+                    self.connection = None;
+                    target.debugger().clear_steps_and_continue();
+                    return Ok(DebugCmd::Continue);
+                }
+            }
+        }
+    }
+
+    /**
+     * Serves the requests made while running, called once per frame.
+     */
+    pub fn poll(&mut self, target: &mut dyn DapTarget) -> Result<(), Error> {
+        loop {
+            let input = match self.connection.as_ref() {
+                Some(connection) => connection.inputs.try_recv(),
+                None => return Ok(()),
+            };
+            match input {
+                Ok(request) => {
+                    // Resuming while running changes nothing, a pause has raised the breakpoint flag.
+                    self.handle(&request, target)?;
+                }
+                Err(std::sync::mpsc::TryRecvError::Empty) => return Ok(()),
+                Err(std::sync::mpsc::TryRecvError::Disconnected) => {
+                    log::info!("DAP client disconnected");
+                    self.connection = None;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn handle(
+        &mut self,
+        request: &Value,
+        target: &mut dyn DapTarget,
+    ) -> Result<Option<DebugCmd>, Error> {
+        let args = &request["arguments"];
+        let command = request["command"].as_str().unwrap_or("");
+        let mut cmd = None;
+
+        let result = match command {
+            "initialize" => {
+                self.respond(
+                    request,
+                    Ok(json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsFunctionBreakpoints": true,
+                        "supportsConditionalBreakpoints": true,
+                        "supportsHitConditionalBreakpoints": true,
+                        "supportsEvaluateForHovers": true,
+                    })),
+                )?;
+                self.send_event("initialized", json!({}))?;
+                return Ok(None);
+            }
+            "launch" | "attach" => {
+                if let Some(root) = args["sourceRoot"].as_str() {
+                    self.source_root = PathBuf::from(root);
+                }
+                self.stop_on_entry =
+                    command == "attach" || args["stopOnEntry"].as_bool() != Some(false);
+                self.source_map = SourceMap::scan(&self.source_root, target.debugger().symbols());
+                Ok(Value::Null)
+            }
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    self.respond(request, Ok(Value::Null))?;
+                    self.send_stopped("entry")?;
+                    return Ok(None);
+                }
+                target.debugger().clear_steps_and_continue();
+                cmd = Some(DebugCmd::Continue);
+                Ok(Value::Null)
+            }
+            "setBreakpoints" => Ok(self.set_source_breakpoints(args, target)),
+            "setFunctionBreakpoints" => Ok(self.set_function_breakpoints(args, target)),
+            "setExceptionBreakpoints" => Ok(json!({ "breakpoints": [] })),
+            "threads" => Ok(json!({
+                "threads": [{ "id": THREAD_ID, "name": "SM83" }],
+            })),
+            "stackTrace" => Ok(self.stack_trace(target)),
+            "scopes" => {
+                let scope = |name: &str, reference: i64| {
+                    json!({
+                        "name": name,
+                        "variablesReference": reference,
+                        "expensive": reference == SCOPE_MEMORY,
+                    })
+                };
+                Ok(json!({
+                    "scopes": [
+                        scope("Registers", SCOPE_REGISTERS),
+                        scope("IO registers", SCOPE_IO_REGISTERS),
+                        scope("Memory", SCOPE_MEMORY),
+                    ],
+                }))
+            }
+            "variables" => {
+                let reference = args["variablesReference"].as_i64().unwrap_or(0);
+                Ok(json!({ "variables": self.variables(reference, target) }))
+            }
+            "evaluate" => {
+                let expression = args["expression"].as_str().unwrap_or("");
+                match Expr::parse(expression) {
+                    Ok(expr) => {
+                        let value = expr.eval(target);
+                        Ok(json!({
+                            "result": format!("0x{:X} ({})", value, value),
+                            "variablesReference": 0,
+                        }))
+                    }
+                    Err(err) => Err(err.to_string()),
+                }
+            }
+            "continue" => {
+                target.debugger().clear_steps_and_continue();
+                self.resume_reason = Some("breakpoint");
+                cmd = Some(DebugCmd::Continue);
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" | "stepIn" | "stepOut" => {
+                let depth = target.call_depth();
+                match command {
+                    "next" => target.debugger().set_run_until(RunUntil::CallDepth(depth)),
+                    "stepOut" if depth > 0 => target
+                        .debugger()
+                        .set_run_until(RunUntil::CallDepth(depth - 1)),
+                    _ => target.debugger().set_step_by_step(),
+                }
+                self.resume_reason = Some("step");
+                cmd = Some(DebugCmd::Step);
+                Ok(Value::Null)
+            }
+            "pause" => {
+                // Already stopped when served here, unless polled while running.
+                if self.resume_reason.is_none() {
+                    self.paused.store(false, Ordering::Relaxed);
+                    self.breakpoint_flag.store(false, Ordering::Relaxed);
+                    self.respond(request, Ok(Value::Null))?;
+                    self.send_stopped("pause")?;
+                    return Ok(None);
+                }
+                Ok(Value::Null)
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(Value::Null))?;
+                log::info!("DAP client detached");
+                self.connection = None;
+                target.debugger().clear_steps_and_continue();
+                return Ok(Some(DebugCmd::Continue));
+            }
+            _ => Err(format!("Unsupported request: {}", command)),
+        };
+
+        self.respond(request, result)?;
+        Ok(cmd)
+    }
+
+    /**
+     * `condition` / `hitCondition` of a breakpoint, in the debugger syntax.
+     */
+    fn condition(breakpoint: &Value) -> Result<Condition, Error> {
+        let mut parts = vec![];
+        if let Some(hit) = breakpoint["hitCondition"].as_str() {
+            parts.extend(["hit", hit.trim()]);
+        }
+        if let Some(condition) = breakpoint["condition"].as_str() {
+            parts.push("if");
+            parts.extend(condition.split_whitespace());
+        }
+        Condition::parse(&parts)
+    }
+
+    fn set_source_breakpoints(&mut self, args: &Value, target: &mut dyn DapTarget) -> Value {
+        let path = args["source"]["path"].as_str().unwrap_or("").to_string();
+        for pc in self.source_breakpoints.remove(&path).unwrap_or_default() {
+            target.debugger().remove_breakpoint(pc);
+        }
+
+        let mut pcs = vec![];
+        let breakpoints = args["breakpoints"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|breakpoint| {
+                let line = breakpoint["line"].as_i64().unwrap_or(0) as usize;
+                let location = self.source_map.resolve_line(Path::new(&path), line);
+                match (location, DapServer::condition(breakpoint)) {
+                    (Some((label_line, bank, pc)), Ok(condition)) => {
+                        let bank = (MEM_AREA_ROM_BANK_N_START..=MEM_AREA_ROM_BANK_N_END)
+                            .contains(&pc)
+                            .then_some(bank);
+                        target
+                            .debugger()
+                            .add_conditional_breakpoint(pc, bank, condition);
+                        pcs.push(pc);
+                        json!({ "verified": true, "line": label_line })
+                    }
+                    (None, _) => json!({
+                        "verified": false,
+                        "message": "No label at or before this line",
+                    }),
+                    (_, Err(err)) => json!({
+                        "verified": false,
+                        "message": err.to_string(),
+                    }),
+                }
+            })
+            .collect::<Vec<_>>();
+        self.source_breakpoints.insert(path, pcs);
+
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn set_function_breakpoints(&mut self, args: &Value, target: &mut dyn DapTarget) -> Value {
+        for pc in std::mem::take(&mut self.function_breakpoints) {
+            target.debugger().remove_breakpoint(pc);
+        }
+
+        let rom_bank = target.variable(Variable::RomBank) as u8;
+        let breakpoints = args["breakpoints"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|breakpoint| {
+                let name = breakpoint["name"].as_str().unwrap_or("");
+                let debugger = target.debugger();
+                match debugger
+                    .resolve(name, rom_bank)
+                    .and_then(|(bank, pc)| Ok((bank, pc, DapServer::condition(breakpoint)?)))
+                {
+                    Ok((bank, pc, condition)) => {
+                        debugger.add_conditional_breakpoint(pc, bank, condition);
+                        self.function_breakpoints.push(pc);
+                        json!({ "verified": true })
+                    }
+                    Err(err) => json!({
+                        "verified": false,
+                        "message": err.to_string(),
+                    }),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn stack_trace(&mut self, target: &mut dyn DapTarget) -> Value {
+        let frames = target
+            .stack()
+            .into_iter()
+            .enumerate()
+            .map(|(i, (addr, rom_bank))| {
+                let symbols = target.debugger().symbols();
+                let label = symbols.describe(addr, rom_bank);
+                let mut frame = json!({
+                    "id": i,
+                    "name": label.clone().unwrap_or_else(|| format!("{:04X}", addr)),
+                    "instructionPointerReference": format!("0x{:04X}", addr),
+                    "column": 1,
+                    "line": 0,
+                });
+                if let Some((path, line)) =
+                    label.and_then(|label| self.source_map.locate(symbols, &label))
+                {
+                    let name = path
+                        .file_name()
+                        .map(|name| name.to_string_lossy().into_owned())
+                        .unwrap_or_default();
+                    frame["line"] = line.into();
+                    frame["source"] = json!({
+                        "name": name,
+                        "path": path.to_string_lossy(),
+                    });
+                }
+                frame
+            })
+            .collect::<Vec<_>>();
+
+        let total = frames.len();
+        json!({
+            "stackFrames": frames,
+            "totalFrames": total,
+        })
+    }
+
+    fn variables(&self, reference: i64, target: &mut dyn DapTarget) -> Vec<Value> {
+        match reference {
+            SCOPE_REGISTERS => {
+                let mut variables = REGISTERS
+                    .iter()
+                    .map(|(name, v)| variable(name, hex_value(target.variable(*v), 4), 0))
+                    .collect::<Vec<_>>();
+                variables.extend(
+                    FLAGS
+                        .iter()
+                        .map(|(name, v)| variable(name, target.variable(*v).into(), 0)),
+                );
+                variables
+            }
+            SCOPE_IO_REGISTERS => IO_REGISTERS
+                .iter()
+                .map(|(name, loc)| variable(name, hex_value(target.read_memory(*loc) as i64, 2), 0))
+                .collect(),
+            SCOPE_MEMORY => MEMORY_REGIONS
+                .iter()
+                .enumerate()
+                .map(|(i, (name, from, to))| {
+                    let range = format!("{:04X}-{:04X}", from, to);
+                    variable(name, range.into(), SCOPE_MEMORY_REGION + i as i64)
+                })
+                .collect(),
+            _ => {
+                let region = (reference - SCOPE_MEMORY_REGION) as usize;
+                let (_, from, to) = match MEMORY_REGIONS.get(region) {
+                    Some(region) => *region,
+                    None => return vec![],
+                };
+                (from as u32..=to as u32)
+                    .step_by(16)
+                    .map(|row| {
+                        let bytes = (row..=(row + 15).min(to as u32))
+                            .map(|addr| format!("{:02X}", target.read_memory(addr as u16)))
+                            .collect::<Vec<_>>()
+                            .join(" ");
+                        variable(&format!("{:04X}", row), bytes.into(), 0)
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dap::*;
+    use crate::expr::NoExprContext;
+    use crate::symbols::Symbols;
+
+    struct TestTarget {
+        debugger: Debugger,
+    }
+
+    impl ExprContext for TestTarget {
+        fn variable(&mut self, variable: Variable) -> i64 {
+            match variable {
+                Variable::PC => 0x0150,
+                Variable::FlagZ => 1,
+                _ => 0,
+            }
+        }
+
+        fn read_memory(&mut self, loc: u16) -> u8 {
+            loc as u8
+        }
+    }
+
+    impl DapTarget for TestTarget {
+        fn stack(&self) -> Vec<(u16, u8)> {
+            vec![(0x0150, 1)]
+        }
+
+        fn call_depth(&self) -> usize {
+            0
+        }
+
+        fn debugger(&mut self) -> &mut Debugger {
+            &mut self.debugger
+        }
+    }
+
+    /**
+     * Server launched on a source directory with `main.asm`, `Main` at 0x0150 and `Main.loop` at 0x0158.
+     */
+    fn launched_server(target: &mut TestTarget) -> DapServer {
+        let dir = std::env::temp_dir().join(format!("lameboy_dap_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("main.asm"),
+            "; header\nMain::\n\tld a, 1\n.loop:\n\tjr .loop\n",
+        )
+        .unwrap();
+        target
+            .debugger
+            .set_symbols(Symbols::parse("00:0150 Main\n00:0158 Main.loop\n").unwrap());
+
+        let mut server = DapServer::new(0, Arc::new(AtomicBool::new(false)), dir.clone()).unwrap();
+        let launch = json!({ "seq": 1, "command": "launch", "arguments": { "stopOnEntry": true } });
+        assert!(server.handle(&launch, target).unwrap().is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+        server
+    }
+
+    #[test]
+    fn test_take_message() {
+        let mut buf =
+            b"Content-Length: 13\r\n\r\n{\"seq\": 1}   Content-Length: 2\r\n\r\n{".to_vec();
+
+        let message = take_message(&mut buf).unwrap().unwrap();
+        assert_eq!(Some(1), message["seq"].as_i64());
+        assert!(take_message(&mut buf).is_none());
+
+        buf.push(b'}');
+        assert_eq!(json!({}), take_message(&mut buf).unwrap().unwrap());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_source_breakpoints() {
+        let mut target = TestTarget {
+            debugger: Debugger::new(Arc::new(AtomicBool::new(false))),
+        };
+        let mut server = launched_server(&mut target);
+        let path = server.source_root.join("main.asm");
+
+        let args = json!({
+            "source": { "path": path },
+            "breakpoints": [
+                { "line": 3 },
+                { "line": 5, "hitCondition": "2" },
+                { "line": 1 },
+                { "line": 3, "condition": "A ==" },
+            ],
+        });
+        let result = server.set_source_breakpoints(&args, &mut target);
+        let breakpoints = result["breakpoints"].as_array().unwrap();
+        // Lines move to the label they belong to.
+        assert_eq!(json!({ "verified": true, "line": 2 }), breakpoints[0]);
+        assert_eq!(json!({ "verified": true, "line": 4 }), breakpoints[1]);
+        assert_eq!(Some(false), breakpoints[2]["verified"].as_bool());
+        assert_eq!(Some(false), breakpoints[3]["verified"].as_bool());
+
+        let debugger = &mut target.debugger;
+        assert!(debugger.should_stop(0x0150, 0, &mut NoExprContext));
+        assert!(!debugger.should_stop(0x0158, 0, &mut NoExprContext));
+        assert!(debugger.should_stop(0x0158, 0, &mut NoExprContext));
+
+        // Setting the breakpoints of a file again replaces them.
+        let args = json!({ "source": { "path": path }, "breakpoints": [] });
+        server.set_source_breakpoints(&args, &mut target);
+        assert!(!target.debugger.should_stop(0x0150, 0, &mut NoExprContext));
+    }
+
+    #[test]
+    fn test_condition() {
+        let breakpoint = json!({ "hitCondition": " 3 ", "condition": "A == 0 && FZ" });
+        let mut condition = DapServer::condition(&breakpoint).unwrap();
+        let mut debugger = Debugger::new(Arc::new(AtomicBool::new(false)));
+        let mut ctx = TestTarget {
+            debugger: Debugger::new(Arc::new(AtomicBool::new(false))),
+        };
+        debugger.add_conditional_breakpoint(0x0150, None, condition);
+        assert!(!debugger.should_stop(0x0150, 0, &mut ctx));
+        assert!(!debugger.should_stop(0x0150, 0, &mut ctx));
+        assert!(debugger.should_stop(0x0150, 0, &mut ctx));
+        // FZ reads 0 without a machine.
+        assert!(!debugger.should_stop(0x0150, 0, &mut NoExprContext));
+
+        condition = DapServer::condition(&json!({ "hitCondition": "2" })).unwrap();
+        debugger.add_conditional_breakpoint(0x0200, None, condition);
+        assert!(!debugger.should_stop(0x0200, 0, &mut NoExprContext));
+        assert!(debugger.should_stop(0x0200, 0, &mut NoExprContext));
+
+        assert!(DapServer::condition(&json!({ "hitCondition": "twice" })).is_err());
+        assert!(DapServer::condition(&json!({ "condition": "A ==" })).is_err());
+    }
+
+    #[test]
+    fn test_variables() {
+        let mut target = TestTarget {
+            debugger: Debugger::new(Arc::new(AtomicBool::new(false))),
+        };
+        let server = DapServer::new(0, Arc::new(AtomicBool::new(false)), PathBuf::new()).unwrap();
+
+        let registers = server.variables(SCOPE_REGISTERS, &mut target);
+        assert_eq!(REGISTERS.len() + FLAGS.len(), registers.len());
+        assert_eq!(json!("0x0150"), registers[5]["value"]);
+        assert_eq!(json!(1), registers[6]["value"]);
+
+        let regions = server.variables(SCOPE_MEMORY, &mut target);
+        assert_eq!(json!("FF80-FFFE"), regions[7]["value"]);
+        assert_eq!(
+            json!(SCOPE_MEMORY_REGION + 7),
+            regions[7]["variablesReference"]
+        );
+
+        // HRAM rows of 16 bytes, the last one cut at 0xFFFE.
+        let rows = server.variables(SCOPE_MEMORY_REGION + 7, &mut target);
+        assert_eq!(8, rows.len());
+        assert_eq!(
+            variable(
+                "FF80",
+                "80 81 82 83 84 85 86 87 88 89 8A 8B 8C 8D 8E 8F".into(),
+                0
+            ),
+            rows[0]
+        );
+        assert_eq!(
+            json!("F0 F1 F2 F3 F4 F5 F6 F7 F8 F9 FA FB FC FD FE"),
+            rows[7]["value"]
+        );
+        assert!(server
+            .variables(SCOPE_MEMORY_REGION + 8, &mut target)
+            .is_empty());
+    }
+}
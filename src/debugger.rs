@@ -5,6 +5,11 @@ use std::sync::{
 
 use log::info;
 
+use crate::conf::*;
+use crate::expr::{parse_variable, Expr, ExprContext, Variable};
+use crate::symbols::Symbols;
+use crate::vm::Interrupt;
+
 pub enum DebugCmd {
     Quit,
     Continue,
@@ -13,15 +18,303 @@ pub enum DebugCmd {
     PrintMemory(u16, usize),
     PrintOpHistory,
     PrintOam,
+    // From address (PC when not set) + instruction count.
+    Disassemble(Option<u16>, usize),
+    PrintCallStack,
+    PrintProfile,
+    StepOver,
+    StepOut,
+    RunTo(Option<u8>, u16),
+    SetVariable(Variable, u16),
+    // From address + bytes + raw (patches ROM / RAM instead of setting MBC registers).
+    WriteMemory(u16, Vec<u8>, bool),
+}
+
+pub const MEMORY_VIEW_SIZE: usize = 0x100;
+
+/**
+ * Memory page of the imgui hex view. Edits are queued by the UI and applied by the VM.
+ */
+#[derive(Default)]
+pub struct MemoryView {
+    pub from: u16,
+    pub bytes: Vec<u8>,
+    pub raw: bool,
+    pub edits: Vec<(u16, u8)>,
+}
+
+/**
+ * Temporary stop of step over / step out / run-to, dropped on any stop.
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RunUntil {
+    // Shadow call stack at most this deep.
+    CallDepth(usize),
+    Address(Option<u8>, u16),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+/**
+ * Optional condition and hit count of a breakpoint or watchpoint. Only hits where the condition holds are counted.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Condition {
+    // Source + parsed expression.
+    expr: Option<(String, Expr)>,
+    // Stops from this hit on, 0 and 1 stop on every hit.
+    hit_target: u32,
+    hits: u32,
+}
+
+impl Condition {
+    /**
+     * Trailing `[hit <n>] [if <expr>]` parts of a breakpoint command.
+     */
+    pub fn parse(parts: &[&str]) -> Result<Condition, Error> {
+        let mut condition = Condition::default();
+        let mut parts = parts;
+
+        if parts.first() == Some(&"hit") {
+            condition.hit_target = parts
+                .get(1)
+                .and_then(|n| n.parse::<u32>().ok())
+                .ok_or("Invalid hit count")?;
+            parts = &parts[2..];
+        }
+
+        if parts.first() == Some(&"if") {
+            let raw = parts[1..].join(" ");
+            let expr = Expr::parse(&raw)?;
+            condition.expr = Some((raw, expr));
+        } else if !parts.is_empty() {
+            return Err(format!("Unexpected: {}", parts.join(" ")).into());
+        }
+
+        Ok(condition)
+    }
+
+    fn is_conditional(&self) -> bool {
+        self.expr.is_some() || self.hit_target > 1
+    }
+
+    fn check(&mut self, ctx: &mut dyn ExprContext) -> bool {
+        if let Some((_, expr)) = self.expr.as_ref() {
+            if !expr.is_true(ctx) {
+                return false;
+            }
+        }
+
+        self.hits += 1;
+        self.hits >= self.hit_target
+    }
+
+    fn describe(&self) -> String {
+        let mut out = String::new();
+        if self.hit_target > 1 {
+            out.push_str(&format!(" hit {}/{}", self.hits, self.hit_target));
+        }
+        if let Some((raw, _)) = self.expr.as_ref() {
+            out.push_str(&format!(" if {}", raw));
+        }
+        out
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Breakpoint {
+    pub pc: u16,
+    // Switchable ROM bank, when set by label.
+    pub bank: Option<u8>,
+    pub condition: Condition,
+}
+
+/**
+ * Memory watchpoint on an inclusive address range, optionally only for a given value.
+ */
+#[derive(Clone, Debug)]
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    pub from: u16,
+    pub to: u16,
+    pub value: Option<u8>,
+    pub condition: Condition,
+}
+
+impl Watchpoint {
+    fn matches(&self, kind: WatchKind, loc: u16, byte: u8) -> bool {
+        (self.kind == WatchKind::Access || self.kind == kind)
+            && (self.from..=self.to).contains(&loc)
+            && self.value.is_none_or(|value| value == byte)
+    }
+
+    /**
+     * `<addr>[-<addr>] [value] [hit <n>] [if <expr>]`, addresses in hex or as labels.
+     */
+    fn parse(
+        kind: WatchKind,
+        parts: &[&str],
+        resolve: &dyn Fn(&str) -> Result<u16, Error>,
+    ) -> Result<Watchpoint, Error> {
+        let range = parts.first().ok_or("Missing address")?;
+        let (from, to) = match range.split_once('-') {
+            Some((from, to)) => (resolve(from)?, resolve(to)?),
+            None => {
+                let loc = resolve(range)?;
+                (loc, loc)
+            }
+        };
+        if from > to {
+            return Err("Invalid address range".into());
+        }
+
+        let mut parts = &parts[1..];
+        let value = match parts.first() {
+            Some(value) if *value != "hit" && *value != "if" => {
+                parts = &parts[1..];
+                Some(u8::from_str_radix(value, 16)?)
+            }
+            _ => None,
+        };
+
+        Ok(Watchpoint {
+            kind,
+            from,
+            to,
+            value,
+            condition: Condition::parse(parts)?,
+        })
+    }
+}
+
+/**
+ * Execution event reported by the VM, checked against the event breakpoints.
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DebugEvent {
+    InterruptService(Interrupt),
+    // Register + byte.
+    IoWrite(u16, u8),
+    // New bank of the MBC.
+    RomBank(u8),
+    Halt,
+    Stop,
+    // Opcode about to execute.
+    IllegalOpcode(u8),
+}
+
+impl DebugEvent {
+    pub fn describe(&self) -> String {
+        match self {
+            DebugEvent::InterruptService(interrupt) => {
+                format!("{:?} interrupt serviced", interrupt)
+            }
+            DebugEvent::IoWrite(loc, byte) => format!("IO write {:04X} = {:02X}", loc, byte),
+            DebugEvent::RomBank(bank) => format!("ROM bank switched to {:02X}", bank),
+            DebugEvent::Halt => "HALT".to_string(),
+            DebugEvent::Stop => "STOP".to_string(),
+            DebugEvent::IllegalOpcode(op) => format!("Illegal opcode {:02X}", op),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EventBreakpoint {
+    // Any interrupt when not set.
+    Interrupt(Option<Interrupt>),
+    // Inclusive register range.
+    IoWrite(u16, u16),
+    RomBank,
+    // HALT and STOP entry.
+    Halt,
+    IllegalOpcode,
+}
+
+impl EventBreakpoint {
+    fn matches(&self, event: DebugEvent) -> bool {
+        match (self, event) {
+            (EventBreakpoint::Interrupt(target), DebugEvent::InterruptService(interrupt)) => {
+                target.is_none_or(|target| target == interrupt)
+            }
+            (EventBreakpoint::IoWrite(from, to), DebugEvent::IoWrite(loc, _)) => {
+                (*from..=*to).contains(&loc)
+            }
+            (EventBreakpoint::RomBank, DebugEvent::RomBank(_)) => true,
+            (EventBreakpoint::Halt, DebugEvent::Halt | DebugEvent::Stop) => true,
+            (EventBreakpoint::IllegalOpcode, DebugEvent::IllegalOpcode(_)) => true,
+            _ => false,
+        }
+    }
+
+    /**
+     * `int [vblank|lcd|timer|serial|joypad]`, `io <reg>[-<reg>]`, `bank`, `halt` or `illegal`.
+     */
+    fn parse(
+        parts: &[&str],
+        resolve: &dyn Fn(&str) -> Result<u16, Error>,
+    ) -> Result<EventBreakpoint, Error> {
+        match parts {
+            ["int"] => Ok(EventBreakpoint::Interrupt(None)),
+            ["int", name] => Interrupt::parse(name)
+                .map(|interrupt| EventBreakpoint::Interrupt(Some(interrupt)))
+                .ok_or_else(|| format!("Unknown interrupt: {}", name).into()),
+            ["io", range] => {
+                let (from, to) = match range.split_once('-') {
+                    Some((from, to)) => (resolve(from)?, resolve(to)?),
+                    None => {
+                        let loc = resolve(range)?;
+                        (loc, loc)
+                    }
+                };
+                if from > to || from < MEM_AREA_IO_START {
+                    return Err("Invalid IO register range".into());
+                }
+                Ok(EventBreakpoint::IoWrite(from, to))
+            }
+            ["bank"] => Ok(EventBreakpoint::RomBank),
+            ["halt"] => Ok(EventBreakpoint::Halt),
+            ["illegal"] => Ok(EventBreakpoint::IllegalOpcode),
+            _ => Err(format!("Unknown event: {}", parts.join(" ")).into()),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            EventBreakpoint::Interrupt(Some(interrupt)) => format!("int {:?}", interrupt),
+            EventBreakpoint::Interrupt(None) => "int".to_string(),
+            EventBreakpoint::IoWrite(from, to) if from == to => format!("io {:04X}", from),
+            EventBreakpoint::IoWrite(from, to) => format!("io {:04X}-{:04X}", from, to),
+            EventBreakpoint::RomBank => "bank".to_string(),
+            EventBreakpoint::Halt => "halt".to_string(),
+            EventBreakpoint::IllegalOpcode => "illegal".to_string(),
+        }
+    }
 }
 
+#[derive(Default)]
 pub struct Debugger {
     break_on_start: bool,
     step_by_step: bool,
-    pc_breakpoints: Vec<u16>,
+    pc_breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    // Watchpoint index + kind + address + value of the accesses since the last instruction.
+    watchpoint_hits: Vec<(usize, WatchKind, u16, u8)>,
+    // Kind + address of the access that made the last stop.
+    last_watchpoint_hit: Option<(WatchKind, u16)>,
+    event_breakpoints: Vec<EventBreakpoint>,
+    // Event since the last instruction, and the one that made the last stop.
+    event_hit: Option<DebugEvent>,
+    last_event_hit: Option<DebugEvent>,
     auto_step_count: usize,
     one_time_break: bool,
+    run_until: Option<RunUntil>,
     breakpoint_flag: Arc<AtomicBool>,
+    symbols: Symbols,
 }
 
 impl Debugger {
@@ -30,13 +323,60 @@ impl Debugger {
             break_on_start: false,
             step_by_step: false,
             pc_breakpoints: vec![],
+            watchpoints: vec![],
+            watchpoint_hits: vec![],
+            last_watchpoint_hit: None,
+            event_breakpoints: vec![],
+            event_hit: None,
+            last_event_hit: None,
             auto_step_count: 0,
             one_time_break: false,
+            run_until: None,
             breakpoint_flag,
+            symbols: Symbols::new(),
+        }
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    /**
+     * Hex address or label. Labels in the switchable ROM area also give their bank.
+     */
+    pub fn resolve(&self, raw: &str, rom_bank: u8) -> Result<(Option<u8>, u16), Error> {
+        if let Some((bank, addr)) = self.symbols.lookup(raw, rom_bank) {
+            let bank = (MEM_AREA_ROM_BANK_N_START..=MEM_AREA_ROM_BANK_N_END)
+                .contains(&addr)
+                .then_some(bank);
+            return Ok((bank, addr));
+        }
+
+        u16::from_str_radix(raw, 16)
+            .map(|addr| (None, addr))
+            .map_err(|_| format!("Unknown address or label: {}", raw).into())
+    }
+
+    fn describe_breakpoint(&self, breakpoint: &Breakpoint) -> String {
+        let label = self
+            .symbols
+            .label_at(breakpoint.pc, breakpoint.bank.unwrap_or(0))
+            .map(|label| format!(" ({})", label))
+            .unwrap_or_default();
+        match breakpoint.bank {
+            Some(bank) => format!("{:02X}:{:04X}{}", bank, breakpoint.pc, label),
+            None => format!("{:04X}{}", breakpoint.pc, label),
         }
     }
 
-    pub fn parse(&mut self, raw: String) -> Option<DebugCmd> {
+    /**
+     * `rom_bank` is the bank currently mapped to 0x4000-0x7FFF, used to pick between labels of the same name.
+     */
+    pub fn parse(&mut self, raw: String, rom_bank: u8) -> Option<DebugCmd> {
         let raw = raw.trim();
         let parts = raw.split(" ").collect::<Vec<&str>>();
 
@@ -58,10 +398,30 @@ impl Debugger {
             Some(DebugCmd::PrintCpu)
         } else if raw == "c" {
             Some(DebugCmd::Continue)
-        } else if parts.len() == 2 && parts[0] == "b" {
-            usize::from_str_radix(parts[1], 16)
-                .ok()
-                .map(|pc| self.add_breakpoint(pc as u16));
+        } else if raw == "bt" {
+            Some(DebugCmd::PrintCallStack)
+        } else if raw == "prof" {
+            Some(DebugCmd::PrintProfile)
+        } else if raw == "o" {
+            Some(DebugCmd::StepOver)
+        } else if raw == "out" {
+            Some(DebugCmd::StepOut)
+        } else if parts.len() == 2 && parts[0] == "r" {
+            match self.resolve(parts[1], rom_bank) {
+                Ok((bank, addr)) => Some(DebugCmd::RunTo(bank, addr)),
+                Err(err) => {
+                    println!("{}", err);
+                    None
+                }
+            }
+        } else if parts.len() >= 2 && parts[0] == "b" {
+            match self
+                .resolve(parts[1], rom_bank)
+                .and_then(|(bank, pc)| Ok((bank, pc, Condition::parse(&parts[2..])?)))
+            {
+                Ok((bank, pc, condition)) => self.add_conditional_breakpoint(pc, bank, condition),
+                Err(err) => println!("Invalid breakpoint: {}", err),
+            }
             self.dump_breakpoints();
             None
         } else if raw == "b?" {
@@ -72,16 +432,68 @@ impl Debugger {
                 self.pc_breakpoints.clear();
             } else {
                 for i in 1..parts.len() {
-                    if let Some(i) = u16::from_str_radix(parts[i], 16)
-                        .ok()
-                        .and_then(|v_in| self.pc_breakpoints.iter().position(|e| e == &v_in))
-                    {
+                    if let Some(i) = self.resolve(parts[i], rom_bank).ok().and_then(|v_in| {
+                        self.pc_breakpoints
+                            .iter()
+                            .position(|e| (e.bank, e.pc) == v_in || (None, e.pc) == v_in)
+                    }) {
                         self.pc_breakpoints.remove(i);
                     }
                 }
             }
             self.dump_breakpoints();
             None
+        } else if parts.len() >= 2 && (parts[0] == "wr" || parts[0] == "ww" || parts[0] == "wa") {
+            let kind = match parts[0] {
+                "wr" => WatchKind::Read,
+                "ww" => WatchKind::Write,
+                _ => WatchKind::Access,
+            };
+            let resolve = |raw: &str| self.resolve(raw, rom_bank).map(|(_, addr)| addr);
+            match Watchpoint::parse(kind, &parts[1..], &resolve) {
+                Ok(watchpoint) => self.add_watchpoint(watchpoint),
+                Err(err) => println!("Invalid watchpoint: {}", err),
+            }
+            self.dump_watchpoints();
+            None
+        } else if raw == "w?" {
+            self.dump_watchpoints();
+            None
+        } else if parts[0] == "w-" {
+            if parts.len() == 1 {
+                self.retain_watchpoints(|_| false);
+            } else {
+                for part in &parts[1..] {
+                    if let Ok((_, from)) = self.resolve(part, rom_bank) {
+                        self.retain_watchpoints(|watchpoint| watchpoint.from != from);
+                    }
+                }
+            }
+            self.dump_watchpoints();
+            None
+        } else if parts.len() >= 2 && parts[0] == "be" {
+            let resolve = |raw: &str| self.resolve(raw, rom_bank).map(|(_, addr)| addr);
+            match EventBreakpoint::parse(&parts[1..], &resolve) {
+                Ok(event_breakpoint) => self.add_event_breakpoint(event_breakpoint),
+                Err(err) => println!("Invalid event breakpoint: {}", err),
+            }
+            self.dump_event_breakpoints();
+            None
+        } else if raw == "be?" {
+            self.dump_event_breakpoints();
+            None
+        } else if parts[0] == "be-" {
+            if parts.len() == 1 {
+                self.event_breakpoints.clear();
+            } else {
+                let resolve = |raw: &str| self.resolve(raw, rom_bank).map(|(_, addr)| addr);
+                if let Ok(event_breakpoint) = EventBreakpoint::parse(&parts[1..], &resolve) {
+                    self.event_breakpoints
+                        .retain(|other| *other != event_breakpoint);
+                }
+            }
+            self.dump_event_breakpoints();
+            None
         } else if raw == "s" {
             self.set_step_by_step();
             None
@@ -100,15 +512,112 @@ impl Debugger {
                 .ok()
         } else if raw == "oam" {
             Some(DebugCmd::PrintOam)
+        } else if parts[0] == "d" {
+            let from = match parts.get(1) {
+                Some(raw_from) => match self.resolve(raw_from, rom_bank) {
+                    Ok((_, from)) => Some(from),
+                    Err(err) => {
+                        println!("{}", err);
+                        return None;
+                    }
+                },
+                None => None,
+            };
+            let count = parts
+                .get(2)
+                .and_then(|count| count.parse::<usize>().ok())
+                .unwrap_or(16);
+            Some(DebugCmd::Disassemble(from, count))
+        } else if ["set", "sb", "sw", "fill", "load"].contains(&parts[0].trim_end_matches('!')) {
+            match self.parse_edit(&parts, rom_bank) {
+                Ok(cmd) => Some(cmd),
+                Err(err) => {
+                    println!("Invalid edit: {}", err);
+                    None
+                }
+            }
         } else {
             println!("Invalid debug command: {}", raw);
             None
         }
     }
 
+    /**
+     * Register and memory edits, a `!` suffix writes in raw mode:
+     * - `set <reg|flag> <value>`
+     * - `sb <addr> <byte> [byte ...]`, `sw <addr> <word>`
+     * - `fill <from> <to> <byte>`, inclusive
+     * - `load <file> <addr> [len]`
+     */
+    fn parse_edit(&self, parts: &[&str], rom_bank: u8) -> Result<DebugCmd, Error> {
+        let raw_mode = parts[0].ends_with('!');
+        let addr = |i: usize| -> Result<u16, Error> {
+            let raw = parts.get(i).ok_or("Missing address")?;
+            self.resolve(raw, rom_bank).map(|(_, addr)| addr)
+        };
+        let byte = |i: usize| -> Result<u8, Error> {
+            let raw = parts.get(i).ok_or("Missing byte")?;
+            Ok(u8::from_str_radix(raw, 16)?)
+        };
+
+        match parts[0].trim_end_matches('!') {
+            "set" => {
+                let name = parts.get(1).ok_or("Missing register")?;
+                let variable = match parse_variable(name) {
+                    Some(Variable::Counter) | Some(Variable::RomBank) | None => {
+                        return Err(format!("Cannot set {}", name).into())
+                    }
+                    Some(variable) => variable,
+                };
+                Ok(DebugCmd::SetVariable(variable, addr(2)?))
+            }
+            "sb" => {
+                let bytes = (2..parts.len().max(3))
+                    .map(byte)
+                    .collect::<Result<Vec<u8>, Error>>()?;
+                Ok(DebugCmd::WriteMemory(addr(1)?, bytes, raw_mode))
+            }
+            "sw" => {
+                let word = addr(2)?;
+                Ok(DebugCmd::WriteMemory(
+                    addr(1)?,
+                    word.to_le_bytes().to_vec(),
+                    raw_mode,
+                ))
+            }
+            "fill" => {
+                let (from, to) = (addr(1)?, addr(2)?);
+                if to < from {
+                    return Err("Fill range ends before its start".into());
+                }
+                let len = (to - from) as usize + 1;
+                Ok(DebugCmd::WriteMemory(from, vec![byte(3)?; len], raw_mode))
+            }
+            _ => {
+                let path = parts.get(1).ok_or("Missing file")?;
+                let mut bytes = std::fs::read(path)?;
+                let from = addr(2)?;
+                if let Some(len) = parts.get(3) {
+                    bytes.truncate(len.parse::<usize>()?);
+                }
+                bytes.truncate(0x10000 - from as usize);
+                Ok(DebugCmd::WriteMemory(from, bytes, raw_mode))
+            }
+        }
+    }
+
     pub fn clear_steps_and_continue(&mut self) {
         self.auto_step_count = 0;
         self.step_by_step = false;
+        self.run_until = None;
+    }
+
+    /**
+     * Runs until the target is reached, even in step by step mode. Breakpoints still stop.
+     */
+    pub fn set_run_until(&mut self, run_until: RunUntil) {
+        self.auto_step_count = 0;
+        self.run_until = Some(run_until);
     }
 
     pub fn set_auto_step_count(&mut self, n: usize) {
@@ -123,23 +632,196 @@ impl Debugger {
         self.step_by_step = true;
     }
 
-    pub fn add_breakpoint(&mut self, breakpoint: u16) {
-        info!("Breakpoint has been added: {:?}", breakpoint);
+    /**
+     * Breakpoint from the command line, by hex address or label.
+     */
+    pub fn add_breakpoint_by_name(&mut self, raw: &str) -> Result<(), Error> {
+        // Nothing is mapped yet, bank 1 is the default of the switchable area.
+        let (bank, pc) = self.resolve(raw, 1)?;
+        self.add_conditional_breakpoint(pc, bank, Condition::default());
+        Ok(())
+    }
+
+    pub fn add_conditional_breakpoint(&mut self, pc: u16, bank: Option<u8>, condition: Condition) {
+        let breakpoint = Breakpoint {
+            pc,
+            bank,
+            condition,
+        };
+        info!(
+            "Breakpoint has been added: {}{}",
+            self.describe_breakpoint(&breakpoint),
+            breakpoint.condition.describe()
+        );
         self.pc_breakpoints.push(breakpoint);
     }
 
-    #[allow(dead_code)]
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        info!("Watchpoint has been added: {:?}", watchpoint);
+        self.watchpoints.push(watchpoint);
+    }
+
+    pub fn add_event_breakpoint(&mut self, event_breakpoint: EventBreakpoint) {
+        info!(
+            "Event breakpoint has been added: {}",
+            event_breakpoint.describe()
+        );
+        self.event_breakpoints.push(event_breakpoint);
+    }
+
+    pub fn has_event_breakpoints(&self) -> bool {
+        !self.event_breakpoints.is_empty()
+    }
+
+    /**
+     * Called by the VM on each event. A hit stops the execution before the next instruction.
+     */
+    pub fn check_event(&mut self, event: DebugEvent) {
+        if self.event_hit.is_none()
+            && self
+                .event_breakpoints
+                .iter()
+                .any(|event_breakpoint| event_breakpoint.matches(event))
+        {
+            self.event_hit = Some(event);
+        }
+    }
+
+    /**
+     * Whether an event will stop the execution before the next instruction.
+     */
+    pub fn has_event_hit(&self) -> bool {
+        self.event_hit.is_some()
+    }
+
+    pub fn take_event_hit(&mut self) -> Option<DebugEvent> {
+        self.last_event_hit.take()
+    }
+
+    pub fn remove_breakpoint(&mut self, pc: u16) {
+        if let Some(i) = self.pc_breakpoints.iter().position(|e| e.pc == pc) {
+            self.pc_breakpoints.remove(i);
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, kind: WatchKind, from: u16) {
+        self.retain_watchpoints(|watchpoint| watchpoint.kind != kind || watchpoint.from != from);
+    }
+
+    /**
+     * Removes watchpoints, keeping the pending hits of the others pointed at them.
+     */
+    fn retain_watchpoints(&mut self, keep: impl Fn(&Watchpoint) -> bool) {
+        let mut new_indexes = vec![];
+        let mut kept = 0;
+        self.watchpoints.retain(|watchpoint| {
+            let is_kept = keep(watchpoint);
+            new_indexes.push(is_kept.then_some(kept));
+            kept += is_kept as usize;
+            is_kept
+        });
+
+        self.watchpoint_hits
+            .retain_mut(|hit| match new_indexes[hit.0] {
+                Some(i) => {
+                    hit.0 = i;
+                    true
+                }
+                None => false,
+            });
+    }
+
+    pub fn take_watchpoint_hit(&mut self) -> Option<(WatchKind, u16)> {
+        self.last_watchpoint_hit.take()
+    }
+
+    /**
+     * Called on every bus access. A hit stops the execution before the next instruction.
+     */
+    pub fn check_watchpoints(&mut self, kind: WatchKind, loc: u16, byte: u8) {
+        if self.watchpoints.is_empty() {
+            return;
+        }
+
+        for (i, watchpoint) in self.watchpoints.iter().enumerate() {
+            if watchpoint.matches(kind, loc, byte)
+                && !self.watchpoint_hits.iter().any(|hit| hit.0 == i)
+            {
+                self.watchpoint_hits.push((i, kind, loc, byte));
+            }
+        }
+    }
+
+    /**
+     * Whether `should_stop` has conditions to evaluate at this PC.
+     */
+    pub fn has_conditions(&self, pc: u16) -> bool {
+        !self.watchpoint_hits.is_empty()
+            || matches!(self.run_until, Some(RunUntil::Address(Some(_), addr)) if addr == pc)
+            || self.pc_breakpoints.iter().any(|breakpoint| {
+                breakpoint.pc == pc
+                    && (breakpoint.bank.is_some() || breakpoint.condition.is_conditional())
+            })
+    }
+
     pub fn request_one_time_break(&mut self) {
         self.one_time_break = true;
     }
 
-    pub fn should_stop(&mut self, pc: u16) -> bool {
+    /**
+     * `call_depth` is the depth of the shadow call stack.
+     */
+    pub fn should_stop(&mut self, pc: u16, call_depth: usize, ctx: &mut dyn ExprContext) -> bool {
+        let should_stop = self.check_stop(pc, call_depth, ctx);
+        if should_stop {
+            self.run_until = None;
+        }
+        should_stop
+    }
+
+    fn check_stop(&mut self, pc: u16, call_depth: usize, ctx: &mut dyn ExprContext) -> bool {
+        // Watchpoints are evaluated after the instruction that made the access.
+        let mut is_watchpoint_hit = false;
+        for (i, kind, loc, byte) in std::mem::take(&mut self.watchpoint_hits) {
+            if self.watchpoints[i].condition.check(ctx) {
+                println!(
+                    "Watchpoint: {:?} {:04X} = {:02X} (next PC {:04X})",
+                    kind, loc, byte, pc
+                );
+                is_watchpoint_hit = true;
+                self.last_watchpoint_hit = Some((kind, loc));
+            }
+        }
+        if is_watchpoint_hit {
+            self.auto_step_count = 0;
+            return true;
+        }
+
+        if let Some(event) = self.event_hit.take() {
+            println!("Event: {} (next PC {:04X})", event.describe(), pc);
+            self.last_event_hit = Some(event);
+            self.auto_step_count = 0;
+            return true;
+        }
+
         if self.auto_step_count > 0 {
             self.auto_step_count -= 1;
             return false;
         }
 
-        if self.step_by_step {
+        let is_in_bank = |bank: Option<u8>, ctx: &mut dyn ExprContext| {
+            bank.is_none_or(|bank| ctx.variable(Variable::RomBank) == bank as i64)
+        };
+
+        if let Some(run_until) = self.run_until {
+            let is_reached = match run_until {
+                RunUntil::CallDepth(depth) => call_depth <= depth,
+                RunUntil::Address(bank, addr) => pc == addr && is_in_bank(bank, ctx),
+            };
+            if is_reached {
+                return true;
+            }
+        } else if self.step_by_step {
             return true;
         }
 
@@ -147,7 +829,17 @@ impl Debugger {
             return true;
         }
 
-        if self.pc_breakpoints.contains(&pc) {
+        let mut is_breakpoint_hit = false;
+        for breakpoint in self.pc_breakpoints.iter_mut() {
+            // All matching breakpoints are checked so each counts its hits.
+            if breakpoint.pc == pc
+                && is_in_bank(breakpoint.bank, ctx)
+                && breakpoint.condition.check(ctx)
+            {
+                is_breakpoint_hit = true;
+            }
+        }
+        if is_breakpoint_hit {
             return true;
         }
 
@@ -170,9 +862,227 @@ impl Debugger {
         let lines = self
             .pc_breakpoints
             .iter()
-            .map(|v| format!("{:04X}", v))
+            .map(|v| format!("{}{}", self.describe_breakpoint(v), v.condition.describe()))
             .collect::<Vec<_>>()
-            .join(" ");
+            .join(", ");
         println!("Breakpoints: {}", lines);
     }
+
+    fn dump_watchpoints(&self) {
+        let lines = self
+            .watchpoints
+            .iter()
+            .map(|watchpoint| {
+                let range = if watchpoint.from == watchpoint.to {
+                    format!("{:04X}", watchpoint.from)
+                } else {
+                    format!("{:04X}-{:04X}", watchpoint.from, watchpoint.to)
+                };
+                let value = match watchpoint.value {
+                    Some(value) => format!("={:02X}", value),
+                    None => String::new(),
+                };
+                format!(
+                    "{:?}:{}{}{}",
+                    watchpoint.kind,
+                    range,
+                    value,
+                    watchpoint.condition.describe()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        println!("Watchpoints: {}", lines);
+    }
+
+    fn dump_event_breakpoints(&self) {
+        let lines = self
+            .event_breakpoints
+            .iter()
+            .map(EventBreakpoint::describe)
+            .collect::<Vec<_>>()
+            .join(", ");
+        println!("Event breakpoints: {}", lines);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::debugger::*;
+    use crate::expr::Variable;
+
+    struct TestContext {
+        a: i64,
+        rom_bank: i64,
+    }
+
+    impl ExprContext for TestContext {
+        fn variable(&mut self, variable: Variable) -> i64 {
+            match variable {
+                Variable::A => self.a,
+                Variable::RomBank => self.rom_bank,
+                _ => 0,
+            }
+        }
+
+        fn read_memory(&mut self, _loc: u16) -> u8 {
+            0
+        }
+    }
+
+    #[test]
+    fn test_watchpoint_stops_on_matching_access() {
+        let mut ctx = TestContext { a: 0, rom_bank: 1 };
+        let mut debugger = Debugger::new(Arc::new(AtomicBool::new(false)));
+        debugger.parse("ww C100 00".to_string(), 1);
+        debugger.parse("wa D000-D0FF".to_string(), 1);
+
+        debugger.check_watchpoints(WatchKind::Write, 0xC100, 0x01);
+        debugger.check_watchpoints(WatchKind::Read, 0xC100, 0x00);
+        assert!(!debugger.should_stop(0x0150, 0, &mut ctx));
+
+        debugger.check_watchpoints(WatchKind::Write, 0xC100, 0x00);
+        assert!(debugger.should_stop(0x0150, 0, &mut ctx));
+
+        debugger.check_watchpoints(WatchKind::Read, 0xD080, 0x42);
+        assert!(debugger.should_stop(0x0151, 0, &mut ctx));
+
+        debugger.parse("w- D000".to_string(), 1);
+        debugger.check_watchpoints(WatchKind::Read, 0xD080, 0x42);
+        assert!(!debugger.should_stop(0x0152, 0, &mut ctx));
+    }
+
+    #[test]
+    fn test_watchpoint_removed_with_pending_hit() {
+        let mut ctx = TestContext { a: 0, rom_bank: 1 };
+        let mut debugger = Debugger::new(Arc::new(AtomicBool::new(false)));
+        debugger.parse("ww C100".to_string(), 1);
+        debugger.parse("ww C200".to_string(), 1);
+
+        // Memory edit from the prompt, then removal before continuing.
+        debugger.check_watchpoints(WatchKind::Write, 0xC100, 0x01);
+        debugger.check_watchpoints(WatchKind::Write, 0xC200, 0x01);
+        debugger.parse("w- C100".to_string(), 1);
+        assert!(debugger.should_stop(0x0150, 0, &mut ctx));
+        assert_eq!(
+            Some((WatchKind::Write, 0xC200)),
+            debugger.take_watchpoint_hit()
+        );
+
+        debugger.check_watchpoints(WatchKind::Write, 0xC200, 0x01);
+        debugger.remove_watchpoint(WatchKind::Write, 0xC200);
+        assert!(!debugger.should_stop(0x0151, 0, &mut ctx));
+
+        debugger.parse("ww C300".to_string(), 1);
+        debugger.check_watchpoints(WatchKind::Write, 0xC300, 0x01);
+        debugger.parse("w-".to_string(), 1);
+        assert!(!debugger.should_stop(0x0152, 0, &mut ctx));
+    }
+
+    #[test]
+    fn test_conditional_breakpoint_with_hit_count() {
+        let mut ctx = TestContext { a: 0, rom_bank: 1 };
+        let mut debugger = Debugger::new(Arc::new(AtomicBool::new(false)));
+        debugger.parse("b 0150 hit 2 if A == 0x3F".to_string(), 1);
+
+        assert!(!debugger.should_stop(0x0150, 0, &mut ctx));
+        ctx.a = 0x3F;
+        assert!(!debugger.should_stop(0x0150, 0, &mut ctx));
+        assert!(debugger.should_stop(0x0150, 0, &mut ctx));
+        assert!(!debugger.should_stop(0x0151, 0, &mut ctx));
+    }
+
+    #[test]
+    fn test_breakpoint_by_label_in_bank() {
+        let mut ctx = TestContext { a: 0, rom_bank: 1 };
+        let mut debugger = Debugger::new(Arc::new(AtomicBool::new(false)));
+        debugger.set_symbols(Symbols::parse("02:4100 Shared\n03:4100 Shared\n").unwrap());
+        debugger.parse("b Shared".to_string(), 3);
+
+        assert!(!debugger.should_stop(0x4100, 0, &mut ctx));
+        ctx.rom_bank = 3;
+        assert!(debugger.should_stop(0x4100, 0, &mut ctx));
+    }
+
+    #[test]
+    fn test_run_until_overrides_step_by_step() {
+        let mut ctx = TestContext { a: 0, rom_bank: 1 };
+        let mut debugger = Debugger::new(Arc::new(AtomicBool::new(false)));
+        debugger.set_step_by_step();
+
+        // Step over a CALL from depth 1.
+        debugger.set_run_until(RunUntil::CallDepth(1));
+        assert!(!debugger.should_stop(0x4000, 2, &mut ctx));
+        assert!(!debugger.should_stop(0x4001, 3, &mut ctx));
+        assert!(debugger.should_stop(0x0153, 1, &mut ctx));
+        assert!(debugger.should_stop(0x0154, 1, &mut ctx));
+
+        debugger.parse("b 0200".to_string(), 1);
+        debugger.set_run_until(RunUntil::Address(None, 0x0300));
+        assert!(!debugger.should_stop(0x0155, 1, &mut ctx));
+        assert!(debugger.should_stop(0x0200, 1, &mut ctx));
+        // Dropped by the breakpoint.
+        assert!(debugger.should_stop(0x0201, 1, &mut ctx));
+    }
+
+    #[test]
+    fn test_parse_edits() {
+        let mut debugger = Debugger::new(Arc::new(AtomicBool::new(false)));
+        debugger.set_symbols(Symbols::parse("00:C0A0 wBuffer\n").unwrap());
+
+        assert!(matches!(
+            debugger.parse("set fz 1".to_string(), 1),
+            Some(DebugCmd::SetVariable(Variable::FlagZ, 1))
+        ));
+        assert!(matches!(
+            debugger.parse("set HL wBuffer".to_string(), 1),
+            Some(DebugCmd::SetVariable(Variable::HL, 0xC0A0))
+        ));
+        assert!(debugger.parse("set bank 2".to_string(), 1).is_none());
+
+        match debugger.parse("sw! 4000 1234".to_string(), 1) {
+            Some(DebugCmd::WriteMemory(0x4000, bytes, true)) => assert_eq!(vec![0x34, 0x12], bytes),
+            _ => panic!("Expected a raw word write"),
+        }
+        match debugger.parse("fill wBuffer C0A3 ff".to_string(), 1) {
+            Some(DebugCmd::WriteMemory(0xC0A0, bytes, false)) => assert_eq!(vec![0xFF; 4], bytes),
+            _ => panic!("Expected a fill"),
+        }
+        assert!(debugger.parse("sb C000".to_string(), 1).is_none());
+        assert!(debugger.parse("fill C010 C000 00".to_string(), 1).is_none());
+    }
+
+    #[test]
+    fn test_event_breakpoints() {
+        let mut ctx = TestContext { a: 0, rom_bank: 1 };
+        let mut debugger = Debugger::new(Arc::new(AtomicBool::new(false)));
+        debugger.parse("be int timer".to_string(), 1);
+        debugger.parse("be io FF40-FF45".to_string(), 1);
+        debugger.parse("be illegal".to_string(), 1);
+        assert!(debugger.parse("be int nmi".to_string(), 1).is_none());
+        assert_eq!(3, debugger.event_breakpoints.len());
+
+        debugger.check_event(DebugEvent::InterruptService(Interrupt::VBlank));
+        debugger.check_event(DebugEvent::IoWrite(0xFF46, 0xC0));
+        debugger.check_event(DebugEvent::Halt);
+        assert!(!debugger.should_stop(0x0150, 0, &mut ctx));
+
+        debugger.check_event(DebugEvent::InterruptService(Interrupt::Timer));
+        assert!(debugger.has_event_hit());
+        assert!(debugger.should_stop(0x0050, 1, &mut ctx));
+        assert_eq!(
+            Some(DebugEvent::InterruptService(Interrupt::Timer)),
+            debugger.take_event_hit()
+        );
+
+        debugger.check_event(DebugEvent::IoWrite(0xFF42, 0x10));
+        assert!(debugger.should_stop(0x0051, 1, &mut ctx));
+
+        debugger.parse("be- io FF40-FF45".to_string(), 1);
+        debugger.check_event(DebugEvent::IoWrite(0xFF42, 0x10));
+        assert!(!debugger.should_stop(0x0052, 1, &mut ctx));
+
+        debugger.check_event(DebugEvent::IllegalOpcode(0xD3));
+        assert!(debugger.should_stop(0x0053, 1, &mut ctx));
+    }
 }
@@ -0,0 +1,177 @@
+/**
+ * Disassembler on top of the `OPCODE_NAME` / `OPCODE_CB_NAME` templates ("LD BC,d16 3 12"): the operand
+ * placeholders are replaced by the decoded values.
+ *
+ * - d8 / d16: immediate, a16: absolute address, a8: 0xFF00 based address
+ * - r8: relative jump target, or signed offset for `ADD SP,r8` / `LD HL,SP+r8`
+ */
+use crate::conf::*;
+
+pub struct Instruction {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub text: String,
+}
+
+impl Instruction {
+    pub fn len(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    pub fn next_addr(&self) -> u16 {
+        self.addr.wrapping_add(self.len())
+    }
+
+    /**
+     * `0150  3E 3F     LD A,0x3F`.
+     */
+    pub fn format(&self) -> String {
+        let bytes = self
+            .bytes
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+        format!("{:04X}  {:<9} {}", self.addr, bytes, self.text)
+    }
+}
+
+/**
+ * Disassembly around PC for the imgui debugger, written by the VM. Lines without address are labels.
+ */
+#[derive(Default)]
+pub struct DisassemblyView {
+    pub lines: Vec<(Option<u16>, String)>,
+    pub pc: u16,
+    pub rom_bank: u8,
+}
+
+impl DisassemblyView {
+    pub fn contains(&self, addr: u16) -> bool {
+        self.lines.iter().any(|(a, _)| *a == Some(addr))
+    }
+}
+
+/**
+ * Mnemonic and length of a template.
+ */
+fn split_template(template: &str) -> (&str, u16) {
+    // "<mnemonic> <length> <cycles>"
+    let mut parts = template.rsplitn(3, ' ');
+    let _cycles = parts.next();
+    let len = parts.next().and_then(|len| len.parse::<u16>().ok());
+    match (len, parts.next()) {
+        (Some(len), Some(mnemonic)) => (mnemonic, len),
+        _ => (template, 1),
+    }
+}
+
+/**
+ * Decodes the instruction at `addr`. `bytes` are the (up to) 3 bytes from `addr`, `label` names addresses used as
+ * operands.
+ */
+pub fn decode(addr: u16, bytes: [u8; 3], label: &dyn Fn(u16) -> Option<String>) -> Instruction {
+    let op = bytes[0];
+
+    if op == 0xCB {
+        let (mnemonic, _) = split_template(OPCODE_CB_NAME[bytes[1] as usize]);
+        return Instruction {
+            addr,
+            bytes: bytes[0..2].to_vec(),
+            text: mnemonic.to_string(),
+        };
+    }
+
+    let template = OPCODE_NAME[op as usize];
+    if template == "Invalid" {
+        return Instruction {
+            addr,
+            bytes: vec![op],
+            text: format!("DB {:#04X}", op),
+        };
+    }
+
+    let (mnemonic, len) = split_template(template);
+    let d8 = bytes[1];
+    let d16 = u16::from_le_bytes([bytes[1], bytes[2]]);
+    let with_label = |value: u16| match label(value) {
+        Some(name) => format!("{:#06X} <{}>", value, name),
+        None => format!("{:#06X}", value),
+    };
+    let signed = |value: u8| {
+        let value = value as i8;
+        if value < 0 {
+            format!("-{:#04X}", value.unsigned_abs())
+        } else {
+            format!("+{:#04X}", value)
+        }
+    };
+
+    let text = if mnemonic.contains("SP+r8") {
+        mnemonic.replace("SP+r8", &format!("SP{}", signed(d8)))
+    } else if mnemonic.starts_with("ADD SP,r8") {
+        mnemonic.replace("r8", &signed(d8))
+    } else if mnemonic.contains("r8") {
+        let target = addr.wrapping_add(len).wrapping_add(d8 as i8 as u16);
+        mnemonic.replace("r8", &with_label(target))
+    } else if mnemonic.contains("a16") {
+        mnemonic.replace("a16", &with_label(d16))
+    } else if mnemonic.contains("d16") {
+        mnemonic.replace("d16", &format!("{:#06X}", d16))
+    } else if mnemonic.contains("a8") {
+        mnemonic.replace("a8", &with_label(0xFF00 | d8 as u16))
+    } else if mnemonic.contains("d8") {
+        mnemonic.replace("d8", &format!("{:#04X}", d8))
+    } else {
+        mnemonic.to_string()
+    };
+
+    Instruction {
+        addr,
+        bytes: bytes[0..len as usize].to_vec(),
+        text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disasm::*;
+
+    fn text(addr: u16, bytes: [u8; 3]) -> String {
+        let label = |addr: u16| (addr == 0x0150).then(|| "Main".to_string());
+        decode(addr, bytes, &label).text
+    }
+
+    #[test]
+    fn test_decode_operands() {
+        assert_eq!("NOP", text(0x0100, [0x00, 0x00, 0x00]));
+        assert_eq!("LD BC,0x1234", text(0x0100, [0x01, 0x34, 0x12]));
+        assert_eq!("LD A,0x3F", text(0x0100, [0x3E, 0x3F, 0x00]));
+        assert_eq!("JP 0x0150 <Main>", text(0x0100, [0xC3, 0x50, 0x01]));
+        assert_eq!("JR NZ,0x0150 <Main>", text(0x0152, [0x20, 0xFC, 0x00]));
+        assert_eq!("JR 0x0164", text(0x0150, [0x18, 0x12, 0x00]));
+        assert_eq!("LDH (0xFF44),A", text(0x0100, [0xE0, 0x44, 0x00]));
+        assert_eq!("LD HL,SP-0x02", text(0x0100, [0xF8, 0xFE, 0x00]));
+        assert_eq!("ADD SP,+0x08", text(0x0100, [0xE8, 0x08, 0x00]));
+        assert_eq!("STOP 0", text(0x0100, [0x10, 0x00, 0x00]));
+        assert_eq!("BIT 7,H", text(0x0100, [0xCB, 0x7C, 0x00]));
+        assert_eq!("DB 0xD3", text(0x0100, [0xD3, 0x00, 0x00]));
+    }
+
+    #[test]
+    fn test_instruction_length() {
+        let label = |_| None;
+        assert_eq!(3, decode(0, [0xCD, 0x00, 0x40], &label).len());
+        assert_eq!(2, decode(0, [0xCB, 0x11, 0x00], &label).len());
+        assert_eq!(2, decode(0, [0x10, 0x00, 0x00], &label).len());
+        // LDH (C),A / LDH A,(C): no operand byte.
+        assert_eq!(1, decode(0, [0xE2, 0x00, 0x00], &label).len());
+        assert_eq!(1, decode(0, [0xF2, 0x00, 0x00], &label).len());
+        assert_eq!("LD (C),A", decode(0, [0xE2, 0x00, 0x00], &label).text);
+        assert_eq!("LD A,(C)", decode(0, [0xF2, 0x00, 0x00], &label).text);
+        assert_eq!(
+            "0000  CD 00 40  CALL 0x4000",
+            decode(0, [0xCD, 0x00, 0x40], &label).format()
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use crate::conf::*;
+use crate::state::*;
+
+/**
+ * OAM DMA (0xFF46): copies 160 bytes from `XX00` to OAM, one per M-cycle, after a 1 M-cycle startup. While bytes
+ * are copied the CPU only reaches HRAM and the IO registers. A write during a transfer restarts it, the running one
+ * goes on through the startup cycle of the new one.
+ */
+pub struct OamDma {
+    reg: u8,
+    // Source and OAM offset of the next byte.
+    running: Option<(u16, u8)>,
+    // Source of a transfer in its startup cycle.
+    starting: Option<u16>,
+    // Source written during the current instruction.
+    requested: Option<u16>,
+}
+
+impl OamDma {
+    pub fn new() -> OamDma {
+        OamDma {
+            reg: 0xFF,
+            running: None,
+            starting: None,
+            requested: None,
+        }
+    }
+
+    pub fn reg(&self) -> u8 {
+        self.reg
+    }
+
+    pub fn request(&mut self, byte: u8) {
+        self.reg = byte;
+        // Sources past WRAM see the echo of WRAM on DMG, 0xFE and 0xFF included.
+        let page = if byte >= 0xE0 { byte - 0x20 } else { byte };
+        self.requested = Some((page as u16) << 8);
+    }
+
+    /**
+     * Whether the CPU is cut off from the bus.
+     */
+    pub fn is_active(&self) -> bool {
+        self.running.is_some()
+    }
+
+    /**
+     * One M-cycle, with the source address and OAM offset of the byte to copy if any.
+     */
+    pub fn step(&mut self) -> Option<(u16, u8)> {
+        let copy = self
+            .running
+            .map(|(source, offs)| (source + offs as u16, offs));
+        self.running = match self.running {
+            Some((source, offs)) if (offs as usize) < OAM_RAM_SIZE - 1 => Some((source, offs + 1)),
+            _ => None,
+        };
+        if let Some(source) = self.starting.take() {
+            self.running = Some((source, 0));
+        }
+        copy
+    }
+
+    /**
+     * Called after the M-cycles of an instruction are stepped: a transfer requested by it starts counting from here.
+     */
+    pub fn end_instruction(&mut self) {
+        if let Some(source) = self.requested.take() {
+            self.starting = Some(source);
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.reg);
+        w.write_bool(self.running.is_some());
+        let (source, offs) = self.running.unwrap_or_default();
+        w.write_u16(source);
+        w.write_u8(offs);
+        w.write_bool(self.starting.is_some());
+        w.write_u16(self.starting.unwrap_or_default());
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.reg = r.read_u8()?;
+        let is_running = r.read_bool()?;
+        let (source, offs) = (r.read_u16()?, r.read_u8()?);
+        self.running = is_running.then_some((source, offs));
+        let is_starting = r.read_bool()?;
+        let source = r.read_u16()?;
+        self.starting = is_starting.then_some(source);
+        self.requested = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dma::*;
+
+    #[test]
+    fn test_startup_and_length() {
+        let mut dma = OamDma::new();
+        dma.request(0xC1);
+        // Cycles of the writing instruction.
+        assert_eq!(None, dma.step());
+        dma.end_instruction();
+        assert!(!dma.is_active());
+
+        // Startup.
+        assert_eq!(None, dma.step());
+        assert!(dma.is_active());
+        for offs in 0..0xA0u16 {
+            assert_eq!(Some((0xC100 + offs, offs as u8)), dma.step());
+        }
+        assert!(!dma.is_active());
+        assert_eq!(None, dma.step());
+        assert_eq!(0xC1, dma.reg());
+    }
+
+    #[test]
+    fn test_restart_and_echo_source() {
+        let mut dma = OamDma::new();
+        dma.request(0xC0);
+        dma.end_instruction();
+        dma.step();
+        dma.step();
+        dma.step();
+
+        // The old transfer copies during the startup of the new one.
+        dma.request(0xFE);
+        dma.end_instruction();
+        assert_eq!(Some((0xC002, 2)), dma.step());
+        assert_eq!(Some((0xDE00, 0)), dma.step());
+        assert_eq!(Some((0xDE01, 1)), dma.step());
+    }
+}
@@ -0,0 +1,108 @@
+/**
+ * Errors of the emulator, by class. The class decides what the VM does with them:
+ *
+ * - `OpenBus`: hardware-legal access with nothing behind it on the DMG. Reads float to 0xFF, writes are dropped.
+ * - `BusFault`: access the emulator cannot serve, a missing feature or a bug. It locks the emulated CPU and shows
+ *   up in the UI instead of aborting.
+ * - Everything else (`BadRom`, `UnsupportedMapper`, `BadState`, `Io`, `Other`) is reported to the caller: the
+ *   cartridge does not load, the state is not restored, the tool fails.
+ */
+use std::fmt;
+
+#[derive(Debug)]
+pub enum EmulatorError {
+    // Cartridge header or content that cannot be loaded.
+    BadRom(String),
+    // Cartridge type byte (0x0147) of a controller that is not emulated.
+    UnsupportedMapper(u8),
+    OpenBus(u16),
+    BusFault(u16, String),
+    // Truncated or mismatching machine state / movie.
+    BadState(String),
+    Io(std::io::Error),
+    Other(String),
+}
+
+impl EmulatorError {
+    /**
+     * Whether the emulated machine cannot go on. Open bus accesses are part of the hardware behaviour.
+     */
+    pub fn is_fault(&self) -> bool {
+        matches!(self, EmulatorError::BusFault(..))
+    }
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmulatorError::BadRom(reason) => write!(f, "Bad ROM: {}", reason),
+            EmulatorError::UnsupportedMapper(code) => {
+                write!(f, "Unsupported cartridge type: {:#04X}", code)
+            }
+            EmulatorError::OpenBus(loc) => write!(f, "Open bus access: {:#06X}", loc),
+            EmulatorError::BusFault(loc, reason) => {
+                write!(f, "Bus fault at {:#06X}: {}", loc, reason)
+            }
+            EmulatorError::BadState(reason) => write!(f, "Bad state: {}", reason),
+            EmulatorError::Io(err) => write!(f, "{}", err),
+            EmulatorError::Other(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for EmulatorError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EmulatorError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for EmulatorError {
+    fn from(err: std::io::Error) -> Self {
+        EmulatorError::Io(err)
+    }
+}
+
+impl From<String> for EmulatorError {
+    fn from(reason: String) -> Self {
+        EmulatorError::Other(reason)
+    }
+}
+
+impl From<&str> for EmulatorError {
+    fn from(reason: &str) -> Self {
+        EmulatorError::Other(reason.to_string())
+    }
+}
+
+impl From<serde_json::Error> for EmulatorError {
+    fn from(err: serde_json::Error) -> Self {
+        EmulatorError::Other(err.to_string())
+    }
+}
+
+impl From<std::num::ParseIntError> for EmulatorError {
+    fn from(err: std::num::ParseIntError) -> Self {
+        EmulatorError::Other(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::*;
+
+    #[test]
+    fn test_classes() {
+        assert!(EmulatorError::BusFault(0xFF4F, "VBK".to_string()).is_fault());
+        assert!(!EmulatorError::OpenBus(0xFEA0).is_fault());
+        assert_eq!(
+            "Unsupported cartridge type: 0x13",
+            EmulatorError::UnsupportedMapper(0x13).to_string()
+        );
+
+        let err: EmulatorError = "Invalid hit count".into();
+        assert_eq!("Invalid hit count", err.to_string());
+    }
+}
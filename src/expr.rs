@@ -0,0 +1,439 @@
+/**
+ * Debugger expressions, eg: `A == 0x3F && [HL] > 10 && LY == 144`.
+ *
+ * - Numbers: decimal or `0x` prefixed hex.
+ * - Registers: A F B C D E H L AF BC DE HL SP PC, flags: FZ FN FH FC.
+ * - IO registers by name (LY, LCDC, STAT, ...), read as memory.
+ * - `[expr]`: memory byte, `COUNTER`: executed instruction counter, `BANK`: ROM bank at 0x4000-0x7FFF.
+ * - Operators by precedence: `||`, `&&`, `== != < <= > >=`, `|`, `^`, `&`, `+ -`, unary `! -`.
+ */
+use crate::conf::*;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Variable {
+    A,
+    F,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+    FlagZ,
+    FlagN,
+    FlagH,
+    FlagC,
+    Counter,
+    RomBank,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Op {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Add,
+    Sub,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Expr {
+    Number(i64),
+    Variable(Variable),
+    Memory(Box<Expr>),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+pub trait ExprContext {
+    fn variable(&mut self, variable: Variable) -> i64;
+    fn read_memory(&mut self, loc: u16) -> u8;
+}
+
+/**
+ * Context for checks known to have nothing to evaluate.
+ */
+pub struct NoExprContext;
+
+impl ExprContext for NoExprContext {
+    fn variable(&mut self, _variable: Variable) -> i64 {
+        0
+    }
+
+    fn read_memory(&mut self, _loc: u16) -> u8 {
+        0xFF
+    }
+}
+
+const VARIABLES: [(&str, Variable); 20] = [
+    ("A", Variable::A),
+    ("F", Variable::F),
+    ("B", Variable::B),
+    ("C", Variable::C),
+    ("D", Variable::D),
+    ("E", Variable::E),
+    ("H", Variable::H),
+    ("L", Variable::L),
+    ("AF", Variable::AF),
+    ("BC", Variable::BC),
+    ("DE", Variable::DE),
+    ("HL", Variable::HL),
+    ("SP", Variable::SP),
+    ("PC", Variable::PC),
+    ("FZ", Variable::FlagZ),
+    ("FN", Variable::FlagN),
+    ("FH", Variable::FlagH),
+    ("FC", Variable::FlagC),
+    ("COUNTER", Variable::Counter),
+    ("BANK", Variable::RomBank),
+];
+
+/**
+ * Register, flag or counter by its expression name, case insensitive.
+ */
+pub fn parse_variable(name: &str) -> Option<Variable> {
+    let name = name.to_uppercase();
+    VARIABLES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, variable)| *variable)
+}
+
+pub const IO_REGISTERS: [(&str, u16); 23] = [
+    ("P1", MEM_LOC_P1),
+    ("SB", MEM_LOC_SB),
+    ("SC", MEM_LOC_SC),
+    ("DIV", MEM_LOC_DIV),
+    ("TIMA", MEM_LOC_TIMA),
+    ("TMA", MEM_LOC_TMA),
+    ("TAC", MEM_LOC_TAC),
+    ("IF", MEM_LOC_IF),
+    ("NR50", MEM_LOC_NR50),
+    ("NR51", MEM_LOC_NR51),
+    ("NR52", MEM_LOC_NR52),
+    ("LCDC", MEM_LOC_LCDC),
+    ("STAT", MEM_LOC_STAT),
+    ("SCY", MEM_LOC_SCY),
+    ("SCX", MEM_LOC_SCX),
+    ("LY", MEM_LOC_LY),
+    ("LYC", MEM_LOC_LYC),
+    ("DMA", MEM_LOC_DMA),
+    ("BGP", MEM_LOC_BGP),
+    ("OBP0", MEM_LOC_OBP0),
+    ("OBP1", MEM_LOC_OBP1),
+    ("WY", MEM_LOC_WY),
+    ("WX", MEM_LOC_WX),
+];
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Op(Op),
+    Not,
+    Sub,
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+}
+
+fn tokenize(raw: &str) -> Result<Vec<Token>, Error> {
+    let chars = raw.chars().collect::<Vec<_>>();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && chars[i].is_ascii_alphanumeric() {
+                i += 1;
+            }
+            let word = chars[start..i].iter().collect::<String>();
+            let number = match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+                Some(hex) => i64::from_str_radix(hex, 16),
+                None => word.parse::<i64>(),
+            }
+            .map_err(|_| format!("Invalid number: {}", word))?;
+            tokens.push(Token::Number(number));
+            continue;
+        }
+
+        if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Name(chars[start..i].iter().collect()));
+            continue;
+        }
+
+        let (token, len) = match (c, next) {
+            ('|', Some('|')) => (Token::Op(Op::Or), 2),
+            ('&', Some('&')) => (Token::Op(Op::And), 2),
+            ('=', Some('=')) => (Token::Op(Op::Eq), 2),
+            ('!', Some('=')) => (Token::Op(Op::Ne), 2),
+            ('<', Some('=')) => (Token::Op(Op::Le), 2),
+            ('>', Some('=')) => (Token::Op(Op::Ge), 2),
+            ('<', _) => (Token::Op(Op::Lt), 1),
+            ('>', _) => (Token::Op(Op::Gt), 1),
+            ('|', _) => (Token::Op(Op::BitOr), 1),
+            ('^', _) => (Token::Op(Op::BitXor), 1),
+            ('&', _) => (Token::Op(Op::BitAnd), 1),
+            ('+', _) => (Token::Op(Op::Add), 1),
+            ('-', _) => (Token::Sub, 1),
+            ('!', _) => (Token::Not, 1),
+            ('(', _) => (Token::LParen, 1),
+            (')', _) => (Token::RParen, 1),
+            ('[', _) => (Token::LBracket, 1),
+            (']', _) => (Token::RBracket, 1),
+            _ => return Err(format!("Unexpected character in expression: {}", c).into()),
+        };
+        tokens.push(token);
+        i += len;
+    }
+
+    Ok(tokens)
+}
+
+// Binary operators from the lowest to the highest precedence.
+const PRECEDENCE: [&[Op]; 6] = [
+    &[Op::Or],
+    &[Op::And],
+    &[Op::Eq, Op::Ne, Op::Lt, Op::Le, Op::Gt, Op::Ge],
+    &[Op::BitOr],
+    &[Op::BitXor],
+    &[Op::BitAnd],
+];
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), Error> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            token => Err(format!("Expected {:?}, got {:?}", expected, token).into()),
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, Error> {
+        if level == PRECEDENCE.len() {
+            return self.additive();
+        }
+
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(Token::Op(op)) = self.peek() {
+            let op = *op;
+            if !PRECEDENCE[level].contains(&op) {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+
+        Ok(lhs)
+    }
+
+    fn additive(&mut self) -> Result<Expr, Error> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op(Op::Add)) => Op::Add,
+                Some(Token::Sub) => Op::Sub,
+                _ => break,
+            };
+            self.pos += 1;
+            let rhs = self.unary()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, Error> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::Sub) => Ok(Expr::Neg(Box::new(self.unary()?))),
+            Some(Token::Number(number)) => Ok(Expr::Number(number)),
+            Some(Token::LParen) => {
+                let expr = self.binary(0)?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            Some(Token::LBracket) => {
+                let expr = self.binary(0)?;
+                self.expect(Token::RBracket)?;
+                Ok(Expr::Memory(Box::new(expr)))
+            }
+            Some(Token::Name(name)) => {
+                let name = name.to_uppercase();
+                if let Some(variable) = parse_variable(&name) {
+                    Ok(Expr::Variable(variable))
+                } else if let Some((_, loc)) = IO_REGISTERS.iter().find(|(n, _)| *n == name) {
+                    Ok(Expr::Memory(Box::new(Expr::Number(*loc as i64))))
+                } else {
+                    Err(format!("Unknown name in expression: {}", name).into())
+                }
+            }
+            token => Err(format!("Unexpected token in expression: {:?}", token).into()),
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(raw: &str) -> Result<Expr, Error> {
+        let mut parser = Parser {
+            tokens: tokenize(raw)?,
+            pos: 0,
+        };
+        let expr = parser.binary(0)?;
+
+        if let Some(token) = parser.peek() {
+            return Err(format!("Unexpected token in expression: {:?}", token).into());
+        }
+
+        Ok(expr)
+    }
+
+    pub fn eval(&self, ctx: &mut dyn ExprContext) -> i64 {
+        match self {
+            Expr::Number(number) => *number,
+            Expr::Variable(variable) => ctx.variable(*variable),
+            Expr::Memory(loc) => {
+                let loc = loc.eval(ctx);
+                ctx.read_memory(loc as u16) as i64
+            }
+            Expr::Not(expr) => (expr.eval(ctx) == 0) as i64,
+            Expr::Neg(expr) => expr.eval(ctx).wrapping_neg(),
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(ctx);
+                // Short circuit, so memory is not read needlessly.
+                match op {
+                    Op::Or if lhs != 0 => return 1,
+                    Op::And if lhs == 0 => return 0,
+                    _ => {}
+                }
+                let rhs = rhs.eval(ctx);
+
+                match op {
+                    Op::Or | Op::And => (rhs != 0) as i64,
+                    Op::Eq => (lhs == rhs) as i64,
+                    Op::Ne => (lhs != rhs) as i64,
+                    Op::Lt => (lhs < rhs) as i64,
+                    Op::Le => (lhs <= rhs) as i64,
+                    Op::Gt => (lhs > rhs) as i64,
+                    Op::Ge => (lhs >= rhs) as i64,
+                    Op::BitOr => lhs | rhs,
+                    Op::BitXor => lhs ^ rhs,
+                    Op::BitAnd => lhs & rhs,
+                    Op::Add => lhs.wrapping_add(rhs),
+                    Op::Sub => lhs.wrapping_sub(rhs),
+                }
+            }
+        }
+    }
+
+    pub fn is_true(&self, ctx: &mut dyn ExprContext) -> bool {
+        self.eval(ctx) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::expr::*;
+
+    struct TestContext {
+        hl: u16,
+        mem: [u8; 0x10000],
+    }
+
+    impl ExprContext for TestContext {
+        fn variable(&mut self, variable: Variable) -> i64 {
+            match variable {
+                Variable::A => 0x3F,
+                Variable::HL => self.hl as i64,
+                Variable::FlagZ => 1,
+                _ => 0,
+            }
+        }
+
+        fn read_memory(&mut self, loc: u16) -> u8 {
+            self.mem[loc as usize]
+        }
+    }
+
+    #[test]
+    fn test_eval() {
+        let mut ctx = TestContext {
+            hl: 0xC000,
+            mem: [0; 0x10000],
+        };
+        ctx.mem[0xC000] = 11;
+        ctx.mem[0xC001] = 0xC0;
+        ctx.mem[MEM_LOC_LY as usize] = 144;
+
+        let cases = [
+            ("A == 0x3F && [HL] > 10 && LY == 144", 1),
+            ("a == 0x3f && [hl] > 11", 0),
+            ("[HL + 1] & 0xF0", 0xC0),
+            ("1 + 2 == 3 || [0] == 1", 1),
+            ("!FZ", 0),
+            ("-(2 - 5)", 3),
+            ("(1 | 2) ^ 1", 2),
+        ];
+        for (raw, expected) in cases {
+            assert_eq!(
+                expected,
+                Expr::parse(raw).unwrap().eval(&mut ctx),
+                "{}",
+                raw
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Expr::parse("A ==").is_err());
+        assert!(Expr::parse("[HL").is_err());
+        assert!(Expr::parse("XYZ == 1").is_err());
+        assert!(Expr::parse("1 2").is_err());
+        assert!(Expr::parse("0xZZ").is_err());
+    }
+}
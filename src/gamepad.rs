@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+
+use sdl2::controller::{Axis, Button as PadButton, GameController};
+use sdl2::event::Event;
+use sdl2::{EventPump, GameControllerSubsystem};
+
+use crate::conf::Error;
+use crate::input_config::InputConfig;
+use crate::joypad::{Button, JoypadInputRequest};
+
+/**
+ * SDL2 game controllers. Lives on the VM thread (SDL is initialized there for audio) and is polled at frame
+ * boundaries together with the keyboard state.
+ */
+pub struct Gamepad {
+    subsystem: GameControllerSubsystem,
+    event_pump: EventPump,
+    // Open controllers by SDL instance id.
+    controllers: HashMap<u32, GameController>,
+    bindings: Vec<(PadButton, Button)>,
+    turbo_bindings: Vec<(PadButton, Button)>,
+    stick: bool,
+    deadzone: i16,
+}
+
+impl Gamepad {
+    pub fn new(config: &InputConfig) -> Result<Gamepad, Error> {
+        let sdl_context = sdl2::init()?;
+        let subsystem = sdl_context.game_controller()?;
+        let event_pump = sdl_context.event_pump()?;
+
+        Ok(Gamepad {
+            subsystem,
+            event_pump,
+            controllers: HashMap::new(),
+            bindings: config.pad_buttons.clone(),
+            turbo_bindings: config.pad_turbo_buttons.clone(),
+            stick: config.pad_stick,
+            deadzone: config.pad_deadzone,
+        })
+    }
+
+    /**
+     * Handles hotplug events and returns the buttons and turbo buttons held on any of the connected controllers.
+     */
+    pub fn poll(&mut self) -> (JoypadInputRequest, JoypadInputRequest) {
+        for event in self.event_pump.poll_iter() {
+            match event {
+                // Also sent at startup for the already connected controllers.
+                Event::ControllerDeviceAdded { which, .. } => match self.subsystem.open(which) {
+                    Ok(controller) => {
+                        log::info!("Gamepad connected: {}", controller.name());
+                        self.controllers
+                            .insert(controller.instance_id(), controller);
+                    }
+                    Err(err) => log::error!("Failed opening gamepad #{}: {}", which, err),
+                },
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    if let Some(controller) = self.controllers.remove(&which) {
+                        log::info!("Gamepad disconnected: {}", controller.name());
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut input = JoypadInputRequest::new();
+        let mut turbo = JoypadInputRequest::new();
+        for controller in self.controllers.values() {
+            for (pad_button, button) in &self.bindings {
+                if controller.button(*pad_button) {
+                    input.set(*button, true);
+                }
+            }
+            for (pad_button, button) in &self.turbo_bindings {
+                if controller.button(*pad_button) {
+                    turbo.set(*button, true);
+                }
+            }
+
+            if self.stick {
+                let x = controller.axis(Axis::LeftX);
+                let y = controller.axis(Axis::LeftY);
+                if x < -self.deadzone {
+                    input.left = true;
+                }
+                if x > self.deadzone {
+                    input.right = true;
+                }
+                if y < -self.deadzone {
+                    input.up = true;
+                }
+                if y > self.deadzone {
+                    input.down = true;
+                }
+            }
+        }
+
+        (input, turbo)
+    }
+}
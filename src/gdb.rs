@@ -0,0 +1,517 @@
+/**
+ * GDB remote serial protocol server, replacing the stdin REPL with `--gdb <port>`.
+ *
+ * Registers are AF, BC, DE, HL, SP, PC as 16 bit little-endian words, the first registers of GDB's z80 layout
+ * (`set architecture z80`). Memory goes through the bus, breakpoints and watchpoints are the debugger's.
+ */
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::thread::spawn;
+
+use crate::conf::*;
+use crate::debugger::*;
+
+pub const REGISTER_COUNT: usize = 6;
+
+// Biggest memory read / write served at once.
+const MAX_MEMORY_LEN: usize = 0x800;
+
+/**
+ * Access to the emulated machine while stopped.
+ */
+pub trait GdbTarget {
+    fn registers(&self) -> [u16; REGISTER_COUNT];
+    fn set_register(&mut self, register: usize, value: u16);
+    fn read_memory(&mut self, addr: u16) -> u8;
+    fn write_memory(&mut self, addr: u16, byte: u8) -> Result<(), Error>;
+    fn debugger(&mut self) -> &mut Debugger;
+}
+
+#[derive(PartialEq, Debug)]
+enum Input {
+    Packet(String),
+    // Checksum mismatch, to be resent.
+    Corrupted,
+    // Ctrl-C while running.
+    Interrupt,
+}
+
+/**
+ * `$<data>#<checksum>` packets out of the byte stream. Acks (`+` / `-`) are ignored.
+ */
+#[derive(Default)]
+struct PacketDecoder {
+    data: Option<Vec<u8>>,
+    // Checksum digits, once `#` has been read.
+    checksum: Option<Vec<u8>>,
+}
+
+impl PacketDecoder {
+    fn feed(&mut self, byte: u8) -> Option<Input> {
+        if let Some(checksum) = self.checksum.as_mut() {
+            checksum.push(byte);
+            if checksum.len() < 2 {
+                return None;
+            }
+
+            let expected = u8::from_str_radix(&String::from_utf8_lossy(checksum), 16).ok();
+            let data = self.data.take().unwrap_or_default();
+            self.checksum = None;
+            return if expected == Some(checksum_of(&data)) {
+                Some(Input::Packet(String::from_utf8_lossy(&data).into_owned()))
+            } else {
+                Some(Input::Corrupted)
+            };
+        }
+
+        match (self.data.as_mut(), byte) {
+            (None, 0x03) => Some(Input::Interrupt),
+            (None, b'$') | (Some(_), b'$') => {
+                self.data = Some(vec![]);
+                None
+            }
+            (None, _) => None,
+            (Some(_), b'#') => {
+                self.checksum = Some(vec![]);
+                None
+            }
+            (Some(data), _) => {
+                data.push(byte);
+                None
+            }
+        }
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex_bytes(raw: &str) -> Result<Vec<u8>, Error> {
+    if !raw.is_ascii() {
+        return Err("Non hex digits".into());
+    }
+    if raw.len() & 1 != 0 {
+        return Err("Odd hex length".into());
+    }
+    (0..raw.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&raw[i..i + 2], 16)?))
+        .collect()
+}
+
+fn parse_addr(raw: &str) -> Result<u16, Error> {
+    Ok(u16::from_str_radix(raw, 16)?)
+}
+
+/**
+ * `<addr>,<len>` of memory and breakpoint packets.
+ */
+fn parse_range(raw: &str) -> Result<(u16, usize), Error> {
+    let (addr, len) = raw.split_once(',').ok_or("Missing length")?;
+    Ok((parse_addr(addr)?, usize::from_str_radix(len, 16)?))
+}
+
+struct Connection {
+    stream: TcpStream,
+    inputs: Receiver<Input>,
+}
+
+pub struct GdbStub {
+    listener: TcpListener,
+    connection: Option<Connection>,
+    breakpoint_flag: Arc<AtomicBool>,
+    interrupted: Arc<AtomicBool>,
+    // A stop reply is owed for the last step / continue.
+    is_running: bool,
+}
+
+impl GdbStub {
+    /**
+     * `breakpoint_flag` is the debugger's, raised on Ctrl-C from GDB.
+     */
+    pub fn new(port: u16, breakpoint_flag: Arc<AtomicBool>) -> Result<GdbStub, Error> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        Ok(GdbStub {
+            listener,
+            connection: None,
+            breakpoint_flag,
+            interrupted: Arc::new(AtomicBool::new(false)),
+            is_running: false,
+        })
+    }
+
+    fn accept(&mut self) -> Result<(), Error> {
+        log::info!("Waiting for GDB on {}", self.listener.local_addr()?);
+        let (stream, peer) = self.listener.accept()?;
+        stream.set_nodelay(true)?;
+        log::info!("GDB connected from {}", peer);
+
+        let (sender, inputs) = channel();
+        let mut reader = stream.try_clone()?;
+        let breakpoint_flag = self.breakpoint_flag.clone();
+        let interrupted = self.interrupted.clone();
+        spawn(move || {
+            let mut decoder = PacketDecoder::default();
+            let mut buf = [0u8; 1024];
+            while let Ok(len @ 1..) = reader.read(&mut buf) {
+                for byte in &buf[..len] {
+                    let input = match decoder.feed(*byte) {
+                        Some(input) => input,
+                        None => continue,
+                    };
+                    let ack: &[u8] = match input {
+                        Input::Packet(_) => b"+",
+                        Input::Corrupted => b"-",
+                        Input::Interrupt => {
+                            interrupted.store(true, Ordering::Relaxed);
+                            breakpoint_flag.store(true, Ordering::Relaxed);
+                            b""
+                        }
+                    };
+                    if reader.write_all(ack).is_err() || sender.send(input).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        self.connection = Some(Connection { stream, inputs });
+        self.is_running = false;
+        Ok(())
+    }
+
+    fn send(&mut self, data: &str) -> Result<(), Error> {
+        if let Some(connection) = self.connection.as_mut() {
+            let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+            connection.stream.write_all(packet.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn stop_reply(&mut self, target: &mut dyn GdbTarget) -> String {
+        if self.interrupted.swap(false, Ordering::Relaxed) {
+            return "S02".to_string();
+        }
+
+        if let Some(DebugEvent::IllegalOpcode(_)) = target.debugger().take_event_hit() {
+            return "S04".to_string();
+        }
+
+        match target.debugger().take_watchpoint_hit() {
+            Some((kind, addr)) => {
+                let reason = match kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T05{}:{:04x};", reason, addr)
+            }
+            None => "S05".to_string(),
+        }
+    }
+
+    /**
+     * Serves GDB while the VM is stopped, until it resumes, detaches or kills. Waits for a connection if needed.
+     */
+    pub fn serve(&mut self, target: &mut dyn GdbTarget) -> Result<DebugCmd, Error> {
+        if self.connection.is_none() {
+            self.accept()?;
+        }
+
+        if self.is_running {
+            self.is_running = false;
+            let reply = self.stop_reply(target);
+            self.send(&reply)?;
+        }
+
+        loop {
+            let input = self
+                .connection
+                .as_ref()
+                .map(|connection| connection.inputs.recv());
+            match input {
+                Some(Ok(Input::Packet(packet))) => {
+                    if let Some(cmd) = self.handle(&packet, target)? {
+                        return Ok(cmd);
+                    }
+                }
+                // Ctrl-C while already stopped.
+                Some(Ok(_)) => {
+                    self.interrupted.store(false, Ordering::Relaxed);
+                    self.breakpoint_flag.store(false, Ordering::Relaxed);
+                }
+                _ => {
+                    log::info!("GDB disconnected");
+                    self.connection = None;
+                    target.debugger().clear_steps_and_continue();
+                    return Ok(DebugCmd::Continue);
+                }
+            }
+        }
+    }
+
+    fn handle(
+        &mut self,
+        packet: &str,
+        target: &mut dyn GdbTarget,
+    ) -> Result<Option<DebugCmd>, Error> {
+        let (cmd, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+
+        let reply = match cmd {
+            "?" => self.stop_reply(target),
+            "g" => {
+                let bytes = target
+                    .registers()
+                    .iter()
+                    .flat_map(|value| value.to_le_bytes())
+                    .collect::<Vec<_>>();
+                hex_bytes(&bytes)
+            }
+            "G" => match parse_hex_bytes(args) {
+                Ok(bytes) if bytes.len() >= REGISTER_COUNT * 2 => {
+                    for (i, word) in bytes.chunks(2).take(REGISTER_COUNT).enumerate() {
+                        target.set_register(i, u16::from_le_bytes([word[0], word[1]]));
+                    }
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(i) if i < REGISTER_COUNT => hex_bytes(&target.registers()[i].to_le_bytes()),
+                // Registers of the z80 layout which don't exist here.
+                Ok(_) => "xxxx".to_string(),
+                Err(_) => "E01".to_string(),
+            },
+            "P" => {
+                let register = args.split_once('=').and_then(|(i, value)| {
+                    let i = usize::from_str_radix(i, 16).ok()?;
+                    match parse_hex_bytes(value).ok()?[..] {
+                        [low, high, ..] => Some((i, u16::from_le_bytes([low, high]))),
+                        _ => None,
+                    }
+                });
+                match register {
+                    Some((i, value)) if i < REGISTER_COUNT => {
+                        target.set_register(i, value);
+                        "OK".to_string()
+                    }
+                    Some(_) => "OK".to_string(),
+                    None => "E01".to_string(),
+                }
+            }
+            "m" => match parse_range(args) {
+                Ok((addr, len)) if len <= MAX_MEMORY_LEN => {
+                    let bytes = (0..len as u16)
+                        .map(|i| target.read_memory(addr.wrapping_add(i)))
+                        .collect::<Vec<_>>();
+                    hex_bytes(&bytes)
+                }
+                _ => "E01".to_string(),
+            },
+            "M" => {
+                let write = args.split_once(':').and_then(|(range, data)| {
+                    Some((parse_range(range).ok()?.0, parse_hex_bytes(data).ok()?))
+                });
+                match write {
+                    Some((addr, bytes)) => {
+                        let result = bytes.iter().enumerate().try_for_each(|(i, byte)| {
+                            target.write_memory(addr.wrapping_add(i as u16), *byte)
+                        });
+                        match result {
+                            Ok(_) => "OK".to_string(),
+                            Err(err) => {
+                                log::warn!("GDB memory write failed: {}", err);
+                                "E02".to_string()
+                            }
+                        }
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            "Z" | "z" => self.handle_breakpoint(cmd == "Z", args, target),
+            "s" | "c" => {
+                if !args.is_empty() {
+                    match parse_addr(args) {
+                        Ok(addr) => target.set_register(REGISTER_COUNT - 1, addr),
+                        Err(_) => {
+                            self.send("E01")?;
+                            return Ok(None);
+                        }
+                    }
+                }
+
+                self.is_running = true;
+                return Ok(Some(if cmd == "s" {
+                    target.debugger().set_step_by_step();
+                    DebugCmd::Step
+                } else {
+                    target.debugger().clear_steps_and_continue();
+                    DebugCmd::Continue
+                }));
+            }
+            "D" => {
+                self.send("OK")?;
+                log::info!("GDB detached");
+                self.connection = None;
+                target.debugger().clear_steps_and_continue();
+                return Ok(Some(DebugCmd::Continue));
+            }
+            "k" => return Ok(Some(DebugCmd::Quit)),
+            "H" | "T" => "OK".to_string(),
+            "q" => match args.split(':').next().unwrap_or("") {
+                "Supported" => format!("PacketSize={:x}", MAX_MEMORY_LEN * 2 + 16),
+                "Attached" => "1".to_string(),
+                "C" => "QC1".to_string(),
+                "fThreadInfo" => "m1".to_string(),
+                "sThreadInfo" => "l".to_string(),
+                _ => String::new(),
+            },
+            // Unsupported, including `vCont`: GDB falls back to `s` / `c`.
+            _ => String::new(),
+        };
+
+        self.send(&reply)?;
+        Ok(None)
+    }
+
+    /**
+     * `Z<type>,<addr>,<kind>`: 0 / 1 breakpoint, 2 write, 3 read, 4 access watchpoint.
+     */
+    fn handle_breakpoint(
+        &mut self,
+        is_insert: bool,
+        args: &str,
+        target: &mut dyn GdbTarget,
+    ) -> String {
+        let (kind, range) = match args.split_once(',') {
+            Some(parts) => parts,
+            None => return "E01".to_string(),
+        };
+        let (addr, len) = match parse_range(range) {
+            Ok((addr, len)) if len <= 0x10000 => (addr, len),
+            _ => return "E01".to_string(),
+        };
+
+        let watch_kind = match kind {
+            "0" | "1" => None,
+            "2" => Some(WatchKind::Write),
+            "3" => Some(WatchKind::Read),
+            "4" => Some(WatchKind::Access),
+            _ => return String::new(),
+        };
+
+        let debugger = target.debugger();
+        match (watch_kind, is_insert) {
+            (None, true) => debugger.add_conditional_breakpoint(addr, None, Condition::default()),
+            (None, false) => debugger.remove_breakpoint(addr),
+            (Some(kind), true) => debugger.add_watchpoint(Watchpoint {
+                kind,
+                from: addr,
+                to: addr.saturating_add((len.max(1) - 1) as u16),
+                value: None,
+                condition: Condition::default(),
+            }),
+            (Some(kind), false) => debugger.remove_watchpoint(kind, addr),
+        }
+        "OK".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gdb::*;
+
+    fn decode(raw: &[u8]) -> Vec<Input> {
+        let mut decoder = PacketDecoder::default();
+        raw.iter().filter_map(|byte| decoder.feed(*byte)).collect()
+    }
+
+    #[test]
+    fn test_decode_packets() {
+        assert_eq!(
+            vec![
+                Input::Packet("g".to_string()),
+                Input::Interrupt,
+                Input::Packet("m100,2".to_string()),
+                Input::Corrupted,
+            ],
+            decode(b"+$g#67\x03-$m100,2#5c$g#00")
+        );
+    }
+
+    #[test]
+    fn test_parse_packet_args() {
+        assert_eq!(0x37, checksum_of(b"qSupported"));
+        assert_eq!(vec![0x3E, 0x00, 0xFF], parse_hex_bytes("3e00ff").unwrap());
+        assert!(parse_hex_bytes("3e0").is_err());
+        assert!(parse_hex_bytes("é0").is_err());
+        assert_eq!((0xC000, 0x10), parse_range("c000,10").unwrap());
+        assert_eq!("01b0", hex_bytes(&0xB001u16.to_le_bytes()));
+    }
+
+    struct TestTarget {
+        registers: [u16; REGISTER_COUNT],
+        debugger: Debugger,
+    }
+
+    impl GdbTarget for TestTarget {
+        fn registers(&self) -> [u16; REGISTER_COUNT] {
+            self.registers
+        }
+
+        fn set_register(&mut self, register: usize, value: u16) {
+            self.registers[register] = value;
+        }
+
+        fn read_memory(&mut self, _addr: u16) -> u8 {
+            0
+        }
+
+        fn write_memory(&mut self, _addr: u16, _byte: u8) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn debugger(&mut self) -> &mut Debugger {
+            &mut self.debugger
+        }
+    }
+
+    #[test]
+    fn test_malformed_packets() {
+        let mut stub = GdbStub::new(0, Arc::new(AtomicBool::new(false))).unwrap();
+        let mut client = TcpStream::connect(stub.listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = stub.listener.accept().unwrap();
+        stub.connection = Some(Connection {
+            stream,
+            inputs: channel().1,
+        });
+        let mut target = TestTarget {
+            registers: [0; REGISTER_COUNT],
+            debugger: Debugger::new(Arc::new(AtomicBool::new(false))),
+        };
+
+        let mut reply = |packet: &str| {
+            assert!(stub.handle(packet, &mut target).unwrap().is_none());
+            let mut buf = [0u8; 64];
+            let len = client.read(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf[..len]).into_owned()
+        };
+        assert_eq!("$E01#a6", reply("P5="));
+        assert_eq!("$E01#a6", reply("P5=3"));
+        assert_eq!("$E01#a6", reply("P5=01"));
+        assert_eq!("$E01#a6", reply("P5=é0"));
+        assert_eq!("$#00", reply("é"));
+        assert_eq!("$E01#a6", reply("Z2,c000,10001"));
+        assert_eq!("$OK#9a", reply("Z2,c000,10000"));
+        assert_eq!("$OK#9a", reply("P5=0201"));
+        assert_eq!(0x0102, target.registers[5]);
+    }
+}
@@ -16,7 +16,17 @@ use std::{
     time::Instant,
 };
 
-use crate::{conf::*, joypad::JoypadInputRequest, ppu::PPU};
+use crate::{
+    conf::*,
+    debugger::MemoryView,
+    disasm::DisassemblyView,
+    input_macro::InputHotkeys,
+    joypad::{Button, JoypadInputRequest},
+    ppu::PPU,
+    speed::SpeedControl,
+    timeline::{EventKind, TimelineEvent, TIMELINE_DOTS, TIMELINE_LINES},
+    vm::{CpuLock, DebugViews, Interrupt},
+};
 
 use log::error;
 use pixels::{
@@ -34,6 +44,21 @@ use winit::{
 };
 use winit_input_helper::WinitInputHelper;
 
+const TIMELINE_DOT_WIDTH: f32 = 1.0;
+const TIMELINE_LINE_HEIGHT: f32 = 2.0;
+
+fn timeline_color(kind: &EventKind) -> [f32; 4] {
+    match kind {
+        EventKind::InterruptRequest(_) => [1.0, 0.4, 0.4, 1.0],
+        EventKind::InterruptService(_) => [1.0, 0.9, 0.3, 1.0],
+        EventKind::StatMode(_) => [0.4, 0.5, 0.6, 1.0],
+        EventKind::LycMatch => [0.3, 0.9, 0.9, 1.0],
+        EventKind::OamDma(_) => [0.8, 0.4, 1.0, 1.0],
+        EventKind::RomBank(_) => [0.4, 1.0, 0.4, 1.0],
+        EventKind::RegisterWrite(_, _) => [1.0, 0.6, 0.2, 1.0],
+    }
+}
+
 struct ImguiService {
     imgui: imgui::Context,
     platform: imgui_winit_support::WinitPlatform,
@@ -42,17 +67,19 @@ struct ImguiService {
     last_cursor: Option<imgui::MouseCursor>,
     show_ui: bool,
     vm_debug_log: Arc<RwLock<Vec<String>>>,
+    vm_disassembly: Arc<RwLock<DisassemblyView>>,
+    // PC the disassembly was last scrolled to.
+    disassembly_scroll_pc: Option<u16>,
+    vm_memory: Arc<RwLock<MemoryView>>,
+    // Address field of the hex view.
+    memory_address: String,
+    vm_timeline: Arc<RwLock<Vec<TimelineEvent>>>,
+    vm_cpu_lock: Arc<RwLock<Option<CpuLock>>>,
     global_should_generate_vm_debug_log: Arc<AtomicBool>,
 }
 
 impl ImguiService {
-    fn new(
-        window: &Window,
-        pixels: &Pixels,
-        show_ui: bool,
-        vm_debug_log: Arc<RwLock<Vec<String>>>,
-        global_should_generate_vm_debug_log: Arc<AtomicBool>,
-    ) -> ImguiService {
+    fn new(window: &Window, pixels: &Pixels, show_ui: bool, views: DebugViews) -> ImguiService {
         let mut imgui = imgui::Context::create();
         imgui.set_ini_filename(None);
 
@@ -98,8 +125,14 @@ impl ImguiService {
             last_frame: Instant::now(),
             last_cursor: None,
             show_ui,
-            vm_debug_log,
-            global_should_generate_vm_debug_log,
+            vm_debug_log: views.debug_log,
+            vm_disassembly: views.disassembly,
+            disassembly_scroll_pc: None,
+            vm_memory: views.memory,
+            memory_address: String::from("C000"),
+            vm_timeline: views.timeline,
+            vm_cpu_lock: views.cpu_lock,
+            global_should_generate_vm_debug_log: views.should_generate_debug_log,
         }
     }
 
@@ -125,6 +158,20 @@ impl ImguiService {
             self.platform.prepare_render(ui, window);
         }
 
+        // Shown with or without the debug panel.
+        if let Some(lock) = self.vm_cpu_lock.read().unwrap().as_ref() {
+            ui.window("CPU locked")
+                .position([10.0, 10.0], imgui::Condition::FirstUseEver)
+                .always_auto_resize(true)
+                .build(|| {
+                    ui.text_colored(
+                        [1.0, 0.3, 0.3, 1.0],
+                        format!("{} at {:04X}", lock.reason, lock.pc),
+                    );
+                    ui.text("The CPU is hung, the LCD, sound and timer keep running.");
+                });
+        }
+
         if self.show_ui {
             ui.window("VM Debug")
                 .position([0.0, 0.0], imgui::Condition::Once)
@@ -138,6 +185,158 @@ impl ImguiService {
                         .for_each(|line| ui.text(line));
                 });
 
+            ui.window("Disassembly")
+                .position([0.0, 250.0], imgui::Condition::Once)
+                .size([300.0, 320.0], imgui::Condition::FirstUseEver)
+                .build(|| {
+                    let disassembly = self.vm_disassembly.read().unwrap();
+                    // Follows PC when it moves, otherwise free to scroll.
+                    let should_scroll = self.disassembly_scroll_pc != Some(disassembly.pc);
+                    for (addr, line) in disassembly.lines.iter() {
+                        if *addr == Some(disassembly.pc) {
+                            ui.text_colored([1.0, 0.9, 0.3, 1.0], format!("> {}", line));
+                            if should_scroll {
+                                ui.set_scroll_here_y_with_ratio(0.5);
+                            }
+                        } else {
+                            ui.text(format!("  {}", line));
+                        }
+                    }
+                    self.disassembly_scroll_pc = Some(disassembly.pc);
+                });
+
+            ui.window("Memory")
+                .position([310.0, 250.0], imgui::Condition::Once)
+                .size([520.0, 320.0], imgui::Condition::FirstUseEver)
+                .build(|| {
+                    let mut view = self.vm_memory.write().unwrap();
+                    ui.set_next_item_width(48.0);
+                    if ui
+                        .input_text("Address", &mut self.memory_address)
+                        .chars_hexadecimal(true)
+                        .enter_returns_true(true)
+                        .build()
+                    {
+                        if let Ok(from) = u16::from_str_radix(&self.memory_address, 16) {
+                            view.from = from & 0xFFF0;
+                            view.bytes.clear();
+                        }
+                    }
+                    ui.same_line();
+                    ui.checkbox("Raw (no MBC registers)", &mut view.raw);
+
+                    // A byte is written when its cell is edited and Enter is pressed.
+                    let mut edits = vec![];
+                    for (row, bytes) in view.bytes.chunks(16).enumerate() {
+                        let row_addr = view.from.wrapping_add(row as u16 * 16);
+                        ui.text(format!("{:04X}", row_addr));
+                        for (i, byte) in bytes.iter().enumerate() {
+                            let loc = row_addr.wrapping_add(i as u16);
+                            let mut cell = format!("{:02X}", byte);
+                            ui.same_line();
+                            ui.set_next_item_width(22.0);
+                            if ui
+                                .input_text(format!("##{:04X}", loc), &mut cell)
+                                .chars_hexadecimal(true)
+                                .enter_returns_true(true)
+                                .build()
+                            {
+                                if let Ok(byte) = u8::from_str_radix(cell.trim(), 16) {
+                                    edits.push((loc, byte));
+                                }
+                            }
+                        }
+                    }
+                    view.edits.extend(edits);
+                });
+
+            ui.window("Event timeline")
+                .position([830.0, 0.0], imgui::Condition::Once)
+                .size([480.0, 440.0], imgui::Condition::FirstUseEver)
+                .build(|| {
+                    let events = self.vm_timeline.read().unwrap();
+                    let origin = ui.cursor_screen_pos();
+                    let (width, height) = (
+                        TIMELINE_DOTS as f32 * TIMELINE_DOT_WIDTH,
+                        TIMELINE_LINES as f32 * TIMELINE_LINE_HEIGHT,
+                    );
+                    let at = |ly: f32, dot: f32| {
+                        [
+                            origin[0] + dot * TIMELINE_DOT_WIDTH,
+                            origin[1] + ly * TIMELINE_LINE_HEIGHT,
+                        ]
+                    };
+
+                    let draw_list = ui.get_window_draw_list();
+                    draw_list
+                        .add_rect(
+                            origin,
+                            at(TIMELINE_LINES as f32, TIMELINE_DOTS as f32),
+                            [0.1, 0.1, 0.1, 1.0],
+                        )
+                        .filled(true)
+                        .build();
+                    // VBlank lines and the end of mode 2.
+                    draw_list
+                        .add_rect(
+                            at(144.0, 0.0),
+                            at(TIMELINE_LINES as f32, TIMELINE_DOTS as f32),
+                            [0.2, 0.2, 0.3, 1.0],
+                        )
+                        .filled(true)
+                        .build();
+                    draw_list
+                        .add_line(at(0.0, 80.0), at(144.0, 80.0), [0.3, 0.3, 0.3, 1.0])
+                        .build();
+                    for event in events.iter() {
+                        let p = at(event.ly as f32, event.dot as f32);
+                        draw_list
+                            .add_rect(
+                                [p[0] - 1.0, p[1]],
+                                [p[0] + 2.0, p[1] + TIMELINE_LINE_HEIGHT],
+                                timeline_color(&event.kind),
+                            )
+                            .filled(true)
+                            .build();
+                    }
+
+                    ui.invisible_button("timeline", [width, height]);
+                    if ui.is_item_hovered() {
+                        let mouse = ui.io().mouse_pos;
+                        let ly = ((mouse[1] - origin[1]) / TIMELINE_LINE_HEIGHT) as i32;
+                        let dot = (mouse[0] - origin[0]) / TIMELINE_DOT_WIDTH;
+                        ui.tooltip(|| {
+                            ui.text(format!("LY {} dot {}", ly, dot as i32));
+                            events
+                                .iter()
+                                .filter(|event| {
+                                    event.ly as i32 == ly && (event.dot as f32 - dot).abs() <= 8.0
+                                })
+                                .for_each(|event| {
+                                    ui.text_colored(timeline_color(&event.kind), event.describe())
+                                });
+                        });
+                    }
+
+                    for (kind, name) in [
+                        (
+                            EventKind::InterruptRequest(Interrupt::VBlank),
+                            "Interrupt requested",
+                        ),
+                        (
+                            EventKind::InterruptService(Interrupt::VBlank),
+                            "Interrupt serviced",
+                        ),
+                        (EventKind::StatMode(0), "STAT mode"),
+                        (EventKind::LycMatch, "LYC match"),
+                        (EventKind::OamDma(0), "OAM DMA"),
+                        (EventKind::RomBank(0), "ROM bank"),
+                        (EventKind::RegisterWrite(0, 0), "Scroll / palette write"),
+                    ] {
+                        ui.text_colored(timeline_color(&kind), format!("# {}", name));
+                    }
+                });
+
             if !self.show_ui {
                 self.global_should_generate_vm_debug_log
                     .store(false, Ordering::Relaxed);
@@ -165,6 +364,10 @@ impl ImguiService {
         )
     }
 
+    pub fn want_capture_keyboard(&self) -> bool {
+        self.imgui.io().want_capture_keyboard
+    }
+
     pub fn handle_event(&mut self, window: &Window, event: &Event<()>) {
         self.platform
             .handle_event(self.imgui.io_mut(), window, event);
@@ -194,18 +397,55 @@ fn make_window(
     (window, pixels)
 }
 
+/**
+ * Flags and input state shared with the VM thread, created in main.rs.
+ */
+pub struct GfxShared {
+    pub breakpoint_flag: Arc<AtomicBool>,
+    pub rewind_flag: Arc<AtomicBool>,
+    pub speed_control: Arc<SpeedControl>,
+    pub movie_anchor_flag: Arc<AtomicBool>,
+    pub buttons: Arc<RwLock<JoypadInputRequest>>,
+    pub input_hotkeys: Arc<InputHotkeys>,
+    pub views: DebugViews,
+}
+
+pub struct GfxOptions {
+    pub key_bindings: Vec<(VirtualKeyCode, Button)>,
+    pub turbo_key_bindings: Vec<(VirtualKeyCode, Button)>,
+    pub macro_key_bindings: Vec<(VirtualKeyCode, usize)>,
+    pub with_tile_debug_window: bool,
+    pub with_background_debug_window: bool,
+    pub with_window_debug_window: bool,
+    pub cartridge_title: String,
+}
+
 pub fn run(
     global_exit_flag: Arc<AtomicBool>,
     video: Arc<RwLock<PPU>>,
-    breakpoint_flag: Arc<AtomicBool>,
-    buttons: Arc<RwLock<JoypadInputRequest>>,
-    with_tile_debug_window: bool,
-    with_background_debug_window: bool,
-    with_window_debug_window: bool,
-    vm_debug_log: Arc<RwLock<Vec<String>>>,
-    global_should_generate_vm_debug_log: Arc<AtomicBool>,
-    catridge_title: String,
+    shared: GfxShared,
+    options: GfxOptions,
 ) {
+    let GfxShared {
+        breakpoint_flag,
+        rewind_flag,
+        speed_control,
+        movie_anchor_flag,
+        buttons,
+        input_hotkeys,
+        views,
+    } = shared;
+    let GfxOptions {
+        key_bindings,
+        turbo_key_bindings,
+        macro_key_bindings,
+        with_tile_debug_window,
+        with_background_debug_window,
+        with_window_debug_window,
+        cartridge_title,
+    } = options;
+    let global_should_generate_vm_debug_log = views.should_generate_debug_log.clone();
+
     let event_loop = EventLoop::new();
     let mut input = WinitInputHelper::new();
 
@@ -228,7 +468,7 @@ pub fn run(
         make_window(&event_loop, "(3) Window map (32 x 32)", 256, 256, show_win);
     let (main_window, main_pixels) = make_window(
         &event_loop,
-        format!("Lameboy <{}>", catridge_title).as_str(),
+        format!("Lameboy <{}>", cartridge_title).as_str(),
         DISPLAY_WIDTH,
         DISPLAY_HEIGHT,
         true,
@@ -239,13 +479,7 @@ pub fn run(
     video.write().unwrap().background_debug_window_id = Some(bg_window.id());
     video.write().unwrap().window_debug_window_id = Some(win_window.id());
 
-    let mut imgui_service = ImguiService::new(
-        &main_window,
-        &main_pixels,
-        false,
-        vm_debug_log,
-        global_should_generate_vm_debug_log.clone(),
-    );
+    let mut imgui_service = ImguiService::new(&main_window, &main_pixels, false, views);
 
     pixels_map.insert(tile_window.id(), tile_pixels);
     pixels_map.insert(bg_window.id(), bg_pixels);
@@ -313,78 +547,88 @@ pub fn run(
                 return;
             }
 
-            if input.key_released(VirtualKeyCode::I) {
-                imgui_service.show_ui = !imgui_service.show_ui;
-                global_should_generate_vm_debug_log.store(imgui_service.show_ui, Ordering::Relaxed);
-            }
+            // Typing into the debug UI (hex view, address field) doesn't reach hotkeys and the joypad.
+            if !imgui_service.want_capture_keyboard() {
+                if input.key_released(VirtualKeyCode::I) {
+                    imgui_service.show_ui = !imgui_service.show_ui;
+                    global_should_generate_vm_debug_log
+                        .store(imgui_service.show_ui, Ordering::Relaxed);
+                }
 
-            if input.key_released(VirtualKeyCode::Key1) {
-                show_tiles = !show_tiles;
-                tile_window.set_visible(show_tiles);
-            }
-            if input.key_released(VirtualKeyCode::Key2) {
-                show_bg = !show_bg;
-                bg_window.set_visible(show_bg);
-            }
-            if input.key_released(VirtualKeyCode::Key3) {
-                show_win = !show_win;
-                win_window.set_visible(show_win);
-            }
+                if input.key_released(VirtualKeyCode::Key1) {
+                    show_tiles = !show_tiles;
+                    tile_window.set_visible(show_tiles);
+                }
+                if input.key_released(VirtualKeyCode::Key2) {
+                    show_bg = !show_bg;
+                    bg_window.set_visible(show_bg);
+                }
+                if input.key_released(VirtualKeyCode::Key3) {
+                    show_win = !show_win;
+                    win_window.set_visible(show_win);
+                }
 
-            if input.key_pressed(VirtualKeyCode::B) {
-                breakpoint_flag.store(true, Ordering::Relaxed);
-            }
+                if input.key_pressed(VirtualKeyCode::B) {
+                    breakpoint_flag.store(true, Ordering::Relaxed);
+                }
 
-            if input.key_pressed(VirtualKeyCode::Z) {
-                buttons.write().expect("Cannot lock buttons").start = true;
-            }
-            if input.key_pressed(VirtualKeyCode::X) {
-                buttons.write().expect("Cannot lock buttons").select = true;
-            }
-            if input.key_pressed(VirtualKeyCode::N) {
-                buttons.write().expect("Cannot lock buttons").a = true;
-            }
-            if input.key_pressed(VirtualKeyCode::M) {
-                buttons.write().expect("Cannot lock buttons").b = true;
-            }
+                if input.key_pressed(VirtualKeyCode::Back) {
+                    rewind_flag.store(true, Ordering::Relaxed);
+                }
+                if input.key_released(VirtualKeyCode::Back) {
+                    rewind_flag.store(false, Ordering::Relaxed);
+                }
 
-            if input.key_pressed(VirtualKeyCode::Up) {
-                buttons.write().expect("Cannot lock buttons").up = true;
-            }
-            if input.key_pressed(VirtualKeyCode::Down) {
-                buttons.write().expect("Cannot lock buttons").down = true;
-            }
-            if input.key_pressed(VirtualKeyCode::Left) {
-                buttons.write().expect("Cannot lock buttons").left = true;
-            }
-            if input.key_pressed(VirtualKeyCode::Right) {
-                buttons.write().expect("Cannot lock buttons").right = true;
-            }
+                if input.key_pressed(VirtualKeyCode::Tab) {
+                    speed_control.set_fast_forward_hold(true);
+                }
+                if input.key_released(VirtualKeyCode::Tab) {
+                    speed_control.set_fast_forward_hold(false);
+                }
+                if input.key_pressed(VirtualKeyCode::F) {
+                    speed_control.toggle_fast_forward();
+                }
+                if input.key_pressed(VirtualKeyCode::S) {
+                    speed_control.cycle_slow_motion();
+                }
+                if input.key_pressed(VirtualKeyCode::P) {
+                    speed_control.toggle_pause();
+                }
+                if input.key_pressed(VirtualKeyCode::Period) {
+                    speed_control.request_frame_advance();
+                }
 
-            if input.key_released(VirtualKeyCode::Z) {
-                buttons.write().expect("Cannot lock buttons").start = false;
-            }
-            if input.key_released(VirtualKeyCode::X) {
-                buttons.write().expect("Cannot lock buttons").select = false;
-            }
-            if input.key_released(VirtualKeyCode::N) {
-                buttons.write().expect("Cannot lock buttons").a = false;
-            }
-            if input.key_released(VirtualKeyCode::M) {
-                buttons.write().expect("Cannot lock buttons").b = false;
-            }
+                if input.key_pressed(VirtualKeyCode::F9) {
+                    movie_anchor_flag.store(true, Ordering::Relaxed);
+                }
 
-            if input.key_released(VirtualKeyCode::Up) {
-                buttons.write().expect("Cannot lock buttons").up = false;
-            }
-            if input.key_released(VirtualKeyCode::Down) {
-                buttons.write().expect("Cannot lock buttons").down = false;
-            }
-            if input.key_released(VirtualKeyCode::Left) {
-                buttons.write().expect("Cannot lock buttons").left = false;
-            }
-            if input.key_released(VirtualKeyCode::Right) {
-                buttons.write().expect("Cannot lock buttons").right = false;
+                let mut held = JoypadInputRequest::new();
+                for (key, button) in &key_bindings {
+                    if input.key_held(*key) {
+                        held.set(*button, true);
+                    }
+                }
+                *buttons.write().expect("Cannot lock buttons") = held;
+
+                let mut turbo_held = JoypadInputRequest::new();
+                for (key, button) in &turbo_key_bindings {
+                    if input.key_held(*key) {
+                        turbo_held.set(*button, true);
+                    }
+                }
+                *input_hotkeys
+                    .turbo
+                    .write()
+                    .expect("Cannot lock turbo buttons") = turbo_held;
+
+                for (key, macro_i) in &macro_key_bindings {
+                    if input.key_pressed(*key) {
+                        *input_hotkeys
+                            .requested_macro
+                            .write()
+                            .expect("Cannot lock macro request") = Some(*macro_i);
+                    }
+                }
             }
 
             let main_window_had_updates = match video
@@ -0,0 +1,373 @@
+/**
+ * Key and gamepad bindings.
+ *
+ * Config file format, one binding per line, `#` starts a comment:
+ *
+ *   a = N, K            # Keyboard keys for a button (replaces the defaults of that button)
+ *   pad.a = a, x        # Gamepad buttons (SDL names: a b x y back guide start leftstick rightstick
+ *                       #   leftshoulder rightshoulder dpup dpdown dpleft dpright)
+ *   pad.stick = true    # Left analog stick acts as D-pad
+ *   pad.deadzone = 8000 # Analog stick deadzone (0..32767)
+ *   turbo_a = J         # Turbo variants of A and B, also as pad.turbo_a / pad.turbo_b
+ *   turbo.rate = 2      # Frames pressed, then frames released
+ *   macro.reset = 10 a+b+start+select, 2 -   # Steps of <frames> <buttons joined by +, or - for none>
+ *   macro.reset.key = F5
+ *
+ * Buttons: start, select, a, b, up, down, left, right.
+ */
+use std::fs::read_to_string;
+
+use sdl2::controller::Button as PadButton;
+use winit::event::VirtualKeyCode;
+
+use crate::conf::Error;
+use crate::input_macro::InputMacro;
+use crate::joypad::{Button, JoypadInputRequest};
+
+const BUTTONS: [Button; 8] = [
+    Button::Start,
+    Button::Select,
+    Button::B,
+    Button::A,
+    Button::Down,
+    Button::Up,
+    Button::Left,
+    Button::Right,
+];
+
+pub struct InputConfig {
+    pub keys: Vec<(VirtualKeyCode, Button)>,
+    pub pad_buttons: Vec<(PadButton, Button)>,
+    pub pad_stick: bool,
+    pub pad_deadzone: i16,
+    pub turbo_keys: Vec<(VirtualKeyCode, Button)>,
+    pub pad_turbo_buttons: Vec<(PadButton, Button)>,
+    pub turbo_rate: u32,
+    pub macros: Vec<InputMacro>,
+    pub macro_keys: Vec<(VirtualKeyCode, usize)>,
+}
+
+impl InputConfig {
+    pub fn new() -> InputConfig {
+        InputConfig {
+            keys: vec![
+                (VirtualKeyCode::Z, Button::Start),
+                (VirtualKeyCode::X, Button::Select),
+                (VirtualKeyCode::N, Button::A),
+                (VirtualKeyCode::M, Button::B),
+                (VirtualKeyCode::Up, Button::Up),
+                (VirtualKeyCode::Down, Button::Down),
+                (VirtualKeyCode::Left, Button::Left),
+                (VirtualKeyCode::Right, Button::Right),
+            ],
+            pad_buttons: vec![
+                (PadButton::Start, Button::Start),
+                (PadButton::Back, Button::Select),
+                (PadButton::A, Button::A),
+                (PadButton::B, Button::B),
+                (PadButton::DPadUp, Button::Up),
+                (PadButton::DPadDown, Button::Down),
+                (PadButton::DPadLeft, Button::Left),
+                (PadButton::DPadRight, Button::Right),
+            ],
+            pad_stick: true,
+            pad_deadzone: 8000,
+            turbo_keys: vec![
+                (VirtualKeyCode::J, Button::A),
+                (VirtualKeyCode::K, Button::B),
+            ],
+            pad_turbo_buttons: vec![(PadButton::X, Button::A), (PadButton::Y, Button::B)],
+            turbo_rate: 2,
+            macros: vec![],
+            macro_keys: vec![],
+        }
+    }
+
+    pub fn load(filename: &str) -> Result<InputConfig, Error> {
+        InputConfig::parse(&read_to_string(filename)?)
+    }
+
+    pub fn parse(raw: &str) -> Result<InputConfig, Error> {
+        let mut config = InputConfig::new();
+        // Macro keys are resolved once all macros are known.
+        let mut macro_keys = vec![];
+
+        for (line_i, line) in raw.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (name, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("Input config line {}: missing '='", line_i + 1))?;
+            let name = name.trim().to_lowercase();
+            let values = value
+                .split(',')
+                .map(|v| v.trim())
+                .filter(|v| !v.is_empty())
+                .collect::<Vec<_>>();
+
+            let invalid = |what: &str, v: &str| -> Error {
+                format!("Input config line {}: unknown {} '{}'", line_i + 1, what, v).into()
+            };
+
+            if name == "pad.stick" {
+                config.pad_stick = value.trim() == "true";
+            } else if name == "pad.deadzone" {
+                config.pad_deadzone = value
+                    .trim()
+                    .parse::<i16>()
+                    .ok()
+                    .filter(|deadzone| *deadzone >= 0)
+                    .ok_or_else(|| invalid("deadzone", value.trim()))?;
+            } else if name == "turbo.rate" {
+                config.turbo_rate = value
+                    .trim()
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|rate| *rate > 0)
+                    .ok_or_else(|| invalid("turbo rate", value.trim()))?;
+            } else if let Some(macro_name) = name.strip_prefix("macro.") {
+                if let Some(macro_name) = macro_name.strip_suffix(".key") {
+                    let key =
+                        key_from_name(value.trim()).ok_or_else(|| invalid("key", value.trim()))?;
+                    macro_keys.push((key, macro_name.to_string()));
+                } else {
+                    let steps = values
+                        .iter()
+                        .map(|v| parse_macro_step(v).ok_or_else(|| invalid("macro step", v)))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    config.macros.retain(|m| m.name != macro_name);
+                    config.macros.push(InputMacro {
+                        name: macro_name.to_string(),
+                        steps,
+                    });
+                }
+            } else if let Some(button_name) = name.strip_prefix("pad.") {
+                let (bindings, button) = match button_name.strip_prefix("turbo_") {
+                    Some(turbo_name) => (
+                        &mut config.pad_turbo_buttons,
+                        turbo_button_from_name(turbo_name),
+                    ),
+                    None => (&mut config.pad_buttons, button_from_name(button_name)),
+                };
+                let button = button.ok_or_else(|| invalid("button", button_name))?;
+                bindings.retain(|(_, b)| *b != button);
+                for v in values {
+                    let pad_button =
+                        pad_button_from_name(v).ok_or_else(|| invalid("pad button", v))?;
+                    bindings.push((pad_button, button));
+                }
+            } else {
+                let (bindings, button) = match name.strip_prefix("turbo_") {
+                    Some(turbo_name) => {
+                        (&mut config.turbo_keys, turbo_button_from_name(turbo_name))
+                    }
+                    None => (&mut config.keys, button_from_name(&name)),
+                };
+                let button = button.ok_or_else(|| invalid("button", &name))?;
+                bindings.retain(|(_, b)| *b != button);
+                for v in values {
+                    let key = key_from_name(v).ok_or_else(|| invalid("key", v))?;
+                    bindings.push((key, button));
+                }
+            }
+        }
+
+        for (key, macro_name) in macro_keys {
+            let macro_i = config
+                .macros
+                .iter()
+                .position(|m| m.name == macro_name)
+                .ok_or_else(|| {
+                    format!("Input config: key bound to unknown macro '{}'", macro_name)
+                })?;
+            config.macro_keys.push((key, macro_i));
+        }
+
+        Ok(config)
+    }
+}
+
+fn button_from_name(name: &str) -> Option<Button> {
+    BUTTONS
+        .iter()
+        .find(|button| format!("{:?}", button).to_lowercase() == name.to_lowercase())
+        .copied()
+}
+
+fn turbo_button_from_name(name: &str) -> Option<Button> {
+    button_from_name(name).filter(|button| *button == Button::A || *button == Button::B)
+}
+
+/**
+ * `<frames> <buttons>`, buttons joined by `+` or `-` for none. Example: `10 a+b+start+select`.
+ */
+fn parse_macro_step(raw: &str) -> Option<(u32, JoypadInputRequest)> {
+    let (frames, buttons) = raw.split_once(' ')?;
+    let frames = frames.parse::<u32>().ok().filter(|frames| *frames > 0)?;
+
+    let mut input = JoypadInputRequest::new();
+    let buttons = buttons.trim();
+    if buttons != "-" {
+        for name in buttons.split('+') {
+            input.set(button_from_name(name.trim())?, true);
+        }
+    }
+
+    Some((frames, input))
+}
+
+fn pad_button_from_name(name: &str) -> Option<PadButton> {
+    match name.to_lowercase().as_str() {
+        "a" => Some(PadButton::A),
+        "b" => Some(PadButton::B),
+        "x" => Some(PadButton::X),
+        "y" => Some(PadButton::Y),
+        "back" => Some(PadButton::Back),
+        "guide" => Some(PadButton::Guide),
+        "start" => Some(PadButton::Start),
+        "leftstick" => Some(PadButton::LeftStick),
+        "rightstick" => Some(PadButton::RightStick),
+        "leftshoulder" => Some(PadButton::LeftShoulder),
+        "rightshoulder" => Some(PadButton::RightShoulder),
+        "dpup" => Some(PadButton::DPadUp),
+        "dpdown" => Some(PadButton::DPadDown),
+        "dpleft" => Some(PadButton::DPadLeft),
+        "dpright" => Some(PadButton::DPadRight),
+        _ => None,
+    }
+}
+
+pub fn key_from_name(name: &str) -> Option<VirtualKeyCode> {
+    use VirtualKeyCode::*;
+
+    const LETTERS: [VirtualKeyCode; 26] = [
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    ];
+    const DIGITS: [VirtualKeyCode; 10] =
+        [Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9];
+    const NUMPAD: [VirtualKeyCode; 10] = [
+        Numpad0, Numpad1, Numpad2, Numpad3, Numpad4, Numpad5, Numpad6, Numpad7, Numpad8, Numpad9,
+    ];
+    const FUNCTION: [VirtualKeyCode; 12] = [F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12];
+
+    let name = name.to_lowercase();
+    let bytes = name.as_bytes();
+
+    if bytes.len() == 1 && bytes[0].is_ascii_lowercase() {
+        return Some(LETTERS[(bytes[0] - b'a') as usize]);
+    }
+    if bytes.len() == 1 && bytes[0].is_ascii_digit() {
+        return Some(DIGITS[(bytes[0] - b'0') as usize]);
+    }
+    if let Some(n) = name
+        .strip_prefix("numpad")
+        .and_then(|n| n.parse::<usize>().ok())
+    {
+        return NUMPAD.get(n).copied();
+    }
+    if let Some(n) = name.strip_prefix('f').and_then(|n| n.parse::<usize>().ok()) {
+        return n.checked_sub(1).and_then(|n| FUNCTION.get(n)).copied();
+    }
+
+    match name.as_str() {
+        "up" => Some(Up),
+        "down" => Some(Down),
+        "left" => Some(Left),
+        "right" => Some(Right),
+        "space" => Some(Space),
+        "return" | "enter" => Some(Return),
+        "back" | "backspace" => Some(Back),
+        "tab" => Some(Tab),
+        "lshift" => Some(LShift),
+        "rshift" => Some(RShift),
+        "lcontrol" => Some(LControl),
+        "rcontrol" => Some(RControl),
+        "lalt" => Some(LAlt),
+        "ralt" => Some(RAlt),
+        "comma" => Some(Comma),
+        "period" => Some(Period),
+        "slash" => Some(Slash),
+        "semicolon" => Some(Semicolon),
+        "apostrophe" => Some(Apostrophe),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input_config::*;
+
+    #[test]
+    fn test_parse_replaces_bindings_per_button() {
+        let config = InputConfig::parse(
+            "# Custom\n\
+             a = K, Space\n\
+             pad.b = x, y\n\
+             pad.deadzone = 1000 # Sensitive\n",
+        )
+        .unwrap();
+
+        let a_keys = config
+            .keys
+            .iter()
+            .filter(|(_, b)| *b == Button::A)
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>();
+        assert_eq!(vec![VirtualKeyCode::K, VirtualKeyCode::Space], a_keys);
+        assert!(config.keys.contains(&(VirtualKeyCode::M, Button::B)));
+        assert!(config.pad_buttons.contains(&(PadButton::Y, Button::B)));
+        assert!(!config.pad_buttons.contains(&(PadButton::B, Button::B)));
+        assert_eq!(1000, config.pad_deadzone);
+    }
+
+    #[test]
+    fn test_parse_turbo_and_macros() {
+        let config = InputConfig::parse(
+            "turbo_b = L\n\
+             turbo.rate = 3\n\
+             macro.reset.key = F5\n\
+             macro.reset = 10 a+b+start+select, 2 -\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                (VirtualKeyCode::J, Button::A),
+                (VirtualKeyCode::L, Button::B)
+            ],
+            config.turbo_keys
+        );
+        assert_eq!(3, config.turbo_rate);
+        assert_eq!(vec![(VirtualKeyCode::F5, 0)], config.macro_keys);
+        assert_eq!(
+            vec![
+                (10, JoypadInputRequest::from_byte(0xF0)),
+                (2, JoypadInputRequest::new())
+            ],
+            config.macros[0].steps
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(InputConfig::parse("jump = Space").is_err());
+        assert!(InputConfig::parse("a = Hyperspace").is_err());
+        assert!(InputConfig::parse("a Space").is_err());
+        assert!(InputConfig::parse("turbo_start = T").is_err());
+        assert!(InputConfig::parse("macro.x = 0 a").is_err());
+        assert!(InputConfig::parse("macro.x.key = F5").is_err());
+        assert!(InputConfig::parse("pad.deadzone = -32768").is_err());
+        assert!(InputConfig::parse("pad.deadzone = 32768").is_err());
+    }
+
+    #[test]
+    fn test_key_from_name() {
+        assert_eq!(Some(VirtualKeyCode::Q), key_from_name("q"));
+        assert_eq!(Some(VirtualKeyCode::Key7), key_from_name("7"));
+        assert_eq!(Some(VirtualKeyCode::F10), key_from_name("F10"));
+        assert_eq!(None, key_from_name("F13"));
+    }
+}
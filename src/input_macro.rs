@@ -0,0 +1,174 @@
+use std::sync::{Arc, RwLock};
+
+use crate::joypad::JoypadInputRequest;
+
+/**
+ * Named sequence of button states, each held for a number of frames.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct InputMacro {
+    pub name: String,
+    pub steps: Vec<(u32, JoypadInputRequest)>,
+}
+
+/**
+ * Hotkey state written by the UI and consumed by the VM at frame boundaries.
+ */
+#[derive(Default)]
+pub struct InputHotkeys {
+    // Turbo buttons currently held (only A and B are used).
+    pub turbo: RwLock<JoypadInputRequest>,
+    // Index of the macro requested to start.
+    pub requested_macro: RwLock<Option<usize>>,
+}
+
+/**
+ * Applies turbo buttons and macro playback on top of the live input. Advanced once per emulated frame, so it stays in
+ * sync with the VM regardless of the emulation speed.
+ */
+pub struct InputSequencer {
+    hotkeys: Arc<InputHotkeys>,
+    // Frames a turbo button stays pressed, then the same number of frames released.
+    turbo_rate: u32,
+    // Frames since turbo buttons started being held.
+    turbo_frame: u32,
+    macros: Vec<InputMacro>,
+    // Macro index + step index + frames left in the step.
+    playing: Option<(usize, usize, u32)>,
+}
+
+impl InputSequencer {
+    pub fn new(
+        hotkeys: Arc<InputHotkeys>,
+        turbo_rate: u32,
+        macros: Vec<InputMacro>,
+    ) -> InputSequencer {
+        InputSequencer {
+            hotkeys,
+            turbo_rate: turbo_rate.max(1),
+            turbo_frame: 0,
+            macros,
+            playing: None,
+        }
+    }
+
+    /**
+     * Returns the input for the next frame. `extra_turbo` are turbo buttons held outside of the UI (gamepad).
+     */
+    pub fn next_input(
+        &mut self,
+        live: JoypadInputRequest,
+        extra_turbo: JoypadInputRequest,
+    ) -> JoypadInputRequest {
+        let mut input = live;
+
+        let turbo = self
+            .hotkeys
+            .turbo
+            .read()
+            .expect("Cannot lock turbo buttons")
+            .merge(extra_turbo);
+        if turbo == JoypadInputRequest::new() {
+            self.turbo_frame = 0;
+        } else {
+            if (self.turbo_frame / self.turbo_rate) & 1 == 0 {
+                input = input.merge(turbo);
+            }
+            self.turbo_frame = self.turbo_frame.wrapping_add(1);
+        }
+
+        if let Some(macro_i) = self
+            .hotkeys
+            .requested_macro
+            .write()
+            .expect("Cannot lock macro request")
+            .take()
+        {
+            if let Some(input_macro) = self.macros.get(macro_i) {
+                log::info!("Macro started: {}", input_macro.name);
+                self.playing = Some((macro_i, 0, 0));
+            }
+        }
+
+        input.merge(self.next_macro_input())
+    }
+
+    fn next_macro_input(&mut self) -> JoypadInputRequest {
+        let Some((macro_i, mut step_i, mut frames_left)) = self.playing else {
+            return JoypadInputRequest::new();
+        };
+        let steps = &self.macros[macro_i].steps;
+
+        // Skip to the next step with frames left (new macro or finished step).
+        while frames_left == 0 {
+            if let Some((frames, _)) = steps.get(step_i) {
+                frames_left = *frames;
+                step_i += 1;
+            } else {
+                self.playing = None;
+                return JoypadInputRequest::new();
+            }
+        }
+
+        self.playing = Some((macro_i, step_i, frames_left - 1));
+        steps[step_i - 1].1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input_macro::*;
+
+    fn press_a() -> JoypadInputRequest {
+        JoypadInputRequest {
+            a: true,
+            ..JoypadInputRequest::new()
+        }
+    }
+
+    #[test]
+    fn test_turbo_alternates_every_rate_frames() {
+        let hotkeys = Arc::new(InputHotkeys::default());
+        let mut sequencer = InputSequencer::new(hotkeys.clone(), 2, vec![]);
+        *hotkeys.turbo.write().unwrap() = press_a();
+
+        let pattern = (0..6)
+            .map(|_| {
+                sequencer
+                    .next_input(JoypadInputRequest::new(), JoypadInputRequest::new())
+                    .a
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(vec![true, true, false, false, true, true], pattern);
+    }
+
+    #[test]
+    fn test_macro_plays_steps_frame_by_frame() {
+        let hotkeys = Arc::new(InputHotkeys::default());
+        let start = JoypadInputRequest {
+            start: true,
+            ..JoypadInputRequest::new()
+        };
+        let input_macro = InputMacro {
+            name: "test".to_string(),
+            steps: vec![(2, press_a()), (1, JoypadInputRequest::new()), (1, start)],
+        };
+        let mut sequencer = InputSequencer::new(hotkeys.clone(), 2, vec![input_macro]);
+        *hotkeys.requested_macro.write().unwrap() = Some(0);
+
+        let frames = (0..6)
+            .map(|_| sequencer.next_input(JoypadInputRequest::new(), JoypadInputRequest::new()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                press_a(),
+                press_a(),
+                JoypadInputRequest::new(),
+                start,
+                JoypadInputRequest::new(),
+                JoypadInputRequest::new()
+            ],
+            frames
+        );
+    }
+}
@@ -1,7 +1,21 @@
 use crate::conf::Error;
+use crate::error::EmulatorError;
+use crate::state::*;
 use std::sync::{Arc, RwLock};
 
-#[derive(Default)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Button {
+    Start,
+    Select,
+    B,
+    A,
+    Down,
+    Up,
+    Left,
+    Right,
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
 pub struct JoypadInputRequest {
     pub start: bool,
     pub select: bool,
@@ -17,6 +31,53 @@ impl JoypadInputRequest {
     pub fn new() -> JoypadInputRequest {
         JoypadInputRequest::default()
     }
+
+    pub fn set(&mut self, button: Button, is_pressed: bool) {
+        match button {
+            Button::Start => self.start = is_pressed,
+            Button::Select => self.select = is_pressed,
+            Button::B => self.b = is_pressed,
+            Button::A => self.a = is_pressed,
+            Button::Down => self.down = is_pressed,
+            Button::Up => self.up = is_pressed,
+            Button::Left => self.left = is_pressed,
+            Button::Right => self.right = is_pressed,
+        }
+    }
+
+    /**
+     * Pressed on either side.
+     */
+    pub fn merge(self, other: JoypadInputRequest) -> JoypadInputRequest {
+        JoypadInputRequest::from_byte(self.to_byte() | other.to_byte())
+    }
+
+    /**
+     * Packs the buttons in P1 order: bit 0-3 Right/Left/Up/Down, bit 4-7 A/B/Select/Start.
+     */
+    pub fn to_byte(self) -> u8 {
+        (self.right as u8)
+            | (self.left as u8) << 1
+            | (self.up as u8) << 2
+            | (self.down as u8) << 3
+            | (self.a as u8) << 4
+            | (self.b as u8) << 5
+            | (self.select as u8) << 6
+            | (self.start as u8) << 7
+    }
+
+    pub fn from_byte(byte: u8) -> JoypadInputRequest {
+        JoypadInputRequest {
+            right: byte & 0b0000_0001 > 0,
+            left: byte & 0b0000_0010 > 0,
+            up: byte & 0b0000_0100 > 0,
+            down: byte & 0b0000_1000 > 0,
+            a: byte & 0b0001_0000 > 0,
+            b: byte & 0b0010_0000 > 0,
+            select: byte & 0b0100_0000 > 0,
+            start: byte & 0b1000_0000 > 0,
+        }
+    }
 }
 
 enum ButtonSelector {
@@ -27,7 +88,10 @@ enum ButtonSelector {
 
 pub struct Joypad {
     need_interrupt: bool,
+    // Live state, written by the UI thread at any time.
     buttons: Arc<RwLock<JoypadInputRequest>>,
+    // State seen by the CPU. Only changes at frame boundaries, so emulation stays deterministic.
+    latched: JoypadInputRequest,
     button_selector: ButtonSelector,
 }
 
@@ -36,11 +100,23 @@ impl Joypad {
         Joypad {
             need_interrupt: false,
             buttons,
+            latched: JoypadInputRequest::new(),
             button_selector: ButtonSelector::None,
         }
     }
 
+    pub fn live_input(&self) -> JoypadInputRequest {
+        *self.buttons.read().expect("Failed read lock of buttons")
+    }
+
+    pub fn set_input(&mut self, input: JoypadInputRequest) {
+        let prev_lines = self.p1_lines();
+        self.latched = input;
+        self.check_interrupt(prev_lines);
+    }
+
     pub fn set_p1_button_selector(&mut self, value: u8) -> Result<(), Error> {
+        let prev_lines = self.p1_lines();
         let button_selector = (value >> 4) & 0b11;
         match button_selector {
             0b11 | 0b00 => self.button_selector = ButtonSelector::None,
@@ -55,15 +131,33 @@ impl Joypad {
             }
         };
 
+        self.check_interrupt(prev_lines);
+
         Ok(())
     }
 
+    /**
+     * P10-P13 input lines (low = pressed on a selected row).
+     */
+    fn p1_lines(&self) -> u8 {
+        self.get_p1() & 0b1111
+    }
+
+    /**
+     * The joypad interrupt is requested when any of P10-P13 goes from high to low.
+     */
+    fn check_interrupt(&mut self, prev_lines: u8) {
+        if prev_lines & !self.p1_lines() != 0 {
+            self.need_interrupt = true;
+        }
+    }
+
     pub fn get_p1(&self) -> u8 {
         match self.button_selector {
             ButtonSelector::None => 0xFF,
             ButtonSelector::DownUpLeftRight => {
                 let mut out = !0b0001_0000;
-                let buttons = self.buttons.read().expect("Failed read lock of buttons");
+                let buttons = &self.latched;
                 if buttons.down {
                     out &= !0b1000;
                 }
@@ -80,7 +174,7 @@ impl Joypad {
             }
             ButtonSelector::StartSelectBA => {
                 let mut out = !0b0010_0000;
-                let buttons = self.buttons.read().expect("Failed read lock of buttons");
+                let buttons = &self.latched;
                 if buttons.start {
                     out &= !0b1000;
                 }
@@ -98,9 +192,79 @@ impl Joypad {
         }
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(match self.button_selector {
+            ButtonSelector::None => 0,
+            ButtonSelector::StartSelectBA => 1,
+            ButtonSelector::DownUpLeftRight => 2,
+        });
+        w.write_bool(self.need_interrupt);
+        w.write_u8(self.latched.to_byte());
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.button_selector = match r.read_u8()? {
+            0 => ButtonSelector::None,
+            1 => ButtonSelector::StartSelectBA,
+            2 => ButtonSelector::DownUpLeftRight,
+            code => {
+                return Err(EmulatorError::BadState(format!(
+                    "Invalid joypad selector in state: {}",
+                    code
+                )))
+            }
+        };
+        self.need_interrupt = r.read_bool()?;
+        self.latched = JoypadInputRequest::from_byte(r.read_u8()?);
+        Ok(())
+    }
+
     pub fn consume_interrupt(&mut self) -> bool {
         let need_interrupt = self.need_interrupt;
         self.need_interrupt = false;
         need_interrupt
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::joypad::*;
+
+    fn make_joypad() -> Joypad {
+        Joypad::new(Arc::new(RwLock::new(JoypadInputRequest::new())))
+    }
+
+    #[test]
+    fn test_interrupt_on_press_of_selected_row() {
+        let mut joypad = make_joypad();
+        joypad.set_p1_button_selector(0b0001_0000).unwrap();
+        assert!(!joypad.consume_interrupt());
+
+        joypad.set_input(JoypadInputRequest {
+            a: true,
+            ..JoypadInputRequest::new()
+        });
+        assert!(joypad.consume_interrupt());
+        assert!(!joypad.consume_interrupt());
+
+        // Release is low-to-high, no interrupt.
+        joypad.set_input(JoypadInputRequest::new());
+        assert!(!joypad.consume_interrupt());
+    }
+
+    #[test]
+    fn test_no_interrupt_on_unselected_row() {
+        let mut joypad = make_joypad();
+        joypad.set_p1_button_selector(0b0010_0000).unwrap();
+
+        joypad.set_input(JoypadInputRequest {
+            start: true,
+            ..JoypadInputRequest::new()
+        });
+        assert!(!joypad.consume_interrupt());
+
+        // Selecting the row with the held button pulls a line low.
+        joypad.set_p1_button_selector(0b0001_0000).unwrap();
+        assert!(joypad.consume_interrupt());
+    }
+}
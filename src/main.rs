@@ -1,14 +1,34 @@
 mod apu;
+mod call_stack;
 mod cartridge;
+mod cdl;
 mod conf;
 mod cpu;
+mod dap;
 mod debugger;
+mod disasm;
+mod dma;
+mod error;
+mod expr;
+mod gamepad;
+mod gdb;
 mod gfx;
+mod input_config;
+mod input_macro;
 mod joypad;
 mod mmu;
+mod movie;
 mod ppu;
+mod profiler;
+mod rewind;
 mod serial;
+mod source_map;
+mod speed;
+mod state;
+mod symbols;
+mod timeline;
 mod timer;
+mod trace;
 mod util;
 mod vm;
 
@@ -17,9 +37,23 @@ use std::sync::Arc;
 use std::sync::RwLock;
 
 use crate::cartridge::*;
+use crate::cdl::CodeDataLog;
 use crate::conf::*;
+use crate::dap::DapServer;
 use crate::debugger::*;
+
+use crate::gamepad::Gamepad;
+use crate::gdb::GdbStub;
+use crate::gfx::{GfxOptions, GfxShared};
+use crate::input_config::InputConfig;
+use crate::input_macro::{InputHotkeys, InputSequencer};
+use crate::movie::Movie;
 use crate::ppu::PPU;
+use crate::profiler::Profiler;
+use crate::rewind::Rewind;
+use crate::speed::SpeedControl;
+use crate::symbols::Symbols;
+use crate::trace::{TraceFilter, TraceFormat, TraceLogger};
 use crate::vm::*;
 
 use std::thread::spawn;
@@ -30,9 +64,10 @@ use clap::Parser;
 #[command(author, version, about, long_about = None)]
 struct Args {
     /// Cartridge.
-    cartridge: String,
+    #[arg(required_unless_present = "trace_diff")]
+    cartridge: Option<String>,
 
-    /// Breakpoints.
+    /// Breakpoint (hex address or label).
     #[arg(short = 'b', long)]
     breakpoint: Option<String>,
 
@@ -44,9 +79,41 @@ struct Args {
     #[arg(short, long)]
     no_fps: bool,
 
-    /// Dump opcode list to file.
+    /// Log every executed instruction to file.
+    #[arg(long)]
+    trace: Option<String>,
+
+    /// Trace line format: doctor (Gameboy Doctor) or lameboy.
+    #[arg(long, default_value = "doctor", value_parser = TraceFormat::parse)]
+    trace_format: TraceFormat,
+
+    /// CPU reads of LY return 0x90 while tracing, to match Gameboy Doctor logs.
+    #[arg(long, requires = "trace")]
+    trace_ly_stub: bool,
+
+    /// Only trace PCs in a hex range, eg. 0150-3FFF.
+    #[arg(long, value_parser = TraceFilter::parse_pc_range)]
+    trace_pc: Option<(u16, u16)>,
+
+    /// Only trace code of a ROM bank (0 for 0000-3FFF).
+    #[arg(long)]
+    trace_bank: Option<u8>,
+
+    /// Only trace a frame window, eg. 60-120.
+    #[arg(long, value_parser = TraceFilter::parse_frame_window)]
+    trace_frames: Option<(u64, u64)>,
+
+    /// Code/Data Logger file, loaded if present and saved on exit.
+    #[arg(long)]
+    cdl: Option<String>,
+
+    /// Profile CPU cycles, print the report on exit and save folded stacks (flamegraph input) to file.
     #[arg(long)]
-    opcode_dump: bool,
+    profile: Option<String>,
+
+    /// Compare a trace to a reference trace, report the first divergence and exit.
+    #[arg(long, num_args = 2, value_names = ["TRACE", "REFERENCE"])]
+    trace_diff: Option<Vec<String>>,
 
     /// Tile map debug window.
     #[arg(long)]
@@ -67,13 +134,61 @@ struct Args {
     /// Turn all sounds off.
     #[arg(long)]
     disable_sound: bool,
+
+    /// Frames between rewind snapshots.
+    #[arg(long, default_value_t = 4)]
+    rewind_interval: u32,
+
+    /// Memory budget of rewind snapshots in MiB.
+    #[arg(long, default_value_t = 32)]
+    rewind_budget: usize,
+
+    /// Speed multiplier of fast-forward (0 = unlimited).
+    #[arg(long, default_value_t = 4.0)]
+    fast_forward_speed: f64,
+
+    /// Record input movie from power-on to file.
+    #[arg(long, conflicts_with = "play_movie")]
+    record_movie: Option<String>,
+
+    /// Play input movie from file.
+    #[arg(long)]
+    play_movie: Option<String>,
+
+    /// Movie playback hands over to recording on input or at the end.
+    #[arg(long, requires = "play_movie")]
+    movie_read_write: bool,
+
+    /// Key and gamepad bindings file.
+    #[arg(long)]
+    input_config: Option<String>,
+
+    /// Ignore gamepads.
+    #[arg(long)]
+    disable_gamepad: bool,
+
+    /// Symbol file (RGBDS / no$gmb .sym, RGBDS .map), defaults to the .sym or .map next to the cartridge.
+    #[arg(long)]
+    symbols: Option<String>,
+
+    /// Serve GDB remote protocol on a local port instead of the terminal debugger, stopped until connected.
+    #[arg(long)]
+    gdb: Option<u16>,
+
+    /// Serve the debug adapter protocol on a local port instead of the terminal debugger, stopped until connected.
+    #[arg(long, conflicts_with = "gdb")]
+    dap: Option<u16>,
 }
 
 impl Args {
-    fn breakpoint_parsed(&self) -> Option<u16> {
-        self.breakpoint.as_ref().map(|breakpoint| {
-            u16::from_str_radix(breakpoint.as_str(), 16)
-                .expect("Failed converting base-16 breakpoint address")
+    fn symbols_path(&self) -> Option<String> {
+        if self.symbols.is_some() {
+            return self.symbols.clone();
+        }
+
+        ["sym", "map"].iter().find_map(|extension| {
+            let path = std::path::Path::new(self.cartridge.as_ref()?).with_extension(extension);
+            path.exists().then(|| path.to_string_lossy().into_owned())
         })
     }
 }
@@ -88,43 +203,160 @@ fn main() -> Result<(), Error> {
 
     let args = Args::parse();
 
+    if let Some(paths) = args.trace_diff.as_ref() {
+        let is_match = trace::diff_files(&paths[0], &paths[1])?;
+        std::process::exit(if is_match { 0 } else { 1 });
+    }
+    let cartridge_path = args.cartridge.clone().unwrap_or_default();
+
     let breakpoint_flag = Arc::new(AtomicBool::new(false));
     let mut debugger = Debugger::new(breakpoint_flag.clone());
 
-    args.breakpoint_parsed()
-        .map(|breakpoint| debugger.add_breakpoint(breakpoint));
+    if let Some(path) = args.symbols_path() {
+        debugger.set_symbols(Symbols::load(&path).expect("Cannot load symbols"));
+    }
+
+    if let Some(breakpoint) = args.breakpoint.as_ref() {
+        debugger
+            .add_breakpoint_by_name(breakpoint)
+            .expect("Failed parsing breakpoint");
+    }
     if args.step_by_step {
         debugger.set_break_on_start();
         debugger.set_step_by_step();
     }
 
-    let vm_debug_log: Arc<RwLock<Vec<String>>> = Arc::new(RwLock::new(vec![]));
+    let gdb = args.gdb.map(|port| {
+        debugger.request_one_time_break();
+        GdbStub::new(port, breakpoint_flag.clone()).expect("Cannot listen for GDB")
+    });
+    let dap = args.dap.map(|port| {
+        debugger.request_one_time_break();
+        let source_root = std::path::Path::new(&cartridge_path)
+            .parent()
+            .map(|dir| dir.to_path_buf())
+            .unwrap_or_default();
+        DapServer::new(port, breakpoint_flag.clone(), source_root)
+            .expect("Cannot listen for DAP client")
+    });
+
+    let views = DebugViews::default();
 
     let global_exit_flag = Arc::new(AtomicBool::new(false));
-    let should_generate_vm_debug_log = Arc::new(AtomicBool::new(false));
 
     let video = Arc::new(RwLock::new(PPU::new()));
     let joypad_button_input_requester = Arc::new(RwLock::new(joypad::JoypadInputRequest::new()));
     let joypad = joypad::Joypad::new(joypad_button_input_requester.clone());
-    let cartridge = Cartridge::new(args.cartridge).expect("Cannot open cartridge");
+    let cartridge = match Cartridge::new(cartridge_path) {
+        Ok(cartridge) => cartridge,
+        Err(err) => {
+            log::error!("Cannot open cartridge: {}", err);
+            std::process::exit(1);
+        }
+    };
     let cartridge_title = cartridge.get_title();
+    let cdl = args.cdl.clone().map(|path| {
+        CodeDataLog::new(path, cartridge.rom_size(), cartridge.ram_size()).expect("Cannot open CDL")
+    });
+
+    let rewind_flag = Arc::new(AtomicBool::new(false));
+    let rewind = Rewind::new(
+        args.rewind_interval,
+        args.rewind_budget * 1024 * 1024,
+        rewind_flag.clone(),
+    );
+
+    let speed_control = Arc::new(SpeedControl::new(args.fast_forward_speed));
+
+    let movie_anchor_flag = Arc::new(AtomicBool::new(false));
+    let trace = args.trace.as_ref().map(|path| {
+        let filter = TraceFilter {
+            pc: args.trace_pc,
+            rom_bank: args.trace_bank,
+            frames: args.trace_frames,
+        };
+        TraceLogger::new(path, args.trace_format, filter, args.trace_ly_stub)
+            .expect("Cannot create trace file")
+    });
+
+    let movie = if let Some(path) = args.record_movie.clone() {
+        Some(Movie::new_recording(
+            path,
+            cartridge.rom_hash(),
+            movie_anchor_flag.clone(),
+        ))
+    } else if let Some(path) = args.play_movie.clone() {
+        Some(
+            Movie::open(
+                path,
+                cartridge.rom_hash(),
+                args.movie_read_write,
+                movie_anchor_flag.clone(),
+            )
+            .expect("Cannot open movie"),
+        )
+    } else {
+        None
+    };
+
+    let input_config = match args.input_config.as_ref() {
+        Some(path) => InputConfig::load(path).expect("Cannot load input config"),
+        None => InputConfig::new(),
+    };
+    let key_bindings = input_config.keys.clone();
+    let turbo_key_bindings = input_config.turbo_keys.clone();
+    let macro_key_bindings = input_config.macro_keys.clone();
+    let input_hotkeys = Arc::new(InputHotkeys::default());
+    let input_sequencer = InputSequencer::new(
+        input_hotkeys.clone(),
+        input_config.turbo_rate,
+        input_config.macros.clone(),
+    );
 
     let vm_thread = spawn({
         let global_exit_flag = global_exit_flag.clone();
         let video = video.clone();
-        let vm_debug_log = vm_debug_log.clone();
-        let should_generate_vm_debug_log = should_generate_vm_debug_log.clone();
+        let views = views.clone();
+        let speed_control = speed_control.clone();
 
         move || {
+            // SDL objects must stay on the thread that initialized SDL (the VM thread, for audio).
+            let gamepad = if args.disable_gamepad {
+                None
+            } else {
+                match Gamepad::new(&input_config) {
+                    Ok(gamepad) => Some(gamepad),
+                    Err(err) => {
+                        log::error!("Gamepad support unavailable: {}", err);
+                        None
+                    }
+                }
+            };
+
+            let options = VmOptions {
+                disable_sound: args.disable_sound,
+                rewind,
+                movie,
+                gamepad,
+                input_sequencer,
+            };
+            let tools = DebugTools {
+                debugger,
+                trace,
+                cdl,
+                profiler: args.profile.clone().map(Profiler::new),
+                gdb,
+                dap,
+            };
+
             if let Ok(mut vm) = VM::new(
                 global_exit_flag.clone(),
                 cartridge,
-                debugger,
                 video,
-                args.opcode_dump,
                 joypad,
-                args.disable_sound,
-                vm_debug_log,
+                options,
+                tools,
+                views,
             ) {
                 if let Err(err) = vm.setup(args.skip_intro) {
                     log::error!("Failed VM setup: {}", err);
@@ -132,7 +364,7 @@ fn main() -> Result<(), Error> {
                     return;
                 }
 
-                if let Err(err) = vm.run(should_generate_vm_debug_log, args.no_fps) {
+                if let Err(err) = vm.run(args.no_fps, speed_control) {
                     log::error!("Failed VM run: {}", err);
                     vm.dump_op_history();
                     global_exit_flag.store(true, std::sync::atomic::Ordering::Release);
@@ -144,18 +376,25 @@ fn main() -> Result<(), Error> {
         }
     });
 
-    gfx::run(
-        global_exit_flag.clone(),
-        video.clone(),
+    let shared = GfxShared {
         breakpoint_flag,
-        joypad_button_input_requester,
-        args.tiles,
-        args.background,
-        args.window,
-        vm_debug_log,
-        should_generate_vm_debug_log,
+        rewind_flag,
+        speed_control,
+        movie_anchor_flag,
+        buttons: joypad_button_input_requester,
+        input_hotkeys,
+        views,
+    };
+    let options = GfxOptions {
+        key_bindings,
+        turbo_key_bindings,
+        macro_key_bindings,
+        with_tile_debug_window: args.tiles,
+        with_background_debug_window: args.background,
+        with_window_debug_window: args.window,
         cartridge_title,
-    );
+    };
+    gfx::run(global_exit_flag.clone(), video.clone(), shared, options);
 
     global_exit_flag.store(true, std::sync::atomic::Ordering::Release);
 
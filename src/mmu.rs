@@ -1,5 +1,7 @@
 use crate::cartridge::*;
 use crate::conf::*;
+use crate::error::EmulatorError;
+use crate::state::*;
 
 pub struct Mmu {
     pub boot_lock_reg: u8,
@@ -41,7 +43,10 @@ impl Mmu {
         } else if (MEM_AREA_HRAM_START..=MEM_AREA_HRAM_END).contains(&loc) {
             self.hram[(loc - MEM_AREA_HRAM_START) as usize]
         } else {
-            return Err(format!("Unimplemented mem read: {:#06X}", loc).into());
+            return Err(EmulatorError::BusFault(
+                loc,
+                "not mapped by the MMU".to_string(),
+            ));
         };
 
         log::debug!("Read: {:#06X} = #{:#04X}", loc, byte);
@@ -51,25 +56,65 @@ impl Mmu {
 
     pub fn write(&mut self, loc: u16, byte: u8) -> Result<(), Error> {
         if (0x0000..=0x7FFF).contains(&loc) {
-            self.cartridge.write(loc, byte);
+            self.cartridge.write(loc, byte)?;
         } else if (MEM_AREA_EXTERNAL_START..=MEM_AREA_EXTERNAL_END).contains(&loc) {
-            self.cartridge.write(loc, byte);
+            self.cartridge.write(loc, byte)?;
         } else if (MEM_AREA_WRAM_START..=MEM_AREA_WRAM_END).contains(&loc) {
             self.wram[(loc - MEM_AREA_WRAM_START) as usize] = byte;
         } else if (MEM_AREA_HRAM_START..=MEM_AREA_HRAM_END).contains(&loc) {
             self.hram[(loc - MEM_AREA_HRAM_START) as usize] = byte;
         } else {
-            return Err(format!("Unimplemented mem write: {:#06X}", loc).into());
+            return Err(EmulatorError::BusFault(
+                loc,
+                "not mapped by the MMU".to_string(),
+            ));
         }
 
         Ok(())
     }
 
+    /**
+     * Write patching the cartridge ROM / RAM in place instead of setting the MBC registers.
+     */
+    pub fn write_raw(&mut self, loc: u16, byte: u8) -> Result<(), Error> {
+        if (0x0000..=0x7FFF).contains(&loc)
+            || (MEM_AREA_EXTERNAL_START..=MEM_AREA_EXTERNAL_END).contains(&loc)
+        {
+            self.cartridge.write_raw(loc, byte)
+        } else {
+            self.write(loc, byte)
+        }
+    }
+
     fn is_bios_mounted(&self) -> bool {
         self.boot_lock_reg == 0b0
     }
 
+    /**
+     * Cartridge byte behind `loc`, none while the boot ROM is mapped over it.
+     */
+    pub fn physical_loc(&self, loc: u16) -> Option<PhysicalLoc> {
+        if loc < BIOS_SIZE as u16 && self.is_bios_mounted() {
+            return None;
+        }
+        self.cartridge.physical_loc(loc)
+    }
+
     pub fn rom_bank_selector(&self) -> u8 {
         self.cartridge.rom_bank_selector()
     }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.boot_lock_reg);
+        w.write_bytes(&self.hram);
+        w.write_bytes(&self.wram);
+        self.cartridge.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.boot_lock_reg = r.read_u8()?;
+        r.read_bytes_into(&mut self.hram)?;
+        r.read_bytes_into(&mut self.wram)?;
+        self.cartridge.load_state(r)
+    }
 }
@@ -0,0 +1,282 @@
+/**
+ * Input movies: per-frame joypad state anchored to power-on or to a machine state.
+ *
+ * File layout (little endian, via the machine state writer):
+ * - magic "LBMV", format version
+ * - CRC-32 of the ROM
+ * - start state flag + length prefixed machine state (when anchored to a state)
+ * - length prefixed frames, one packed `JoypadInputRequest` byte per frame
+ */
+use std::fs::File;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::conf::Error;
+use crate::error::EmulatorError;
+use crate::joypad::JoypadInputRequest;
+use crate::state::*;
+
+const MOVIE_MAGIC: &[u8; 4] = b"LBMV";
+const MOVIE_VERSION: u8 = 1;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum MovieMode {
+    Recording,
+    // Plays the inputs, live input is ignored. Once finished, live input takes over.
+    ReadOnly,
+    // Plays the inputs until the end or until a button is pressed, then keeps recording from there.
+    ReadWrite,
+}
+
+pub struct Movie {
+    path: String,
+    mode: MovieMode,
+    rom_hash: u32,
+    start_state: Option<Vec<u8>>,
+    frames: Vec<u8>,
+    cursor: usize,
+    is_finished: bool,
+    // Request (hotkey) to restart recording anchored to the current machine state.
+    anchor_flag: Arc<AtomicBool>,
+}
+
+impl Movie {
+    pub fn new_recording(path: String, rom_hash: u32, anchor_flag: Arc<AtomicBool>) -> Movie {
+        Movie {
+            path,
+            mode: MovieMode::Recording,
+            rom_hash,
+            start_state: None,
+            frames: vec![],
+            cursor: 0,
+            is_finished: false,
+            anchor_flag,
+        }
+    }
+
+    pub fn open(
+        path: String,
+        rom_hash: u32,
+        is_read_write: bool,
+        anchor_flag: Arc<AtomicBool>,
+    ) -> Result<Movie, Error> {
+        let mut buf = vec![];
+        File::open(&path)?.read_to_end(&mut buf)?;
+
+        if buf.len() < MOVIE_MAGIC.len() || &buf[0..MOVIE_MAGIC.len()] != MOVIE_MAGIC {
+            return Err(EmulatorError::BadState(format!(
+                "Not a movie file: {}",
+                path
+            )));
+        }
+
+        let mut r = StateReader::new(&buf[MOVIE_MAGIC.len()..]);
+        let version = r.read_u8()?;
+        if version != MOVIE_VERSION {
+            return Err(EmulatorError::BadState(format!(
+                "Unsupported movie version: {}",
+                version
+            )));
+        }
+
+        let movie_rom_hash = r.read_u32()?;
+        if movie_rom_hash != rom_hash {
+            return Err(EmulatorError::BadState(format!(
+                "Movie was recorded with a different ROM: {:08X} (cartridge: {:08X})",
+                movie_rom_hash, rom_hash
+            )));
+        }
+
+        let start_state = if r.read_bool()? {
+            Some(r.read_bytes()?)
+        } else {
+            None
+        };
+        let frames = r.read_bytes()?;
+
+        log::info!("Movie loaded: {} frames", frames.len());
+
+        Ok(Movie {
+            path,
+            mode: if is_read_write {
+                MovieMode::ReadWrite
+            } else {
+                MovieMode::ReadOnly
+            },
+            rom_hash,
+            start_state,
+            frames,
+            cursor: 0,
+            is_finished: false,
+            anchor_flag,
+        })
+    }
+
+    pub fn start_state(&self) -> Option<&Vec<u8>> {
+        self.start_state.as_ref()
+    }
+
+    pub fn is_anchor_requested(&self) -> bool {
+        self.anchor_flag.swap(false, Ordering::Relaxed)
+    }
+
+    /**
+     * Drops all recorded frames and starts recording from the given machine state.
+     */
+    pub fn restart_recording(&mut self, start_state: Vec<u8>) {
+        log::info!("Movie recording restarted from the current state");
+
+        self.mode = MovieMode::Recording;
+        self.start_state = Some(start_state);
+        self.frames.clear();
+        self.cursor = 0;
+        self.is_finished = false;
+    }
+
+    /**
+     * Called once per frame boundary with the live input. Returns the input the CPU should see for the next frame.
+     */
+    pub fn next_input(&mut self, live: JoypadInputRequest) -> JoypadInputRequest {
+        if self.mode == MovieMode::ReadWrite
+            && (self.cursor >= self.frames.len() || live != JoypadInputRequest::new())
+        {
+            log::info!("Movie switched to recording at frame {}", self.cursor);
+            self.frames.truncate(self.cursor);
+            self.mode = MovieMode::Recording;
+        }
+
+        match self.mode {
+            MovieMode::Recording => {
+                self.frames.push(live.to_byte());
+                self.cursor += 1;
+                live
+            }
+            MovieMode::ReadOnly | MovieMode::ReadWrite => {
+                if let Some(byte) = self.frames.get(self.cursor) {
+                    self.cursor += 1;
+                    JoypadInputRequest::from_byte(*byte)
+                } else {
+                    if !self.is_finished {
+                        self.is_finished = true;
+                        log::info!("Movie playback finished at frame {}", self.cursor);
+                    }
+                    live
+                }
+            }
+        }
+    }
+
+    /**
+     * Writes the movie file if anything was recorded.
+     */
+    pub fn save(&self) -> Result<(), Error> {
+        if self.mode != MovieMode::Recording {
+            return Ok(());
+        }
+
+        let mut w = StateWriter::new();
+        w.write_u8(MOVIE_VERSION);
+        w.write_u32(self.rom_hash);
+        w.write_bool(self.start_state.is_some());
+        if let Some(start_state) = self.start_state.as_ref() {
+            w.write_bytes(start_state);
+        }
+        w.write_bytes(&self.frames);
+
+        let mut file = File::create(&self.path)?;
+        file.write_all(MOVIE_MAGIC)?;
+        file.write_all(&w.into_inner())?;
+
+        log::info!("Movie saved: {} ({} frames)", self.path, self.frames.len());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::joypad::Button;
+    use crate::movie::*;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("lameboy_{}_{}.lbmv", name, std::process::id()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn pressed(button: Button) -> JoypadInputRequest {
+        let mut input = JoypadInputRequest::new();
+        input.set(button, true);
+        input
+    }
+
+    fn record(path: &str, inputs: &[JoypadInputRequest]) {
+        let mut movie = Movie::new_recording(path.to_string(), 0x1234_5678, Default::default());
+        movie.restart_recording(vec![1, 2, 3]);
+        for input in inputs {
+            assert_eq!(*input, movie.next_input(*input));
+        }
+        movie.save().unwrap();
+    }
+
+    #[test]
+    fn test_save_and_open() {
+        let path = temp_path("movie_round_trip");
+        let inputs = [
+            pressed(Button::A),
+            JoypadInputRequest::new(),
+            pressed(Button::Up),
+        ];
+        record(&path, &inputs);
+
+        let mut movie = Movie::open(path.clone(), 0x1234_5678, false, Default::default()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(Some(&vec![1, 2, 3]), movie.start_state());
+        // Live input is ignored while playing, then takes over.
+        for input in inputs {
+            assert_eq!(input, movie.next_input(pressed(Button::B)));
+        }
+        assert_eq!(pressed(Button::B), movie.next_input(pressed(Button::B)));
+        assert!(movie.is_finished);
+    }
+
+    #[test]
+    fn test_open_with_other_rom() {
+        let path = temp_path("movie_other_rom");
+        record(&path, &[JoypadInputRequest::new()]);
+
+        let result = Movie::open(path.clone(), 0x8765_4321, false, Default::default());
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(EmulatorError::BadState(_))));
+    }
+
+    #[test]
+    fn test_read_write_takes_over_on_input() {
+        let path = temp_path("movie_read_write");
+        record(&path, &[pressed(Button::A); 4]);
+
+        let mut movie = Movie::open(path.clone(), 0x1234_5678, true, Default::default()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            pressed(Button::A),
+            movie.next_input(JoypadInputRequest::new())
+        );
+        assert_eq!(MovieMode::ReadWrite, movie.mode);
+
+        // A press truncates the rest of the movie and records from there.
+        assert_eq!(
+            pressed(Button::Start),
+            movie.next_input(pressed(Button::Start))
+        );
+        assert_eq!(MovieMode::Recording, movie.mode);
+        assert_eq!(
+            vec![
+                pressed(Button::A).to_byte(),
+                pressed(Button::Start).to_byte()
+            ],
+            movie.frames
+        );
+    }
+}
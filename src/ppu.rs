@@ -1,8 +1,11 @@
+use std::collections::VecDeque;
 use std::sync::atomic::AtomicBool;
 
 use winit::window::WindowId;
 
 use crate::conf::*;
+use crate::error::EmulatorError;
+use crate::state::*;
 use crate::util::*;
 
 #[derive(PartialEq)]
@@ -28,6 +31,83 @@ enum ObjSpriteSize {
     Size8x16,
 }
 
+/**
+ * Mode 3 of the current line: the background/window fetcher and the pixel FIFOs shifting out to the LCD.
+ */
+struct LineFifo {
+    // Raw background/window colors.
+    bg_fifo: VecDeque<u8>,
+    // Object pixels from the next LCD pixel on: color | OBP1 << 2 | behind BG << 3.
+    obj_fifo: VecDeque<u8>,
+    // Pixels sent to the LCD.
+    lx: u8,
+    // Pixels to shift out unseen: SCX fine scroll, window left of the screen.
+    discard: u8,
+    // Tile column of the next fetch.
+    fetcher_x: u8,
+    // Dots into the current tile fetch.
+    fetcher_dots: u8,
+    // Dots the pipeline waits: first (dummy) tile fetch, object fetches.
+    stall: u8,
+    in_window: bool,
+    // OAM indexes of the (max 10) objects of the line by X, and the next one to fetch.
+    objs: Vec<u8>,
+    next_obj: usize,
+}
+
+impl LineFifo {
+    fn new(discard: u8, objs: Vec<u8>) -> LineFifo {
+        LineFifo {
+            bg_fifo: VecDeque::with_capacity(16),
+            obj_fifo: VecDeque::with_capacity(8),
+            lx: 0,
+            discard,
+            fetcher_x: 0,
+            fetcher_dots: 0,
+            stall: 6,
+            in_window: false,
+            objs,
+            next_obj: 0,
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.bg_fifo.iter().copied().collect::<Vec<_>>());
+        w.write_bytes(&self.obj_fifo.iter().copied().collect::<Vec<_>>());
+        w.write_u8(self.lx);
+        w.write_u8(self.discard);
+        w.write_u8(self.fetcher_x);
+        w.write_u8(self.fetcher_dots);
+        w.write_u8(self.stall);
+        w.write_bool(self.in_window);
+        w.write_bytes(&self.objs);
+        w.write_u8(self.next_obj as u8);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.bg_fifo = r.read_bytes()?.into();
+        self.obj_fifo = r.read_bytes()?.into();
+        self.lx = r.read_u8()?;
+        self.discard = r.read_u8()?;
+        self.fetcher_x = r.read_u8()?;
+        self.fetcher_dots = r.read_u8()?;
+        self.stall = r.read_u8()?;
+        self.in_window = r.read_bool()?;
+        self.objs = r.read_bytes()?;
+        self.next_obj = r.read_u8()? as usize;
+        if self.lx as u32 > DISPLAY_WIDTH
+            || self.objs.len() > 10
+            || self.objs.iter().any(|i| *i as usize >= OAM_RAM_SIZE / 4)
+            || self.next_obj > self.objs.len()
+        {
+            return Err(EmulatorError::BadState(
+                "Invalid pixel FIFO line in state".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
 pub const VIDEO_RESULT_MASK_STAT_INTERRUPT: u8 = 0b1;
 pub const VIDEO_RESULT_MASK_VBLANK_INTERRUPT: u8 = 0b10;
 
@@ -55,7 +135,11 @@ pub struct PPU {
     pub background_debug_window_id: Option<WindowId>,
     pub window_debug_window_id: Option<WindowId>,
     lyc_change_interrupt: bool,
-    wy_offset: u8,
+    // Set once LY matched WY in the frame, the window can start from then on.
+    wy_triggered: bool,
+    // Window line to draw next, it only advances on lines showing the window.
+    window_line: u8,
+    line: LineFifo,
 }
 
 impl PPU {
@@ -83,7 +167,9 @@ impl PPU {
             background_debug_window_id: None,
             window_debug_window_id: None,
             lyc_change_interrupt: false,
-            wy_offset: 0,
+            wy_triggered: false,
+            window_line: 0,
+            line: LineFifo::new(0, vec![]),
         }
     }
 
@@ -109,7 +195,9 @@ impl PPU {
         self.oam_ram.iter_mut().for_each(|b| *b = 0);
         self.display_buffer.iter_mut().for_each(|b| *b = 0);
         self.lyc_change_interrupt = false;
-        self.wy_offset = 0;
+        self.wy_triggered = false;
+        self.window_line = 0;
+        self.line = LineFifo::new(0, vec![]);
     }
 
     /**
@@ -128,7 +216,20 @@ impl PPU {
 
         // println!("Ticks: {}", cpu_cycles);
 
-        self.stat_counter += cpu_cycles as u64;
+        for _ in 0..cpu_cycles {
+            self.update_dot(&mut interrupt_mask);
+        }
+
+        if self.lyc_change_interrupt {
+            self.lyc_change_interrupt = false;
+            interrupt_mask = interrupt_mask | VIDEO_RESULT_MASK_STAT_INTERRUPT;
+        }
+
+        interrupt_mask
+    }
+
+    fn update_dot(&mut self, interrupt_mask: &mut u8) {
+        self.stat_counter += 1;
 
         // Mode 2  2_____2_____2_____2_____2_____2___________________2____
         // Mode 3  _33____33____33____33____33____33__________________3___
@@ -141,48 +242,48 @@ impl PPU {
                     self.stat_counter -= 80;
                     // Mode to 3.
                     if self.set_lcd_stat_ppu_mode(3) {
-                        interrupt_mask |= VIDEO_RESULT_MASK_STAT_INTERRUPT;
+                        *interrupt_mask |= VIDEO_RESULT_MASK_STAT_INTERRUPT;
                     }
-                    self.draw_line_to_screen(self.ly);
+                    self.start_line();
                 }
             }
-            // Sending pixels to the LCD.
+            // Sending pixels to the LCD: 172 to 289 dots, depending on SCX, the window and objects.
             LcdPpuMode::M3 => {
-                // Todo: 172 to 289 dots, depending on object count
-                let m3_len = 252;
-                if self.stat_counter >= m3_len {
-                    self.stat_counter -= m3_len;
+                if self.update_line_dot() {
+                    self.prev_m3_len = self.stat_counter;
+                    self.stat_counter = 0;
 
-                    self.prev_m3_len = m3_len;
+                    if self.line.in_window {
+                        self.window_line += 1;
+                    }
 
                     // Mode to 0.
                     if self.set_lcd_stat_ppu_mode(0) {
-                        interrupt_mask |= VIDEO_RESULT_MASK_STAT_INTERRUPT;
+                        *interrupt_mask |= VIDEO_RESULT_MASK_STAT_INTERRUPT;
                     }
                 }
             }
-            // Waiting until the end of the scanline.
+            // Waiting until the end of the scanline: 87 to 204 dots, what M3 left.
             LcdPpuMode::M0 => {
-                // Todo: 87 to 204 dots (I assume depending on object count, reverse (vs M3))
                 let m0_len = 87 + 289 - self.prev_m3_len;
 
                 if self.stat_counter >= m0_len {
                     self.stat_counter -= m0_len;
 
                     // Increase LY.
-                    self.update_ly(self.ly + 1, &mut interrupt_mask);
+                    self.update_ly(self.ly + 1, interrupt_mask);
 
                     if self.ly < 144 {
                         // Mode to 2.
                         if self.set_lcd_stat_ppu_mode(2) {
-                            interrupt_mask |= VIDEO_RESULT_MASK_STAT_INTERRUPT;
+                            *interrupt_mask |= VIDEO_RESULT_MASK_STAT_INTERRUPT;
                         }
                     } else {
                         // Mode to 1.
                         if self.set_lcd_stat_ppu_mode(1) {
-                            interrupt_mask |= VIDEO_RESULT_MASK_STAT_INTERRUPT;
+                            *interrupt_mask |= VIDEO_RESULT_MASK_STAT_INTERRUPT;
                         }
-                        interrupt_mask |= VIDEO_RESULT_MASK_VBLANK_INTERRUPT;
+                        *interrupt_mask |= VIDEO_RESULT_MASK_VBLANK_INTERRUPT;
 
                         self.display_finished
                             .store(true, std::sync::atomic::Ordering::Relaxed);
@@ -195,25 +296,18 @@ impl PPU {
                     self.stat_counter -= 456;
 
                     if self.ly == 152 {
-                        self.update_ly(0, &mut interrupt_mask);
+                        self.update_ly(0, interrupt_mask);
                     } else if self.ly == 0 {
                         // Mode to 2.
                         if self.set_lcd_stat_ppu_mode(2) {
-                            interrupt_mask |= VIDEO_RESULT_MASK_STAT_INTERRUPT;
+                            *interrupt_mask |= VIDEO_RESULT_MASK_STAT_INTERRUPT;
                         }
                     } else {
-                        self.update_ly(self.ly + 1, &mut interrupt_mask);
+                        self.update_ly(self.ly + 1, interrupt_mask);
                     }
                 }
             }
         };
-
-        if self.lyc_change_interrupt {
-            self.lyc_change_interrupt = false;
-            interrupt_mask = interrupt_mask | VIDEO_RESULT_MASK_STAT_INTERRUPT;
-        }
-
-        interrupt_mask
     }
 
     fn update_ly(&mut self, new_ly: u8, interrupt_mask: &mut u8) {
@@ -230,181 +324,209 @@ impl PPU {
         }
     }
 
-    pub fn draw_line_to_screen(&mut self, ly: u8) {
-        if self.is_background_window_display_priority() {
-            self.draw_background_to_screen(ly);
-            self.draw_window_to_screen(ly);
+    /**
+     * Start of mode 3: the OAM scan result and a fresh fetcher.
+     */
+    fn start_line(&mut self) {
+        if self.ly == self.wy {
+            self.wy_triggered = true;
         }
 
-        if self.is_obj_sprite_display_enabled() {
-            self.draw_objects_to_screen(ly);
-        }
+        let tile_height = self.obj_tile_height();
+        let ly = self.ly as u16 + 16;
+        let mut objs = (0..40u8)
+            .filter(|i| {
+                let y = self.oam_ram[*i as usize * 4] as u16;
+                ly >= y && ly < y + tile_height as u16
+            })
+            .take(10)
+            .collect::<Vec<_>>();
+        // Lower X first, OAM order on ties.
+        objs.sort_by_key(|i| self.oam_ram[*i as usize * 4 + 1]);
+
+        self.line = LineFifo::new(self.scx & 0b111, objs);
     }
 
-    fn draw_objects_to_screen(&mut self, ly: u8) {
-        // Object attributes reside in the object attribute memory (OAM) at $FE00-FE9F.
+    /**
+     * One dot of mode 3. Return: whether the 160 pixels of the line are out.
+     */
+    fn update_line_dot(&mut self) -> bool {
+        if self.line.stall > 0 {
+            self.line.stall -= 1;
+            return false;
+        }
 
-        // In 8×8 mode (LCDC bit 2 = 0), this byte specifies the object’s only tile index ($00-$FF).
-        // This unsigned value selects a tile from the memory area at $8000-$8FFF.
-        // In 8×16 mode (LCDC bit 2 = 1), the memory area at $8000-$8FFF is still interpreted as a
-        // series of 8×8 tiles, where every 2 tiles form an object.
-        let tile_height = match self.obj_sprite_size() {
-            ObjSpriteSize::Size8x8 => 8,
-            ObjSpriteSize::Size8x16 => 16,
-        } as i16;
-
-        for i in 0..40 {
-            let byte_y_pos = self.oam_ram[(i * 4) + 0] as i16;
-            let mut tile_y = ly as i16 - (byte_y_pos - 16);
-            if tile_y < 0 || tile_y >= tile_height {
-                // Tile surface does not cover LY.
-                continue;
-            }
+        // The window starts once the pixel at WX - 7 is reached, on a frame where LY matched WY.
+        if !self.line.in_window
+            && self.wy_triggered
+            && self.is_window_display_enabled()
+            && self.wx <= 166
+            && self.line.lx as u16 + 7 >= self.wx as u16
+        {
+            self.line.in_window = true;
+            self.line.bg_fifo.clear();
+            self.line.fetcher_x = 0;
+            self.line.fetcher_dots = 0;
+            self.line.discard = 7u8.saturating_sub(self.wx);
+        }
 
-            let byte_x_pos = self.oam_ram[(i * 4) + 1] as i16;
-            if byte_x_pos == 0 || byte_x_pos - 8 >= DISPLAY_WIDTH as i16 {
-                // Horizontally out of screen.
-                continue;
+        // An object starting at this pixel stops the pipeline while it is fetched, after the background tile. Objects
+        // are placed on LCD pixels, so not before the discarded ones are out.
+        if self.is_obj_sprite_display_enabled()
+            && self.line.discard == 0
+            && !self.line.bg_fifo.is_empty()
+        {
+            if let Some(&i) = self.line.objs.get(self.line.next_obj) {
+                let x = self.oam_ram[i as usize * 4 + 1];
+                if x as u16 <= self.line.lx as u16 + 8 {
+                    self.fetch_obj(i);
+                    self.line.next_obj += 1;
+                    // This dot included.
+                    self.line.stall = 5 + 5 - self.line.fetcher_dots.min(5);
+                    self.line.fetcher_dots = self.line.fetcher_dots.max(6);
+                    return false;
+                }
             }
+        }
 
-            let byte_tile_index = self.oam_ram[(i * 4) + 2] as usize;
-            let byte_attr_and_flags = self.oam_ram[(i * 4) + 3];
-
-            let priority = is_bit(byte_attr_and_flags, 7);
-            let y_flip = is_bit(byte_attr_and_flags, 6);
-            let x_flip = is_bit(byte_attr_and_flags, 5);
-            // DMG palette [Non CGB Mode only]: 0 = OBP0, 1 = OBP1
-            let palette = if is_bit(byte_attr_and_flags, 4) {
-                self.obp1
+        if let Some(bg_color) = self.line.bg_fifo.pop_front() {
+            if self.line.discard > 0 {
+                self.line.discard -= 1;
             } else {
-                self.obp0
-            };
-
-            if y_flip {
-                tile_y = tile_height - 1 - tile_y;
+                let obj = self.line.obj_fifo.pop_front();
+                self.push_lcd_pixel(bg_color, obj);
+                self.line.lx += 1;
             }
+        }
 
-            // MISSING: 10 sprite per line check.
-            // MISSING: mode-3 length adjustment.
-
-            let tile_start_addr = (byte_tile_index * 8 * 2) as usize;
-
-            let row_lo = self.vram[tile_start_addr + (tile_y as usize * 2) + 0];
-            let row_hi = self.vram[tile_start_addr + (tile_y as usize * 2) + 1];
-            for x in 0..8 {
-                let row_bit = if x_flip { x } else { 7 - x };
-                let color = (bit(row_hi, row_bit) << 1) | bit(row_lo, row_bit);
-
-                let physical_x = byte_x_pos - 8 + x as i16;
-                if physical_x < 0 || physical_x >= DISPLAY_WIDTH as i16 {
-                    continue;
-                }
-
-                // Transparency check.
-                if color == 0 {
-                    continue;
-                }
-
-                if !priority || self.is_display_pixel_color_zero(physical_x as _, ly as _) {
-                    self.set_display_pixel(physical_x as _, ly as _, palette, color);
-                }
-            }
+        // Tile number, low and high data: 2 dots each, then wait for the FIFO to run empty.
+        self.line.fetcher_dots = self.line.fetcher_dots.saturating_add(1);
+        if self.line.fetcher_dots >= 6 && self.line.bg_fifo.is_empty() {
+            let (lo, hi) = self.fetch_bg_tile_row();
+            self.line
+                .bg_fifo
+                .extend((0..8).map(|x| (bit(hi, 7 - x) << 1) | bit(lo, 7 - x)));
+            self.line.fetcher_x = self.line.fetcher_x.wrapping_add(1);
+            self.line.fetcher_dots = 0;
         }
+
+        self.line.lx as usize == DISPLAY_WIDTH as usize
     }
 
-    // There are 32x32 tiles on the map: 256x256 pixels.
-    fn draw_background_to_screen(&mut self, ly: u8) {
+    /**
+     * Tile row under the fetcher, with the registers as they are now: SCX/SCY/LCDC writes show up mid-line.
+     */
+    fn fetch_bg_tile_row(&self) -> (u8, u8) {
+        let (tile_map_start, map_x, map_y) = if self.line.in_window {
+            (
+                self.window_tile_map_display_section_start(),
+                self.line.fetcher_x & 0x1F,
+                self.window_line,
+            )
+        } else {
+            (
+                self.background_tile_map_display_section_start(),
+                (self.scx / 8).wrapping_add(self.line.fetcher_x) & 0x1F,
+                self.ly.wrapping_add(self.scy),
+            )
+        };
+        let tile_map_start = (tile_map_start - MEM_AREA_VRAM_START) as usize;
         let tile_data_section_start =
             (self.backround_window_tile_data_section_start() - MEM_AREA_VRAM_START) as usize;
-        let tile_map_start =
-            (self.background_tile_map_display_section_start() - MEM_AREA_VRAM_START) as usize;
-
-        // The background map wraps.
-        let actual_ly = ly.wrapping_add(self.scy);
-
-        let tile_row = actual_ly / 8;
-        let tile_y = actual_ly % 8;
-
-        for i in 0..DISPLAY_WIDTH {
-            let actual_x = self.scx.wrapping_add(i as u8);
-            let tile_col = actual_x / 8;
-            let tile_x = (actual_x % 8) as u8;
-            let tile_data_i = (tile_row as usize * 32) + tile_col as usize;
-            let tile_i = self.vram[tile_map_start + tile_data_i];
 
-            let tile_i = if tile_data_section_start == 0x0800 {
-                tile_i.wrapping_add(128)
-            } else {
-                tile_i
-            };
-
-            let tile_lo =
-                self.vram[tile_data_section_start + tile_i as usize * 16 + tile_y as usize * 2];
-            let tile_hi =
-                self.vram[tile_data_section_start + tile_i as usize * 16 + tile_y as usize * 2 + 1];
-            let color = (bit(tile_hi, 7 - tile_x) << 1) | bit(tile_lo, 7 - tile_x);
+        let tile_i = self.vram[tile_map_start + (map_y as usize / 8) * 32 + map_x as usize];
+        let tile_i = if tile_data_section_start == 0x0800 {
+            tile_i.wrapping_add(128)
+        } else {
+            tile_i
+        };
 
-            self.set_display_pixel(i as _, ly as _, self.bgp, color);
-        }
+        let row_addr = tile_data_section_start + tile_i as usize * 16 + (map_y as usize % 8) * 2;
+        (self.vram[row_addr], self.vram[row_addr + 1])
     }
 
-    fn draw_window_to_screen(&mut self, ly: u8) {
-        if ly < self.wy {
-            return;
-        }
-
-        if self.wx >= DISPLAY_WIDTH as u8 + 7 || !self.is_window_display_enabled() {
-            // If the window is used and a scan line interrupt
-            // disables it (either by writing to LCDC or by setting
-            // WX > 166) and a scan line interrupt a little later on
-            // enables it then the window will resume appearing on
-            // the screen at the exact position of the window where
-            // it left off earlier. This way, even if there are only
-            // 16 lines of useful graphics in the window, you could
-            // display the first 8 lines at the top of the screen and
-            // the next 8 lines at the bottom if you wanted to do so.
-            self.wy_offset += 1;
+    /**
+     * Mixes the row of OAM object `i` into the object FIFO. Pixels already there win, as lower X / OAM index.
+     */
+    fn fetch_obj(&mut self, i: u8) {
+        // Object attributes reside in the object attribute memory (OAM) at $FE00-FE9F.
 
-            return;
+        // In 8×8 mode (LCDC bit 2 = 0), this byte specifies the object’s only tile index ($00-$FF).
+        // This unsigned value selects a tile from the memory area at $8000-$8FFF.
+        // In 8×16 mode (LCDC bit 2 = 1), the memory area at $8000-$8FFF is still interpreted as a
+        // series of 8×8 tiles, where every 2 tiles form an object.
+        let tile_height = self.obj_tile_height();
+        let addr = i as usize * 4;
+        let byte_y_pos = self.oam_ram[addr];
+        let byte_x_pos = self.oam_ram[addr + 1];
+        let byte_tile_index = self.oam_ram[addr + 2];
+        let byte_attr_and_flags = self.oam_ram[addr + 3];
+
+        let behind_bg = is_bit(byte_attr_and_flags, 7);
+        let y_flip = is_bit(byte_attr_and_flags, 6);
+        let x_flip = is_bit(byte_attr_and_flags, 5);
+        // DMG palette [Non CGB Mode only]: 0 = OBP0, 1 = OBP1
+        let is_obp1 = is_bit(byte_attr_and_flags, 4);
+
+        // LCDC.2 may have changed since the OAM scan.
+        let mut tile_y = (self.ly.wrapping_add(16).wrapping_sub(byte_y_pos)) % tile_height;
+        if y_flip {
+            tile_y = tile_height - 1 - tile_y;
         }
+        let tile_index = if tile_height == 16 {
+            byte_tile_index & 0xFE
+        } else {
+            byte_tile_index
+        };
 
-        let tile_data_section_start =
-            (self.backround_window_tile_data_section_start() - MEM_AREA_VRAM_START) as usize;
-        let tile_map_start =
-            (self.window_tile_map_display_section_start() - MEM_AREA_VRAM_START) as usize;
+        let row_addr = tile_index as usize * 16 + tile_y as usize * 2;
+        let (row_lo, row_hi) = (self.vram[row_addr], self.vram[row_addr + 1]);
 
-        let actual_ly = ly as i16 - self.wy as i16 - self.wy_offset as i16;
-        if actual_ly < 0 || actual_ly >= 0x100 {
-            return;
-        }
-
-        let tile_row = actual_ly / 8;
-        let tile_y = actual_ly % 8;
+        // Part of the object left of the screen or already shifted out.
+        let skip = (self.line.lx + 8).saturating_sub(byte_x_pos);
+        for x in skip..8 {
+            let row_bit = if x_flip { x } else { 7 - x };
+            let color = (bit(row_hi, row_bit) << 1) | bit(row_lo, row_bit);
+            let pixel = color | ((is_obp1 as u8) << 2) | ((behind_bg as u8) << 3);
 
-        for i in 0..DISPLAY_WIDTH {
-            let actual_x = i as i16 - (self.wx as i16 - 7);
-            if actual_x < 0 || actual_x >= 0x100 {
-                continue;
+            match self.line.obj_fifo.get_mut((x - skip) as usize) {
+                Some(prev) if *prev & 0b11 == 0 => *prev = pixel,
+                Some(_) => (),
+                None => self.line.obj_fifo.push_back(pixel),
             }
+        }
+    }
 
-            let tile_col = actual_x / 8;
-            let tile_x = (actual_x % 8) as u8;
-            let tile_data_i = (tile_row as usize * 32) + tile_col as usize;
-            let tile_i = self.vram[tile_map_start + tile_data_i];
+    /**
+     * Background/object mix of one pixel, with the palettes and LCDC as they are now.
+     */
+    fn push_lcd_pixel(&mut self, bg_color: u8, obj: Option<u8>) {
+        // On DMG LCDC.0 blanks both the background and the window.
+        let bg_color = if self.is_background_window_display_priority() {
+            bg_color
+        } else {
+            0
+        };
 
-            let tile_i = if tile_data_section_start == 0x0800 {
-                tile_i.wrapping_add(128)
-            } else {
-                tile_i
-            };
+        let (palette, color) = match obj {
+            Some(obj)
+                if self.is_obj_sprite_display_enabled()
+                    && obj & 0b11 != 0
+                    && !(is_bit(obj, 3) && bg_color != 0) =>
+            {
+                let palette = if is_bit(obj, 2) { self.obp1 } else { self.obp0 };
+                (palette, obj & 0b11)
+            }
+            _ => (self.bgp, bg_color),
+        };
 
-            let tile_lo =
-                self.vram[tile_data_section_start + tile_i as usize * 16 + tile_y as usize * 2];
-            let tile_hi =
-                self.vram[tile_data_section_start + tile_i as usize * 16 + tile_y as usize * 2 + 1];
-            let color = (bit(tile_hi, 7 - tile_x) << 1) | bit(tile_lo, 7 - tile_x);
+        self.set_display_pixel(self.line.lx as _, self.ly as _, palette, color);
+    }
 
-            self.set_display_pixel(i as _, ly as _, self.bgp, color);
+    fn obj_tile_height(&self) -> u8 {
+        match self.obj_sprite_size() {
+            ObjSpriteSize::Size8x8 => 8,
+            ObjSpriteSize::Size8x16 => 16,
         }
     }
 
@@ -417,6 +539,42 @@ impl PPU {
             || (self.lcd_ppu_mode() != LcdPpuMode::M2 && self.lcd_ppu_mode() != LcdPpuMode::M3)
     }
 
+    /**
+     * 0xFEA0-0xFEFF on DMG: 0xFF while OAM is blocked, 0x00 otherwise.
+     */
+    pub fn read_prohibited(&self) -> u8 {
+        if self.is_oam_accessible() {
+            0x00
+        } else {
+            0xFF
+        }
+    }
+
+    /**
+     * DMG OAM corruption bug: a CPU access to 0xFE00-0xFEFF while OAM is scanned (mode 2) corrupts the row being
+     * scanned with the preceding one. Rows are 8 bytes (2 objects), one per M-cycle, the first one is never hit.
+     */
+    pub fn corrupt_oam(&mut self, is_write: bool) {
+        if !self.is_lcd_display_enabled() || self.lcd_ppu_mode() != LcdPpuMode::M2 {
+            return;
+        }
+        let row = (self.stat_counter / 4) as usize;
+        if row == 0 || row >= OAM_RAM_SIZE / 8 {
+            return;
+        }
+
+        let (row, prev) = (row * 8, (row - 1) * 8);
+        let word = |i: usize| u16::from_le_bytes([self.oam_ram[i], self.oam_ram[i + 1]]);
+        let (a, b, c) = (word(row), word(prev), word(prev + 4));
+        let first = if is_write {
+            ((a ^ c) & (b ^ c)) ^ c
+        } else {
+            b | (a & c)
+        };
+        self.oam_ram[row..row + 2].copy_from_slice(&first.to_le_bytes());
+        self.oam_ram.copy_within(prev + 2..prev + 8, row + 2);
+    }
+
     pub fn read(&self, loc: u16) -> Result<u8, Error> {
         let byte = match loc {
             MEM_AREA_VRAM_START..=MEM_AREA_VRAM_END => {
@@ -444,13 +602,18 @@ impl PPU {
             MEM_LOC_OBP1 => self.obp1,
             MEM_LOC_WY => self.wy,
             MEM_LOC_WX => self.wx,
-            _ => panic!("Illegal video address read: {:#06X}", loc),
+            _ => {
+                return Err(EmulatorError::BusFault(
+                    loc,
+                    "not a video address".to_string(),
+                ))
+            }
         };
 
         Ok(byte)
     }
 
-    pub fn write(&mut self, loc: u16, byte: u8) {
+    pub fn write(&mut self, loc: u16, byte: u8) -> Result<(), Error> {
         match loc {
             MEM_AREA_VRAM_START..=MEM_AREA_VRAM_END => {
                 if self.is_vram_accessible() {
@@ -470,6 +633,8 @@ impl PPU {
                 if lcdc_on_prev && !lcdc_on_curr {
                     self.stat_counter = 0;
                     self.ly = 0;
+                    self.wy_triggered = false;
+                    self.window_line = 0;
                     let _ = self.set_lcd_stat_ppu_mode(0);
                 }
                 if !lcdc_on_prev && lcdc_on_curr {
@@ -482,7 +647,8 @@ impl PPU {
             }
             MEM_LOC_SCY => self.scy = byte,
             MEM_LOC_SCX => self.scx = byte,
-            MEM_LOC_LY => panic!("Cannot write LY"),
+            // Read only.
+            MEM_LOC_LY => return Err(EmulatorError::OpenBus(loc)),
             MEM_LOC_LYC => {
                 self.lyc = byte;
 
@@ -495,16 +661,83 @@ impl PPU {
             MEM_LOC_OBP1 => self.obp1 = byte,
             MEM_LOC_WY => self.wy = byte,
             MEM_LOC_WX => self.wx = byte,
-            _ => panic!("Illegal video address write: {:#06X}", loc),
+            _ => {
+                return Err(EmulatorError::BusFault(
+                    loc,
+                    "not a video address".to_string(),
+                ))
+            }
         }
+
+        Ok(())
     }
 
-    pub fn dma_oam_transfer(&mut self, block: Vec<u8>) {
-        assert!(block.len() == 0xA0);
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u64(self.stat_counter);
+        w.write_u64(self.prev_m3_len);
+        w.write_u8(self.lcdc);
+        w.write_u8(self.stat);
+        w.write_u8(self.scy);
+        w.write_u8(self.scx);
+        w.write_u8(self.ly);
+        w.write_u8(self.lyc);
+        w.write_u8(self.bgp);
+        w.write_u8(self.obp0);
+        w.write_u8(self.obp1);
+        w.write_u8(self.wy);
+        w.write_u8(self.wx);
+        w.write_bytes(&self.vram);
+        w.write_bytes(&self.oam_ram);
+        w.write_bool(self.lyc_change_interrupt);
+        w.write_bool(self.wy_triggered);
+        w.write_u8(self.window_line);
+        self.line.save_state(w);
+    }
 
-        for (i, byte) in block.iter().enumerate() {
-            self.oam_ram[i] = *byte;
+    /**
+     * The display buffer is not part of the state, it is redrawn by the following frame.
+     */
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.stat_counter = r.read_u64()?;
+        self.prev_m3_len = r.read_u64()?;
+        // Mode 0 takes what mode 3 left of the line.
+        if self.prev_m3_len > 87 + 289 {
+            return Err(EmulatorError::BadState(format!(
+                "Invalid mode 3 length in state: {}",
+                self.prev_m3_len
+            )));
+        }
+        self.lcdc = r.read_u8()?;
+        self.stat = r.read_u8()?;
+        self.scy = r.read_u8()?;
+        self.scx = r.read_u8()?;
+        self.ly = r.read_u8()?;
+        if self.ly > 153 {
+            return Err(EmulatorError::BadState(format!(
+                "Invalid LY in state: {}",
+                self.ly
+            )));
         }
+        self.lyc = r.read_u8()?;
+        self.bgp = r.read_u8()?;
+        self.obp0 = r.read_u8()?;
+        self.obp1 = r.read_u8()?;
+        self.wy = r.read_u8()?;
+        self.wx = r.read_u8()?;
+        r.read_bytes_into(&mut self.vram)?;
+        r.read_bytes_into(&mut self.oam_ram)?;
+        self.lyc_change_interrupt = r.read_bool()?;
+        self.wy_triggered = r.read_bool()?;
+        self.window_line = r.read_u8()?;
+        self.line.load_state(r)?;
+        Ok(())
+    }
+
+    /**
+     * One byte of an OAM DMA transfer, whatever the mode.
+     */
+    pub fn dma_oam_write(&mut self, offs: u8, byte: u8) {
+        self.oam_ram[offs as usize] = byte;
     }
 
     fn is_lcd_display_enabled(&self) -> bool {
@@ -592,6 +825,18 @@ impl PPU {
         is_bit(self.stat, 3)
     }
 
+    /**
+     * Dot of the current scanline (0-455).
+     */
+    pub fn dot(&self) -> u16 {
+        let mode_start = match self.lcd_ppu_mode() {
+            LcdPpuMode::M2 | LcdPpuMode::M1 => 0,
+            LcdPpuMode::M3 => 80,
+            LcdPpuMode::M0 => 80 + self.prev_m3_len,
+        };
+        (mode_start + self.stat_counter) as u16
+    }
+
     fn lcd_ppu_mode(&self) -> LcdPpuMode {
         match self.stat & 0b11 {
             0b00 => LcdPpuMode::M0,
@@ -611,7 +856,8 @@ impl PPU {
         match self.lcd_ppu_mode() {
             LcdPpuMode::M0 => self.is_mode0_hblank_interrupt_enabled(),
             LcdPpuMode::M1 => {
-                self.wy_offset = 0;
+                self.wy_triggered = false;
+                self.window_line = 0;
                 self.is_mode1_vblank_interrupt_enabled()
             }
             LcdPpuMode::M2 => self.is_mode2_oam_interrupt_enabled(),
@@ -739,13 +985,6 @@ impl PPU {
         self.display_buffer[offs + 3] = rgb8888[3];
     }
 
-    fn is_display_pixel_color_zero(&self, x: usize, y: usize) -> bool {
-        let offs = (y * DISPLAY_WIDTH as usize + x) << 2;
-
-        // Practically we can just check the first byte as the colors don't share component values.
-        self.display_buffer[offs] == PALETTE[0][0]
-    }
-
     pub fn debug_oam(&self) {
         for i in 0..40usize {
             let addr = i * 4;
@@ -763,3 +1002,141 @@ impl PPU {
         println!("");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::ppu::*;
+
+    fn ppu_scanning_oam_row_1() -> PPU {
+        let mut ppu = PPU::new();
+        ppu.write(MEM_LOC_LCDC, 0x00).unwrap();
+        for (i, byte) in [0x00, 0xF0, 0x00, 0x00, 0x0F, 0x0F, 0x00, 0x00, 0xAA, 0x55]
+            .iter()
+            .enumerate()
+        {
+            ppu.write(MEM_AREA_OAM_START + i as u16, *byte).unwrap();
+        }
+        assert_eq!(0x00, ppu.read_prohibited());
+        ppu.corrupt_oam(false);
+        assert_eq!([0xAA, 0x55], ppu.oam_ram[8..10]);
+
+        // Mode 2, 4 cycles in.
+        ppu.write(MEM_LOC_LCDC, 0x80).unwrap();
+        assert_eq!(0xFF, ppu.read_prohibited());
+        ppu
+    }
+
+    #[test]
+    fn test_oam_corruption() {
+        let mut ppu = ppu_scanning_oam_row_1();
+        ppu.corrupt_oam(false);
+        assert_eq!(
+            [0x0A, 0xF5, 0x00, 0x00, 0x0F, 0x0F, 0x00, 0x00],
+            ppu.oam_ram[8..16]
+        );
+
+        let mut ppu = ppu_scanning_oam_row_1();
+        ppu.corrupt_oam(true);
+        assert_eq!(
+            [0x0A, 0x55, 0x00, 0x00, 0x0F, 0x0F, 0x00, 0x00],
+            ppu.oam_ram[8..16]
+        );
+        // Row 0 is left alone.
+        assert_eq!([0x00, 0xF0], ppu.oam_ram[0..2]);
+    }
+
+    /**
+     * LCD on with BG and objects, tile 0 solid color 3 on the whole map. Returns in mode 3 of line 0.
+     */
+    fn ppu_in_mode_3(scx: u8, objs: &[(u8, u8)]) -> PPU {
+        let mut ppu = PPU::new();
+        ppu.write(MEM_LOC_LCDC, 0x00).unwrap();
+        for i in 0..16 {
+            ppu.write(MEM_AREA_VRAM_START + i, 0xFF).unwrap();
+        }
+        for (i, (y, x)) in objs.iter().enumerate() {
+            ppu.write(MEM_AREA_OAM_START + i as u16 * 4, *y).unwrap();
+            ppu.write(MEM_AREA_OAM_START + i as u16 * 4 + 1, *x)
+                .unwrap();
+        }
+        ppu.write(MEM_LOC_BGP, 0b1110_0100).unwrap();
+        ppu.write(MEM_LOC_SCX, scx).unwrap();
+        ppu.write(MEM_LOC_LCDC, 0x93).unwrap();
+        while ppu.lcd_ppu_mode() != LcdPpuMode::M3 {
+            let _ = ppu.update(1);
+        }
+        ppu
+    }
+
+    fn finish_mode_3(ppu: &mut PPU) -> u64 {
+        while ppu.lcd_ppu_mode() == LcdPpuMode::M3 {
+            let _ = ppu.update(1);
+        }
+        ppu.prev_m3_len
+    }
+
+    #[test]
+    fn test_mode_3_length() {
+        assert_eq!(172, finish_mode_3(&mut ppu_in_mode_3(0, &[])));
+        assert_eq!(175, finish_mode_3(&mut ppu_in_mode_3(3, &[])));
+        // Object at the left edge, waiting for the whole first tile fetch.
+        assert_eq!(172 + 11, finish_mode_3(&mut ppu_in_mode_3(0, &[(16, 8)])));
+        // Fetched once the SCX fine scroll is discarded, the penalty depends on it.
+        assert_eq!(175 + 8, finish_mode_3(&mut ppu_in_mode_3(3, &[(16, 8)])));
+        // Off line 0, no penalty.
+        assert_eq!(172, finish_mode_3(&mut ppu_in_mode_3(0, &[(40, 8)])));
+
+        let mut ppu = ppu_in_mode_3(0, &[]);
+        finish_mode_3(&mut ppu);
+        while ppu.lcd_ppu_mode() == LcdPpuMode::M0 {
+            let _ = ppu.update(1);
+        }
+        assert_eq!(1, ppu.ly);
+        assert_eq!(0, ppu.dot());
+    }
+
+    #[test]
+    fn test_obj_with_fine_scroll() {
+        let mut ppu = ppu_in_mode_3(3, &[(16, 8)]);
+        // Object tile 1: a single color 1 column on the left.
+        ppu.oam_ram[2] = 1;
+        ppu.vram[16] = 0x80;
+        ppu.obp0 = 0b1110_0100;
+        finish_mode_3(&mut ppu);
+
+        let pixel = |x: usize| &ppu.display_buffer[x * 4..x * 4 + 4];
+        assert_eq!(PALETTE[1], pixel(0));
+        assert_eq!(PALETTE[3], pixel(1));
+    }
+
+    #[test]
+    fn test_mid_line_palette_write() {
+        let mut ppu = ppu_in_mode_3(0, &[]);
+        let _ = ppu.update(12 + 80);
+        ppu.write(MEM_LOC_BGP, 0x00).unwrap();
+        finish_mode_3(&mut ppu);
+
+        let pixel = |x: usize| &ppu.display_buffer[x * 4..x * 4 + 4];
+        assert_eq!(PALETTE[3], pixel(0));
+        assert_eq!(PALETTE[3], pixel(79));
+        assert_eq!(PALETTE[0], pixel(80));
+        assert_eq!(PALETTE[0], pixel(159));
+    }
+
+    #[test]
+    fn test_load_tampered_state() {
+        let mut w = StateWriter::new();
+        PPU::new().save_state(&mut w);
+        let mut state = w.into_inner();
+        PPU::new()
+            .load_state(&mut StateReader::new(&state))
+            .unwrap();
+
+        // Mode 3 length, right after the stat counter.
+        state[8..16].copy_from_slice(&377u64.to_le_bytes());
+        assert!(matches!(
+            PPU::new().load_state(&mut StateReader::new(&state)),
+            Err(EmulatorError::BadState(_))
+        ));
+    }
+}
@@ -0,0 +1,361 @@
+/**
+ * CPU profiler: every executed M-cycle is attributed to its PC, its ROM bank and its node in the call tree built
+ * from the shadow call stack. HALT cycles and interrupt handlers are counted separately.
+ *
+ * The call tree can be exported as folded stacks (`main;Func;Inner 1234` lines), the input of flamegraph tools.
+ */
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::call_stack::CallStack;
+use crate::conf::*;
+use crate::symbols::Symbols;
+use crate::util::Stats;
+use crate::vm::Interrupt;
+
+const TOP_COUNT: usize = 16;
+// Frames of the busy cycles statistics.
+const FRAME_WINDOW: usize = 600;
+
+struct Node {
+    // Bank + address of the called code, none for the root.
+    entry: Option<(u8, u16)>,
+    interrupt: Option<Interrupt>,
+    parent: usize,
+    children: HashMap<(u8, u16), usize>,
+    // Innermost interrupt handler on the path from the root.
+    handler: Option<Interrupt>,
+    mcycles: u64,
+}
+
+pub struct Profiler {
+    // Folded stacks output.
+    path: String,
+    total: u64,
+    halt: u64,
+    // PC with its bank (0 outside 0x4000-0x7FFF).
+    by_pc: HashMap<(u8, u16), u64>,
+    // None for code running from RAM.
+    by_bank: HashMap<Option<u8>, u64>,
+    // Services + cycles of the handlers, nested calls included.
+    interrupts: HashMap<Interrupt, (u64, u64)>,
+    nodes: Vec<Node>,
+    frames: u64,
+    frame_mcycles: u64,
+    frame_stats: Stats,
+}
+
+/**
+ * Bank of the code at `pc`: 0 for 0x0000-0x3FFF, `rom_bank` for 0x4000-0x7FFF, none for RAM.
+ */
+pub fn bank_at(pc: u16, rom_bank: u8) -> Option<u8> {
+    if pc <= MEM_AREA_ROM_BANK_0_END {
+        Some(0)
+    } else if pc <= MEM_AREA_ROM_BANK_N_END {
+        Some(rom_bank)
+    } else {
+        None
+    }
+}
+
+fn describe(symbols: &Symbols, bank: u8, addr: u16) -> String {
+    let location = if (MEM_AREA_ROM_BANK_N_START..=MEM_AREA_ROM_BANK_N_END).contains(&addr) {
+        format!("{:02X}:{:04X}", bank, addr)
+    } else {
+        format!("{:04X}", addr)
+    };
+    match symbols.describe(addr, bank) {
+        Some(label) => format!("{} {}", location, label),
+        None => location,
+    }
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    part as f64 * 100.0 / total.max(1) as f64
+}
+
+impl Profiler {
+    pub fn new(path: String) -> Profiler {
+        Profiler {
+            path,
+            total: 0,
+            halt: 0,
+            by_pc: HashMap::new(),
+            by_bank: HashMap::new(),
+            interrupts: HashMap::new(),
+            nodes: vec![Node {
+                entry: None,
+                interrupt: None,
+                parent: 0,
+                children: HashMap::new(),
+                handler: None,
+                mcycles: 0,
+            }],
+            frames: 0,
+            frame_mcycles: 0,
+            frame_stats: Stats::new(FRAME_WINDOW, 1),
+        }
+    }
+
+    /**
+     * Call tree node of the current call stack, created when first seen.
+     */
+    pub fn node(&mut self, call_stack: &CallStack) -> usize {
+        let mut node = 0;
+        for frame in call_stack.frames().rev() {
+            let bank = bank_at(frame.entry, frame.rom_bank).unwrap_or(0);
+            node = match self.nodes[node].children.get(&(bank, frame.entry)) {
+                Some(child) => *child,
+                None => {
+                    let child = self.nodes.len();
+                    let handler = frame.interrupt.or(self.nodes[node].handler);
+                    self.nodes.push(Node {
+                        entry: Some((bank, frame.entry)),
+                        interrupt: frame.interrupt,
+                        parent: node,
+                        children: HashMap::new(),
+                        handler,
+                        mcycles: 0,
+                    });
+                    self.nodes[node].children.insert((bank, frame.entry), child);
+                    child
+                }
+            };
+        }
+        node
+    }
+
+    /**
+     * Cycles of one step: an instruction at `pc` (plus the interrupt dispatch leading to it), or a HALT wait.
+     */
+    pub fn add(&mut self, node: usize, pc: u16, rom_bank: u8, mcycles: u64, is_halted: bool) {
+        self.total += mcycles;
+        if is_halted {
+            self.halt += mcycles;
+            return;
+        }
+
+        self.frame_mcycles += mcycles;
+        let bank = bank_at(pc, rom_bank);
+        *self.by_pc.entry((bank.unwrap_or(0), pc)).or_default() += mcycles;
+        *self.by_bank.entry(bank).or_default() += mcycles;
+        self.nodes[node].mcycles += mcycles;
+        if let Some(interrupt) = self.nodes[node].handler {
+            self.interrupts.entry(interrupt).or_default().1 += mcycles;
+        }
+    }
+
+    pub fn count_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupts.entry(interrupt).or_default().0 += 1;
+    }
+
+    pub fn end_frame(&mut self) {
+        self.frames += 1;
+        self.frame_stats.push(self.frame_mcycles as i32);
+        self.frame_mcycles = 0;
+    }
+
+    fn node_name(&self, node: usize, symbols: &Symbols) -> String {
+        match (self.nodes[node].entry, self.nodes[node].interrupt) {
+            (_, Some(interrupt)) => format!("int:{:?}", interrupt),
+            (Some((bank, addr)), None) => symbols
+                .describe(addr, bank)
+                .unwrap_or_else(|| format!("{:02X}:{:04X}", bank, addr)),
+            (None, None) => "main".to_string(),
+        }
+    }
+
+    fn path(&self, mut node: usize, symbols: &Symbols) -> Vec<String> {
+        let mut names = vec![self.node_name(node, symbols)];
+        while node != 0 {
+            node = self.nodes[node].parent;
+            names.push(self.node_name(node, symbols));
+        }
+        names.reverse();
+        names
+    }
+
+    /**
+     * Cycles of the functions: own cycles, and cycles with their callees (counted once under recursion).
+     */
+    fn functions(&self) -> HashMap<(u8, u16), (u64, u64)> {
+        let mut functions: HashMap<(u8, u16), (u64, u64)> = HashMap::new();
+        for (i, node) in self.nodes.iter().enumerate().skip(1) {
+            if let Some(entry) = node.entry {
+                functions.entry(entry).or_default().0 += node.mcycles;
+            }
+
+            let mut seen = vec![];
+            let mut ancestor = i;
+            while ancestor != 0 {
+                if let Some(entry) = self.nodes[ancestor].entry {
+                    if !seen.contains(&entry) {
+                        seen.push(entry);
+                        functions.entry(entry).or_default().1 += node.mcycles;
+                    }
+                }
+                ancestor = self.nodes[ancestor].parent;
+            }
+        }
+        functions
+    }
+
+    pub fn report(&mut self, symbols: &Symbols) -> Vec<String> {
+        let total = self.total;
+        let busy = total - self.halt;
+        let mut lines = vec![format!(
+            "Profile: {} M-cycles over {} frames, HALT {} ({:.1}%)",
+            total,
+            self.frames,
+            self.halt,
+            percent(self.halt, total)
+        )];
+        if self.frames > 0 {
+            lines.push(format!(
+                "Busy M-cycles per frame: avg {} / p90 {} (of {})",
+                self.frame_stats.avg(),
+                self.frame_stats.p90(),
+                CLOCKS_PER_FRAME / CYCLE_PER_MCYCLE
+            ));
+        }
+
+        lines.push("Hotspots:".to_string());
+        let mut by_pc = self.by_pc.iter().collect::<Vec<_>>();
+        by_pc.sort_by_key(|(_, mcycles)| Reverse(**mcycles));
+        for ((bank, pc), mcycles) in by_pc.into_iter().take(TOP_COUNT) {
+            lines.push(format!(
+                "  {:5.1}% {:>10}  {}",
+                percent(*mcycles, busy),
+                mcycles,
+                describe(symbols, *bank, *pc)
+            ));
+        }
+
+        lines.push("Functions (self / with callees):".to_string());
+        let mut functions = self.functions().into_iter().collect::<Vec<_>>();
+        functions.sort_by_key(|(_, (_, with_callees))| Reverse(*with_callees));
+        for ((bank, addr), (own, with_callees)) in functions.into_iter().take(TOP_COUNT) {
+            lines.push(format!(
+                "  {:5.1}% {:5.1}%  {}",
+                percent(own, busy),
+                percent(with_callees, busy),
+                describe(symbols, bank, addr)
+            ));
+        }
+
+        lines.push("ROM banks:".to_string());
+        let mut by_bank = self.by_bank.iter().collect::<Vec<_>>();
+        by_bank.sort();
+        for (bank, mcycles) in by_bank {
+            let name = match bank {
+                Some(bank) => format!("{:02X}", bank),
+                None => "RAM".to_string(),
+            };
+            lines.push(format!(
+                "  {:>3} {:5.1}% {:>10}",
+                name,
+                percent(*mcycles, busy),
+                mcycles
+            ));
+        }
+
+        lines.push("Interrupt handlers:".to_string());
+        let mut interrupts = self.interrupts.iter().collect::<Vec<_>>();
+        interrupts.sort_by_key(|(_, (_, mcycles))| Reverse(*mcycles));
+        for (interrupt, (count, mcycles)) in interrupts {
+            lines.push(format!(
+                "  {:<7} {:5.1}% {:>10}  {} calls, {} per call",
+                format!("{:?}", interrupt),
+                percent(*mcycles, busy),
+                mcycles,
+                count,
+                mcycles / (*count).max(1)
+            ));
+        }
+
+        lines
+    }
+
+    pub fn save(&self, symbols: &Symbols) -> Result<(), Error> {
+        std::fs::write(&self.path, self.folded_stacks(symbols))?;
+        log::info!("Profile saved: {}", self.path);
+        Ok(())
+    }
+
+    pub fn folded_stacks(&self, symbols: &Symbols) -> String {
+        let mut out = String::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.mcycles > 0 {
+                let _ = writeln!(out, "{} {}", self.path(i, symbols).join(";"), node.mcycles);
+            }
+        }
+        if self.halt > 0 {
+            let _ = writeln!(out, "[halt] {}", self.halt);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::call_stack::CallFrame;
+    use crate::profiler::*;
+
+    fn frame(entry: u16, sp: u16, interrupt: Option<Interrupt>) -> CallFrame {
+        CallFrame {
+            return_addr: 0,
+            entry,
+            sp,
+            rom_bank: 2,
+            interrupt,
+        }
+    }
+
+    #[test]
+    fn test_call_tree_attribution() {
+        let symbols = Symbols::parse("00:0150 Main\n02:4000 Update\n00:0040 VBlank\n").unwrap();
+        let mut profiler = Profiler::new(String::new());
+        let mut call_stack = CallStack::new();
+
+        let root = profiler.node(&call_stack);
+        profiler.add(root, 0x0150, 2, 10, false);
+
+        call_stack.push(frame(0x4000, 0xFFFC, None));
+        let update = profiler.node(&call_stack);
+        profiler.add(update, 0x4002, 2, 30, false);
+
+        call_stack.push(frame(0x0040, 0xFFFA, Some(Interrupt::VBlank)));
+        profiler.count_interrupt(Interrupt::VBlank);
+        let vblank = profiler.node(&call_stack);
+        profiler.add(vblank, 0x0040, 2, 20, false);
+
+        call_stack.clear();
+        assert_eq!(root, profiler.node(&call_stack));
+        profiler.add(root, 0x0152, 2, 40, true);
+        profiler.end_frame();
+
+        assert_eq!(
+            "[halt] 40\nmain 10\nmain;Update 30\nmain;Update;int:VBlank 20\n",
+            reorder(&profiler.folded_stacks(&symbols))
+        );
+        assert_eq!(Some(&(1, 20)), profiler.interrupts.get(&Interrupt::VBlank));
+        assert_eq!(Some(&30), profiler.by_bank.get(&Some(2)));
+        assert_eq!(Some(&(30, 50)), profiler.functions().get(&(2, 0x4000)));
+
+        let report = profiler.report(&symbols);
+        assert_eq!(
+            "Profile: 100 M-cycles over 1 frames, HALT 40 (40.0%)",
+            report[0]
+        );
+        assert!(report
+            .iter()
+            .any(|line| line.contains("50.0%") && line.ends_with("02:4002 Update+2")));
+    }
+
+    fn reorder(folded: &str) -> String {
+        let mut lines = folded.lines().collect::<Vec<_>>();
+        lines.sort();
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/**
+ * Ring buffer of machine state snapshots for rewinding.
+ *
+ * The newest snapshot is kept uncompressed (head). Every older snapshot is stored as an XOR delta against the one
+ * following it, run-length encoded - consecutive states mostly differ in a few bytes so zero runs dominate.
+ * Walking backwards means applying the newest delta to the head. When the memory budget is exceeded the oldest
+ * deltas are dropped.
+ */
+pub struct Rewind {
+    // Snapshot is taken every N frames.
+    interval: u32,
+    // Max bytes for the compressed deltas.
+    budget: usize,
+    frame_counter: u32,
+    head: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+    deltas_size: usize,
+    rewind_flag: Arc<AtomicBool>,
+}
+
+impl Rewind {
+    pub fn new(interval: u32, budget: usize, rewind_flag: Arc<AtomicBool>) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            frame_counter: 0,
+            head: None,
+            deltas: VecDeque::new(),
+            deltas_size: 0,
+            rewind_flag,
+        }
+    }
+
+    /**
+     * Whether the rewind hotkey is held.
+     */
+    pub fn is_rewinding(&self) -> bool {
+        self.rewind_flag.load(Ordering::Relaxed)
+    }
+
+    /**
+     * Call on every frame end. Returns true when a snapshot should be pushed.
+     */
+    pub fn should_capture(&mut self) -> bool {
+        self.frame_counter += 1;
+        if self.frame_counter >= self.interval {
+            self.frame_counter = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn push(&mut self, snapshot: Vec<u8>) {
+        if let Some(prev) = self.head.take() {
+            let delta = compress_delta(&snapshot, &prev);
+            self.deltas_size += delta.len();
+            self.deltas.push_back(delta);
+
+            while self.deltas_size > self.budget {
+                match self.deltas.pop_front() {
+                    Some(delta) => self.deltas_size -= delta.len(),
+                    None => break,
+                }
+            }
+        }
+
+        self.head = Some(snapshot);
+    }
+
+    /**
+     * Returns the newest snapshot and steps the head back by one. Once only the oldest snapshot is left it keeps
+     * being returned.
+     */
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let head = self.head.as_mut()?;
+        let snapshot = head.clone();
+
+        if let Some(delta) = self.deltas.pop_back() {
+            self.deltas_size -= delta.len();
+            apply_delta(head, &delta);
+        }
+
+        self.frame_counter = 0;
+
+        Some(snapshot)
+    }
+}
+
+/**
+ * Encodes `base ^ target` (zero padded to the longer one) as a list of (zero run, literal run, literals) chunks.
+ * The first 4 bytes hold the length of `target`.
+ */
+fn compress_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let len = base.len().max(target.len());
+    let xor_at = |i: usize| base.get(i).unwrap_or(&0) ^ target.get(i).unwrap_or(&0);
+
+    let mut out = vec![];
+    out.extend_from_slice(&(target.len() as u32).to_le_bytes());
+
+    let mut i = 0;
+    while i < len {
+        let zero_start = i;
+        while i < len && xor_at(i) == 0 {
+            i += 1;
+        }
+        let literal_start = i;
+        while i < len && xor_at(i) != 0 {
+            i += 1;
+        }
+
+        write_varint(&mut out, literal_start - zero_start);
+        write_varint(&mut out, i - literal_start);
+        (literal_start..i).for_each(|j| out.push(xor_at(j)));
+    }
+
+    out
+}
+
+/**
+ * Turns `base` into the `target` the delta was made with.
+ */
+fn apply_delta(base: &mut Vec<u8>, delta: &[u8]) {
+    let target_len = u32::from_le_bytes([delta[0], delta[1], delta[2], delta[3]]) as usize;
+    if base.len() < target_len {
+        base.resize(target_len, 0);
+    }
+
+    let mut pos = 4;
+    let mut i = 0;
+    while pos < delta.len() {
+        i += read_varint(delta, &mut pos);
+        let literal_len = read_varint(delta, &mut pos);
+
+        for _ in 0..literal_len {
+            if i < base.len() {
+                base[i] ^= delta[pos];
+            }
+            i += 1;
+            pos += 1;
+        }
+    }
+
+    base.truncate(target_len);
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0usize;
+    let mut shift = 0;
+    loop {
+        let byte = buf[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rewind::*;
+
+    #[test]
+    fn test_delta_round_trip() {
+        let prev = vec![1u8, 2, 3, 4, 5, 6, 7, 8, 9];
+        let mut curr = prev.clone();
+        curr[2] = 0xFF;
+        curr[7] = 0;
+        curr.push(0xAA);
+
+        let delta = compress_delta(&curr, &prev);
+        let mut restored = curr.clone();
+        apply_delta(&mut restored, &delta);
+        assert_eq!(prev, restored);
+    }
+
+    #[test]
+    fn test_pop_walks_backwards() {
+        let mut rewind = Rewind::new(1, usize::MAX, Arc::new(AtomicBool::new(false)));
+        rewind.push(vec![0; 300]);
+        rewind.push(vec![1; 300]);
+        rewind.push(vec![2; 300]);
+
+        assert_eq!(Some(vec![2; 300]), rewind.pop());
+        assert_eq!(Some(vec![1; 300]), rewind.pop());
+        assert_eq!(Some(vec![0; 300]), rewind.pop());
+        assert_eq!(Some(vec![0; 300]), rewind.pop());
+    }
+
+    #[test]
+    fn test_budget_drops_oldest() {
+        let mut rewind = Rewind::new(1, 0, Arc::new(AtomicBool::new(false)));
+        rewind.push(vec![0; 8]);
+        rewind.push(vec![1; 8]);
+
+        assert_eq!(Some(vec![1; 8]), rewind.pop());
+        assert_eq!(Some(vec![1; 8]), rewind.pop());
+    }
+}
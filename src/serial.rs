@@ -1,17 +1,47 @@
 use log::warn;
 
-pub struct Serial;
+use crate::conf::Error;
+use crate::state::*;
+
+/**
+ * Serial port registers, without transfers.
+ */
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+}
 
 impl Serial {
     pub fn new() -> Serial {
-        Serial
+        Serial { sb: 0, sc: 0 }
     }
 
     pub fn set_sb(&mut self, value: u8) {
         warn!("Serial - SB Set: {}", value);
+        self.sb = value;
     }
 
     pub fn set_sc(&mut self, value: u8) {
         warn!("Serial - SC Set: {}", value);
+        self.sc = value;
+    }
+
+    pub fn sb(&self) -> u8 {
+        self.sb
+    }
+
+    pub fn sc(&self) -> u8 {
+        self.sc
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.sb);
+        w.write_u8(self.sc);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.sb = r.read_u8()?;
+        self.sc = r.read_u8()?;
+        Ok(())
     }
 }
@@ -0,0 +1,168 @@
+/**
+ * Source lines of labels, from the label definitions (`Label:`, `Label::`, `.local:`) of the assembly files under a
+ * directory, located through the symbols. Symbol and map files have no line information, so addresses map to the
+ * line of the label they belong to.
+ */
+use std::collections::HashMap;
+use std::fs::{read_dir, read_to_string};
+use std::path::{Path, PathBuf};
+
+use crate::symbols::Symbols;
+
+const SOURCE_EXTENSIONS: [&str; 4] = ["asm", "s", "inc", "z80"];
+
+#[derive(Default)]
+pub struct SourceMap {
+    // Label definitions of each file: line (1 based) + bank + address, by line.
+    by_file: HashMap<PathBuf, Vec<(usize, u8, u16)>>,
+    by_location: HashMap<(u8, u16), (PathBuf, usize)>,
+}
+
+/**
+ * Label defined at the start of a line. Local labels are returned as found, starting with `.`.
+ */
+fn label_definition(line: &str) -> Option<&str> {
+    let line = line.split(';').next().unwrap_or("");
+    if line.starts_with(char::is_whitespace) {
+        return None;
+    }
+
+    let (name, _) = line.split_once(':')?;
+    let is_valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.#@".contains(c));
+    is_valid.then_some(name)
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap::default()
+    }
+
+    /**
+     * Scans the assembly files under `dir`, recursively.
+     */
+    pub fn scan(dir: &Path, symbols: &Symbols) -> SourceMap {
+        let mut source_map = SourceMap::new();
+        let mut dirs = vec![dir.to_path_buf()];
+
+        while let Some(dir) = dirs.pop() {
+            let entries = match read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    log::warn!("Cannot scan sources in {}: {}", dir.display(), err);
+                    continue;
+                }
+            };
+
+            for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if path
+                    .extension()
+                    .is_some_and(|ext| SOURCE_EXTENSIONS.iter().any(|e| ext == *e))
+                {
+                    if let Ok(raw) = read_to_string(&path) {
+                        source_map.add_file(path, &raw, symbols);
+                    }
+                }
+            }
+        }
+
+        log::info!(
+            "Source map: {} labels in {} files",
+            source_map.by_location.len(),
+            source_map.by_file.len()
+        );
+        source_map
+    }
+
+    fn add_file(&mut self, path: PathBuf, raw: &str, symbols: &Symbols) {
+        let mut scope = String::new();
+        let mut labels = vec![];
+
+        for (line_i, line) in raw.lines().enumerate() {
+            let name = match label_definition(line) {
+                Some(name) if name.starts_with('.') => format!("{}{}", scope, name),
+                Some(name) => {
+                    scope = name.split('.').next().unwrap_or(name).to_string();
+                    name.to_string()
+                }
+                None => continue,
+            };
+
+            // Any bank, labels are unique within a program.
+            if let Some((bank, addr)) = symbols.lookup(&name, 0) {
+                labels.push((line_i + 1, bank, addr));
+                self.by_location
+                    .entry((bank, addr))
+                    .or_insert((path.clone(), line_i + 1));
+            }
+        }
+
+        if !labels.is_empty() {
+            self.by_file.insert(path, labels);
+        }
+    }
+
+    /**
+     * Label at or before `line` in the file: its line, bank and address.
+     */
+    pub fn resolve_line(&self, path: &Path, line: usize) -> Option<(usize, u8, u16)> {
+        let labels = self.by_file.get(path).or_else(|| {
+            // Clients may send another form of the same path.
+            let canonical = path.canonicalize().ok()?;
+            self.by_file
+                .iter()
+                .find(|(p, _)| p.canonicalize().ok().as_ref() == Some(&canonical))
+                .map(|(_, labels)| labels)
+        })?;
+        labels
+            .iter()
+            .filter(|(label_line, _, _)| *label_line <= line)
+            .max_by_key(|(label_line, _, _)| *label_line)
+            .copied()
+    }
+
+    /**
+     * File and line of the label of an address, `label` being its name as given by `Symbols::describe`.
+     */
+    pub fn locate(&self, symbols: &Symbols, label: &str) -> Option<(&Path, usize)> {
+        let name = label.split('+').next().unwrap_or(label);
+        let location = symbols.lookup(name, 0)?;
+        self.by_location
+            .get(&location)
+            .map(|(path, line)| (path.as_path(), *line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::source_map::*;
+
+    #[test]
+    fn test_labels_to_lines() {
+        let symbols = Symbols::parse("00:0150 Main\n00:0158 Main.loop\n01:4000 Banked\n").unwrap();
+        let mut source_map = SourceMap::new();
+        let source = "; header\n\
+                      Main::\n\
+                      \tld a, 1\n\
+                      .loop: ; wait\n\
+                      \tjr .loop\n\
+                      \n\
+                      Banked:\n\
+                      \tret\n";
+        let path = PathBuf::from("main.asm");
+        source_map.add_file(path.clone(), source, &symbols);
+
+        assert_eq!(Some((2, 0, 0x0150)), source_map.resolve_line(&path, 3));
+        assert_eq!(Some((4, 0, 0x0158)), source_map.resolve_line(&path, 5));
+        assert_eq!(Some((7, 1, 0x4000)), source_map.resolve_line(&path, 8));
+        assert_eq!(None, source_map.resolve_line(&path, 1));
+        assert_eq!(
+            Some((path.as_path(), 4)),
+            source_map.locate(&symbols, "Main.loop+2")
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+
+/**
+ * Emulation speed settings shared between the UI (hotkeys) and the VM thread (limiter).
+ */
+pub struct SpeedControl {
+    // Multiplier used while fast-forwarding. 0.0 means unlimited.
+    fast_forward_speed: f64,
+    fast_forward_hold: AtomicBool,
+    fast_forward_toggle: AtomicBool,
+    // 0: off, 1: 0.5x, 2: 0.25x.
+    slow_motion_level: AtomicU8,
+    paused: AtomicBool,
+    // Frames requested to run while paused.
+    frame_advance_requests: AtomicU32,
+}
+
+impl SpeedControl {
+    pub fn new(fast_forward_speed: f64) -> SpeedControl {
+        SpeedControl {
+            fast_forward_speed,
+            fast_forward_hold: AtomicBool::new(false),
+            fast_forward_toggle: AtomicBool::new(false),
+            slow_motion_level: AtomicU8::new(0),
+            paused: AtomicBool::new(false),
+            frame_advance_requests: AtomicU32::new(0),
+        }
+    }
+
+    pub fn set_fast_forward_hold(&self, is_on: bool) {
+        self.fast_forward_hold.store(is_on, Ordering::Relaxed);
+    }
+
+    pub fn toggle_fast_forward(&self) {
+        self.fast_forward_toggle.fetch_xor(true, Ordering::Relaxed);
+    }
+
+    /**
+     * Cycles 1x -> 0.5x -> 0.25x -> 1x.
+     */
+    pub fn cycle_slow_motion(&self) {
+        let level = (self.slow_motion_level.load(Ordering::Relaxed) + 1) % 3;
+        self.slow_motion_level.store(level, Ordering::Relaxed);
+        log::info!("Speed: {}x", self.multiplier().unwrap_or(f64::INFINITY));
+    }
+
+    pub fn toggle_pause(&self) {
+        let was_paused = self.paused.fetch_xor(true, Ordering::Relaxed);
+        self.frame_advance_requests.store(0, Ordering::Relaxed);
+        log::info!("Paused: {}", !was_paused);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /**
+     * Runs one more frame while paused. Pauses when not yet paused.
+     */
+    pub fn request_frame_advance(&self) {
+        if self.paused.swap(true, Ordering::Relaxed) {
+            self.frame_advance_requests.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn consume_frame_advance(&self) -> bool {
+        self.frame_advance_requests
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok()
+    }
+
+    /**
+     * Current speed relative to real hardware. None when unlimited.
+     */
+    pub fn multiplier(&self) -> Option<f64> {
+        if self.fast_forward_hold.load(Ordering::Relaxed)
+            || self.fast_forward_toggle.load(Ordering::Relaxed)
+        {
+            return if self.fast_forward_speed > 0.0 {
+                Some(self.fast_forward_speed)
+            } else {
+                None
+            };
+        }
+
+        match self.slow_motion_level.load(Ordering::Relaxed) {
+            1 => Some(0.5),
+            2 => Some(0.25),
+            _ => Some(1.0),
+        }
+    }
+
+    pub fn is_natural_speed(&self) -> bool {
+        self.multiplier() == Some(1.0)
+    }
+}
@@ -0,0 +1,159 @@
+/**
+ * Machine state serialization.
+ *
+ * Every stateful component writes its fields in a fixed order into a flat byte buffer and reads them back in the
+ * same order. There is no versioning - a state is only valid for the same build and the same cartridge.
+ */
+use crate::conf::Error;
+use crate::error::EmulatorError;
+
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { buf: vec![] }
+    }
+
+    pub fn write_u8(&mut self, byte: u8) {
+        self.buf.push(byte);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, word: u16) {
+        self.buf.extend_from_slice(&word.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_f32(&mut self, value: f32) {
+        self.write_u32(value.to_bits());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub struct StateReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(buf: &'a [u8]) -> StateReader<'a> {
+        StateReader { buf, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.pos + len > self.buf.len() {
+            return Err(EmulatorError::BadState(format!(
+                "Machine state is truncated: need {} bytes at {}, have {}",
+                len,
+                self.pos,
+                self.buf.len()
+            )));
+        }
+
+        let slice = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, Error> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, Error> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, Error> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, Error> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, Error> {
+        Ok(f32::from_bits(self.read_u32()?))
+    }
+
+    pub fn read_bytes(&mut self) -> Result<Vec<u8>, Error> {
+        let len = self.read_u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    /**
+     * Reads a length prefixed block into a fixed size buffer. The length must match exactly.
+     */
+    pub fn read_bytes_into(&mut self, out: &mut [u8]) -> Result<(), Error> {
+        let len = self.read_u32()? as usize;
+        if len != out.len() {
+            return Err(EmulatorError::BadState(format!(
+                "Machine state block size mismatch: expected {}, got {}",
+                out.len(),
+                len
+            )));
+        }
+
+        out.copy_from_slice(self.take(len)?);
+        Ok(())
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.pos == self.buf.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::state::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.write_u8(0xAB);
+        writer.write_bool(true);
+        writer.write_u16(0x1234);
+        writer.write_u64(0xDEAD_BEEF_0000_0001);
+        writer.write_bytes(&[1, 2, 3]);
+        let buf = writer.into_inner();
+
+        let mut reader = StateReader::new(&buf);
+        assert_eq!(0xAB, reader.read_u8().unwrap());
+        assert!(reader.read_bool().unwrap());
+        assert_eq!(0x1234, reader.read_u16().unwrap());
+        assert_eq!(0xDEAD_BEEF_0000_0001, reader.read_u64().unwrap());
+
+        let mut block = [0u8; 3];
+        reader.read_bytes_into(&mut block).unwrap();
+        assert_eq!([1, 2, 3], block);
+        assert!(reader.is_finished());
+        assert!(reader.read_u8().is_err());
+    }
+}
@@ -0,0 +1,225 @@
+/**
+ * Symbol files as generated by RGBDS (`rgblink -n`) or no$gmb: one `BB:AAAA Label` per line, `;` comments.
+ * RGBDS map files (`rgblink -m`, `.map`) are read for the symbols listed under their bank headers.
+ *
+ * The bank of ROM symbols is the ROM bank (0 for 0x0000-0x3FFF), for RAM symbols the RAM bank, which is not
+ * tracked here, so RAM symbols match in any bank.
+ */
+use std::collections::{BTreeMap, HashMap};
+use std::fs::read_to_string;
+
+use crate::conf::*;
+
+#[derive(Default)]
+pub struct Symbols {
+    // First label of each bank + address.
+    by_location: BTreeMap<(u8, u16), String>,
+    by_name: HashMap<String, Vec<(u8, u16)>>,
+    banks: Vec<u8>,
+}
+
+// Nearest preceding symbol is only used within this distance, eg. for fields of a RAM struct.
+const MAX_SYMBOL_OFFSET: u16 = 0x100;
+
+fn is_banked_rom(addr: u16) -> bool {
+    (MEM_AREA_ROM_BANK_N_START..=MEM_AREA_ROM_BANK_N_END).contains(&addr)
+}
+
+fn is_rom(addr: u16) -> bool {
+    addr <= MEM_AREA_ROM_BANK_N_END
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    pub fn load(filename: &str) -> Result<Symbols, Error> {
+        let raw = read_to_string(filename)?;
+        let symbols = if filename.ends_with(".map") {
+            Symbols::parse_map(&raw)?
+        } else {
+            Symbols::parse(&raw)?
+        };
+        log::info!("Symbols loaded: {} ({} labels)", filename, symbols.len());
+        Ok(symbols)
+    }
+
+    pub fn parse(raw: &str) -> Result<Symbols, Error> {
+        let mut symbols = Symbols::new();
+
+        for (line_i, line) in raw.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let invalid = || format!("Invalid symbol at line {}: {}", line_i + 1, line);
+            let (location, name) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let (bank, addr) = location.split_once(':').ok_or_else(invalid)?;
+            let bank = u8::from_str_radix(bank, 16).map_err(|_| invalid())?;
+            let addr = u16::from_str_radix(addr, 16).map_err(|_| invalid())?;
+
+            symbols.add(bank, addr, name.trim().to_string());
+        }
+
+        Ok(symbols)
+    }
+
+    /**
+     * `ROMX bank #2:` headers followed by `$4000 = Label` lines, section lines are skipped.
+     */
+    pub fn parse_map(raw: &str) -> Result<Symbols, Error> {
+        let mut symbols = Symbols::new();
+        let mut bank = 0u8;
+
+        for (line_i, line) in raw.lines().enumerate() {
+            let line = line.trim();
+            if let Some(header) = line.strip_suffix(':') {
+                if let Some((_, number)) = header.split_once(" bank #") {
+                    bank = number
+                        .parse::<u8>()
+                        .map_err(|_| format!("Invalid bank at line {}: {}", line_i + 1, line))?;
+                }
+            } else if let Some((addr, name)) = line
+                .strip_prefix('$')
+                .and_then(|symbol| symbol.split_once(" = "))
+            {
+                let addr = u16::from_str_radix(addr, 16)
+                    .map_err(|_| format!("Invalid symbol at line {}: {}", line_i + 1, line))?;
+                symbols.add(bank, addr, name.trim().to_string());
+            }
+        }
+
+        Ok(symbols)
+    }
+
+    fn add(&mut self, bank: u8, addr: u16, name: String) {
+        self.by_name
+            .entry(name.clone())
+            .or_default()
+            .push((bank, addr));
+
+        if !self.banks.contains(&bank) {
+            self.banks.push(bank);
+        }
+        self.by_location.entry((bank, addr)).or_insert(name);
+    }
+
+    fn len(&self) -> usize {
+        self.by_name.values().map(|locations| locations.len()).sum()
+    }
+
+    /**
+     * Banks to search for an address, with `rom_bank` being the bank mapped to 0x4000-0x7FFF.
+     */
+    fn banks_of(&self, addr: u16, rom_bank: u8) -> Vec<u8> {
+        if is_banked_rom(addr) {
+            vec![rom_bank]
+        } else if is_rom(addr) {
+            vec![0]
+        } else {
+            self.banks.clone()
+        }
+    }
+
+    /**
+     * Label exactly at the address.
+     */
+    pub fn label_at(&self, addr: u16, rom_bank: u8) -> Option<&str> {
+        self.banks_of(addr, rom_bank)
+            .into_iter()
+            .find_map(|bank| self.by_location.get(&(bank, addr)))
+            .map(|name| name.as_str())
+    }
+
+    /**
+     * `Label` or `Label+N` of the nearest preceding symbol in the same memory area.
+     */
+    pub fn describe(&self, addr: u16, rom_bank: u8) -> Option<String> {
+        let area_start = if is_banked_rom(addr) {
+            MEM_AREA_ROM_BANK_N_START
+        } else if is_rom(addr) {
+            0
+        } else {
+            MEM_AREA_ROM_BANK_N_END + 1
+        };
+        let from = addr.saturating_sub(MAX_SYMBOL_OFFSET - 1).max(area_start);
+
+        self.banks_of(addr, rom_bank)
+            .into_iter()
+            .filter_map(|bank| {
+                self.by_location
+                    .range((bank, from)..=(bank, addr))
+                    .next_back()
+            })
+            .max_by_key(|((_, a), _)| *a)
+            .map(|((_, a), name)| {
+                if *a == addr {
+                    name.clone()
+                } else {
+                    format!("{}+{}", name, addr - a)
+                }
+            })
+    }
+
+    /**
+     * Bank + address of a label. When defined in multiple banks, the one in the current ROM bank wins.
+     */
+    pub fn lookup(&self, name: &str, rom_bank: u8) -> Option<(u8, u16)> {
+        let locations = self.by_name.get(name)?;
+        locations
+            .iter()
+            .find(|(bank, addr)| is_banked_rom(*addr) && *bank == rom_bank)
+            .or_else(|| locations.first())
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::symbols::*;
+
+    const SYM: &str = "; File generated by rgblink\n\
+                       00:0150 Main\n\
+                       00:0158 Main.loop\n\
+                       01:4000 Banked\n\
+                       02:4000 OtherBanked\n\
+                       02:4100 Shared\n\
+                       03:4100 Shared\n\
+                       00:C000 wPlayer\n";
+
+    #[test]
+    fn test_labels_by_bank() {
+        let symbols = Symbols::parse(SYM).unwrap();
+
+        assert_eq!(7, symbols.len());
+        assert_eq!(Some("Main.loop"), symbols.label_at(0x0158, 5));
+        assert_eq!(Some("Banked"), symbols.label_at(0x4000, 1));
+        assert_eq!(Some("OtherBanked"), symbols.label_at(0x4000, 2));
+        assert_eq!(None, symbols.label_at(0x4000, 4));
+        assert_eq!(Some("Main+3".to_string()), symbols.describe(0x0153, 1));
+        assert_eq!(Some("wPlayer+2".to_string()), symbols.describe(0xC002, 1));
+        assert_eq!(None, symbols.describe(0x4002, 4));
+    }
+
+    #[test]
+    fn test_lookup_prefers_current_bank() {
+        let symbols = Symbols::parse(SYM).unwrap();
+
+        assert_eq!(Some((0, 0x0158)), symbols.lookup("Main.loop", 1));
+        assert_eq!(Some((3, 0x4100)), symbols.lookup("Shared", 3));
+        assert_eq!(Some((2, 0x4100)), symbols.lookup("Shared", 1));
+        assert_eq!(None, symbols.lookup("Missing", 1));
+        assert!(Symbols::parse("0150 Main").is_err());
+    }
+
+    #[test]
+    fn test_parse_map() {
+        let map = "ROM0 bank #0:\n                   \tSECTION: $0150-$0160 ($0011 bytes) [\"Main\"]\n                   \t         $0150 = Main\n                   ROMX bank #2:\n                   \tSECTION: $4000-$4010 ($0011 bytes) [\"Banked\"]\n                   \t         $4000 = OtherBanked\n";
+        let symbols = Symbols::parse_map(map).unwrap();
+
+        assert_eq!(Some((0, 0x0150)), symbols.lookup("Main", 1));
+        assert_eq!(Some("OtherBanked"), symbols.label_at(0x4000, 2));
+    }
+}
@@ -0,0 +1,152 @@
+/**
+ * Event timeline of a frame, positioned by scanline (LY 0-153) and dot (0-455), like bgb's event viewer:
+ * interrupts requested and serviced, STAT mode changes, LYC matches, OAM DMA, MBC bank switches and scroll /
+ * palette register writes.
+ *
+ * Events are collected from LY 0 to the end of VBlank, then published for the imgui panel.
+ */
+use std::sync::{Arc, RwLock};
+
+use crate::conf::*;
+use crate::vm::Interrupt;
+
+pub const TIMELINE_LINES: usize = 154;
+pub const TIMELINE_DOTS: usize = 456;
+
+// Collected with the LCD off, where no frame ends.
+const MAX_EVENTS: usize = 8192;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EventKind {
+    InterruptRequest(Interrupt),
+    InterruptService(Interrupt),
+    StatMode(u8),
+    LycMatch,
+    // Source page.
+    OamDma(u8),
+    RomBank(u8),
+    // Register + byte.
+    RegisterWrite(u16, u8),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TimelineEvent {
+    pub ly: u8,
+    pub dot: u16,
+    pub kind: EventKind,
+}
+
+/**
+ * Registers of raster effects.
+ */
+pub fn is_raster_register(loc: u16) -> bool {
+    matches!(
+        loc,
+        MEM_LOC_SCY
+            | MEM_LOC_SCX
+            | MEM_LOC_WY
+            | MEM_LOC_WX
+            | MEM_LOC_BGP
+            | MEM_LOC_OBP0
+            | MEM_LOC_OBP1
+    )
+}
+
+fn register_name(loc: u16) -> &'static str {
+    match loc {
+        MEM_LOC_SCY => "SCY",
+        MEM_LOC_SCX => "SCX",
+        MEM_LOC_WY => "WY",
+        MEM_LOC_WX => "WX",
+        MEM_LOC_BGP => "BGP",
+        MEM_LOC_OBP0 => "OBP0",
+        MEM_LOC_OBP1 => "OBP1",
+        _ => "?",
+    }
+}
+
+impl TimelineEvent {
+    pub fn describe(&self) -> String {
+        let what = match self.kind {
+            EventKind::InterruptRequest(interrupt) => format!("{:?} requested", interrupt),
+            EventKind::InterruptService(interrupt) => format!("{:?} serviced", interrupt),
+            EventKind::StatMode(mode) => format!("Mode {}", mode),
+            EventKind::LycMatch => "LY == LYC".to_string(),
+            EventKind::OamDma(page) => format!("OAM DMA from {:02X}00", page),
+            EventKind::RomBank(bank) => format!("ROM bank {:02X}", bank),
+            EventKind::RegisterWrite(loc, byte) => {
+                format!("{} = {:02X}", register_name(loc), byte)
+            }
+        };
+        format!("LY {:3} dot {:3}: {}", self.ly, self.dot, what)
+    }
+}
+
+pub struct Timeline {
+    // Only recorded while the panel is shown.
+    pub is_enabled: bool,
+    events: Vec<TimelineEvent>,
+    last_ly: u8,
+    published: Arc<RwLock<Vec<TimelineEvent>>>,
+}
+
+impl Timeline {
+    pub fn new(published: Arc<RwLock<Vec<TimelineEvent>>>) -> Timeline {
+        Timeline {
+            is_enabled: false,
+            events: vec![],
+            last_ly: 0,
+            published,
+        }
+    }
+
+    pub fn record(&mut self, ly: u8, dot: u16, kind: EventKind) {
+        if self.is_enabled && self.events.len() < MAX_EVENTS {
+            self.events.push(TimelineEvent { ly, dot, kind });
+        }
+    }
+
+    /**
+     * Called with the PPU position after each step, publishes the events when LY wraps to a new frame.
+     */
+    pub fn advance(&mut self, ly: u8) {
+        if ly < self.last_ly {
+            let events = std::mem::take(&mut self.events);
+            if self.is_enabled {
+                *self.published.write().unwrap() = events;
+            }
+        }
+        self.last_ly = ly;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::timeline::*;
+
+    #[test]
+    fn test_publish_on_frame_wrap() {
+        let published = Arc::new(RwLock::new(vec![]));
+        let mut timeline = Timeline::new(published.clone());
+
+        timeline.record(10, 0, EventKind::LycMatch);
+        timeline.is_enabled = true;
+        timeline.advance(144);
+        timeline.record(144, 4, EventKind::InterruptRequest(Interrupt::VBlank));
+        timeline.advance(153);
+        assert!(published.read().unwrap().is_empty());
+
+        timeline.advance(0);
+        timeline.record(0, 100, EventKind::RegisterWrite(MEM_LOC_SCX, 0x12));
+        let events = published.read().unwrap().clone();
+        assert_eq!(1, events.len());
+        assert_eq!("LY 144 dot   4: VBlank requested", events[0].describe());
+
+        timeline.advance(1);
+        timeline.advance(0);
+        assert_eq!(
+            "LY   0 dot 100: SCX = 12",
+            published.read().unwrap()[0].describe()
+        );
+    }
+}
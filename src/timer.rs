@@ -1,4 +1,5 @@
 use crate::conf::*;
+use crate::state::*;
 use crate::util::*;
 
 pub struct Timer {
@@ -89,6 +90,24 @@ impl Timer {
         self.tima = byte;
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.div);
+        w.write_u8(self.tac);
+        w.write_u8(self.tma);
+        w.write_u8(self.tima);
+        self.div_ticker.save_state(w);
+        self.tima_ticker.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.div = r.read_u8()?;
+        self.tac = r.read_u8()?;
+        self.tma = r.read_u8()?;
+        self.tima = r.read_u8()?;
+        self.div_ticker.load_state(r)?;
+        self.tima_ticker.load_state(r)
+    }
+
     pub fn dump_debug_panel(&self) {
         println!("\x1B[93mDIV\x1B[0m {:02X} | \x1B[93mTIMA\x1B[0m {:02X} ({:X}) | \x1B[93mTMA\x1B[0m {:02X} | \x1B[93mTAC\x1B[0m {:02X}", self.div, self.tima, self.tima_ticker.counter, self.tma, self.tac);
     }
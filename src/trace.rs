@@ -0,0 +1,336 @@
+/**
+ * Execution trace: one line per instruction, before it executes.
+ *
+ * - Doctor: Gameboy Doctor format, `A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02`.
+ *   Gameboy Doctor expects the boot ROM skipped and LY reading 0x90, see `--trace-ly-stub`.
+ * - Lameboy: registers with the opcode and LY.
+ *
+ * Lines can be limited to a PC range, a ROM bank and a frame window.
+ */
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+use crate::conf::*;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TraceFormat {
+    Doctor,
+    Lameboy,
+}
+
+impl TraceFormat {
+    pub fn parse(raw: &str) -> Result<TraceFormat, Error> {
+        match raw {
+            "doctor" => Ok(TraceFormat::Doctor),
+            "lameboy" => Ok(TraceFormat::Lameboy),
+            _ => Err(format!("Unknown trace format: {} (doctor, lameboy)", raw).into()),
+        }
+    }
+}
+
+/**
+ * CPU state before an instruction.
+ */
+pub struct TraceEntry {
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub sp: u16,
+    pub pc: u16,
+    pub pcmem: [u8; 4],
+    pub ly: u8,
+}
+
+impl TraceEntry {
+    pub fn format(&self, format: TraceFormat) -> String {
+        let [a, f] = self.af.to_be_bytes();
+        let [b, c] = self.bc.to_be_bytes();
+        let [d, e] = self.de.to_be_bytes();
+        let [h, l] = self.hl.to_be_bytes();
+
+        match format {
+            TraceFormat::Doctor => format!(
+                "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+                a, f, b, c, d, e, h, l, self.sp, self.pc,
+                self.pcmem[0], self.pcmem[1], self.pcmem[2], self.pcmem[3]
+            ),
+            TraceFormat::Lameboy => format!(
+                "PC={:04X} OP={:02X} LY={:02X} AF={:04X} BC={:04X} DE={:04X} HL={:04X} SP={:04X}",
+                self.pc, self.pcmem[0], self.ly, self.af, self.bc, self.de, self.hl, self.sp
+            ),
+        }
+    }
+}
+
+/**
+ * Inclusive limits, unset ones match everything.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct TraceFilter {
+    pub pc: Option<(u16, u16)>,
+    // Bank of the instruction: 0 for 0x0000-0x3FFF, the switched bank for 0x4000-0x7FFF. Code in RAM always matches.
+    pub rom_bank: Option<u8>,
+    pub frames: Option<(u64, u64)>,
+}
+
+/**
+ * `<from>-<to>` range, in hex or decimal.
+ */
+fn parse_range<T: Copy + PartialOrd>(
+    raw: &str,
+    parse: impl Fn(&str) -> Result<T, std::num::ParseIntError>,
+) -> Result<(T, T), Error> {
+    let (from, to) = raw
+        .split_once('-')
+        .ok_or_else(|| format!("Invalid range: {} (<from>-<to>)", raw))?;
+    let (from, to) = (parse(from)?, parse(to)?);
+    if to < from {
+        return Err(format!("Range ends before its start: {}", raw).into());
+    }
+    Ok((from, to))
+}
+
+impl TraceFilter {
+    pub fn parse_pc_range(raw: &str) -> Result<(u16, u16), Error> {
+        parse_range(raw, |addr| u16::from_str_radix(addr, 16))
+    }
+
+    pub fn parse_frame_window(raw: &str) -> Result<(u64, u64), Error> {
+        parse_range(raw, |frame| frame.parse::<u64>())
+    }
+
+    fn matches(&self, pc: u16, rom_bank: u8, frame: u64) -> bool {
+        let bank_at_pc = if pc <= MEM_AREA_ROM_BANK_0_END {
+            0
+        } else {
+            rom_bank
+        };
+
+        self.pc.is_none_or(|(from, to)| (from..=to).contains(&pc))
+            && (pc > MEM_AREA_ROM_BANK_N_END || self.rom_bank.is_none_or(|bank| bank == bank_at_pc))
+            && self
+                .frames
+                .is_none_or(|(from, to)| (from..=to).contains(&frame))
+    }
+}
+
+pub struct TraceLogger {
+    out: BufWriter<File>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    // CPU reads of LY return 0x90, as Gameboy Doctor logs assume.
+    stub_ly: bool,
+    frame: u64,
+}
+
+impl TraceLogger {
+    pub fn new(
+        path: &str,
+        format: TraceFormat,
+        filter: TraceFilter,
+        stub_ly: bool,
+    ) -> Result<TraceLogger, Error> {
+        Ok(TraceLogger {
+            out: BufWriter::new(File::create(path)?),
+            format,
+            filter,
+            stub_ly,
+            frame: 0,
+        })
+    }
+
+    pub fn stubs_ly(&self) -> bool {
+        self.stub_ly
+    }
+
+    /**
+     * Whether the instruction at `pc` is logged, to skip building its entry otherwise.
+     */
+    pub fn is_enabled(&self, pc: u16, rom_bank: u8) -> bool {
+        self.filter.matches(pc, rom_bank, self.frame)
+    }
+
+    pub fn log(&mut self, entry: &TraceEntry) -> Result<(), Error> {
+        writeln!(self.out, "{}", entry.format(self.format))?;
+        Ok(())
+    }
+
+    pub fn next_frame(&mut self) -> Result<(), Error> {
+        self.frame += 1;
+        if self
+            .filter
+            .frames
+            .is_some_and(|(_, to)| self.frame == to + 1)
+        {
+            self.out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TraceLogger {
+    fn drop(&mut self) {
+        if let Err(err) = self.out.flush() {
+            log::error!("Cannot flush trace: {}", err);
+        }
+    }
+}
+
+/**
+ * First line where a trace and a reference trace differ.
+ */
+#[derive(PartialEq, Debug)]
+pub struct Divergence {
+    // 1 based.
+    pub line: usize,
+    pub trace: Option<String>,
+    pub reference: Option<String>,
+}
+
+impl Divergence {
+    /**
+     * Fields that differ, as `name: trace != reference`, for lines made of `name:value` / `name=value` fields.
+     */
+    pub fn differing_fields(&self) -> Vec<String> {
+        let (Some(trace), Some(reference)) = (&self.trace, &self.reference) else {
+            return vec![];
+        };
+        let field = |part: &str| {
+            part.split_once([':', '='])
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+        };
+
+        trace
+            .split_whitespace()
+            .zip(reference.split_whitespace())
+            .filter_map(|(ours, theirs)| match (field(ours), field(theirs)) {
+                (Some((name, ours)), Some((_, theirs))) if ours != theirs => {
+                    Some(format!("{}: {} != {}", name, ours, theirs))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+pub fn diff(trace: impl BufRead, reference: impl BufRead) -> Result<Option<Divergence>, Error> {
+    let mut trace = trace.lines();
+    let mut reference = reference.lines();
+
+    for line in 1.. {
+        let (ours, theirs) = (trace.next().transpose()?, reference.next().transpose()?);
+        if ours.is_none() && theirs.is_none() {
+            break;
+        }
+        if ours.as_deref().map(str::trim_end) != theirs.as_deref().map(str::trim_end) {
+            return Ok(Some(Divergence {
+                line,
+                trace: ours,
+                reference: theirs,
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+/**
+ * Prints the first divergence of two trace files, returns whether they match.
+ */
+pub fn diff_files(trace_path: &str, reference_path: &str) -> Result<bool, Error> {
+    let divergence = diff(
+        BufReader::new(File::open(trace_path)?),
+        BufReader::new(File::open(reference_path)?),
+    )?;
+
+    let Some(divergence) = divergence else {
+        println!("Traces match");
+        return Ok(true);
+    };
+
+    println!("First divergence at line {}:", divergence.line);
+    println!(
+        "  trace:     {}",
+        divergence.trace.as_deref().unwrap_or("<end of trace>")
+    );
+    println!(
+        "  reference: {}",
+        divergence.reference.as_deref().unwrap_or("<end of trace>")
+    );
+    for field in divergence.differing_fields() {
+        println!("  {}", field);
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::trace::*;
+
+    #[test]
+    fn test_doctor_format() {
+        let entry = TraceEntry {
+            af: 0x01B0,
+            bc: 0x0013,
+            de: 0x00D8,
+            hl: 0x014D,
+            sp: 0xFFFE,
+            pc: 0x0100,
+            pcmem: [0x00, 0xC3, 0x13, 0x02],
+            ly: 0x90,
+        };
+        assert_eq!(
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02",
+            entry.format(TraceFormat::Doctor)
+        );
+        assert_eq!(
+            "PC=0100 OP=00 LY=90 AF=01B0 BC=0013 DE=00D8 HL=014D SP=FFFE",
+            entry.format(TraceFormat::Lameboy)
+        );
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = TraceFilter {
+            pc: Some(TraceFilter::parse_pc_range("0150-7FFF").unwrap()),
+            rom_bank: Some(2),
+            frames: Some(TraceFilter::parse_frame_window("10-20").unwrap()),
+        };
+
+        assert!(filter.matches(0x4000, 2, 10));
+        assert!(!filter.matches(0x4000, 3, 10));
+        assert!(!filter.matches(0x0150, 2, 10));
+        assert!(!filter.matches(0x4000, 2, 21));
+        assert!(!filter.matches(0x0100, 2, 10));
+        assert!(TraceFilter::parse_pc_range("0200-0100").is_err());
+    }
+
+    #[test]
+    fn test_diff_reports_first_divergence() {
+        let reference = "A:01 F:B0 PC:0100\nA:01 F:B0 PC:0101\nA:02 F:00 PC:0104\n";
+        let trace = "A:01 F:B0 PC:0100\nA:01 F:B0 PC:0101\nA:02 F:80 PC:0104\nA:00 F:00 PC:0105\n";
+
+        let divergence = diff(trace.as_bytes(), reference.as_bytes())
+            .unwrap()
+            .unwrap();
+        assert_eq!(3, divergence.line);
+        assert_eq!(vec!["F: 80 != 00"], divergence.differing_fields());
+
+        let divergence = diff(reference.as_bytes(), reference.as_bytes()).unwrap();
+        assert_eq!(None, divergence);
+
+        let divergence = diff(
+            trace.as_bytes(),
+            trace
+                .lines()
+                .take(3)
+                .collect::<Vec<_>>()
+                .join("\n")
+                .as_bytes(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!((4, None), (divergence.line, divergence.reference));
+    }
+}
@@ -1,6 +1,8 @@
 use std::collections::VecDeque;
 
-use crate::conf::PALETTE;
+use crate::conf::{Error, PALETTE};
+use crate::error::EmulatorError;
+use crate::state::{StateReader, StateWriter};
 
 pub fn is_carry_add_u8(acc: u8, add: u8) -> bool {
     (u8::MAX - acc) < add
@@ -117,6 +119,21 @@ pub fn pixel_rgb8888_color(gb_color: u8) -> [u8; 4] {
     PALETTE[gb_color as usize]
 }
 
+/**
+ * CRC-32 (IEEE), used to identify ROMs.
+ */
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
 pub struct SizedQueue<T> {
     capacity: usize,
     deque: VecDeque<T>,
@@ -173,11 +190,12 @@ impl Stats {
     }
 
     pub fn avg(&self) -> i32 {
-        self.queue.deque.iter().sum::<i32>() / self.queue.deque.len() as i32
+        self.queue.deque.iter().sum::<i32>() / self.queue.deque.len().max(1) as i32
     }
 
     pub fn p90(&mut self) -> i32 {
-        let list = self.queue.deque.as_mut_slices().0;
+        // Sorted copy, the queue keeps its order to drop the oldest values.
+        let mut list = self.queue.deque.iter().copied().collect::<Vec<_>>();
         if list.len() == 0 {
             return 0;
         }
@@ -235,12 +253,58 @@ impl Counter {
     pub fn reset(&mut self) {
         self.counter = 0;
     }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32(self.counter);
+        w.write_u32(self.modulo);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        let counter = r.read_u32()?;
+        let modulo = r.read_u32()?;
+        if counter >= modulo {
+            return Err(EmulatorError::BadState(format!(
+                "Invalid counter in state: {} (modulo: {})",
+                counter, modulo
+            )));
+        }
+        self.counter = counter;
+        self.modulo = modulo;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::util::*;
 
+    #[test]
+    fn test_counter_state() {
+        let mut w = StateWriter::new();
+        w.write_u32(3);
+        w.write_u32(4);
+        w.write_u32(0);
+        w.write_u32(0);
+        w.write_u32(4);
+        w.write_u32(4);
+        let state = w.into_inner();
+
+        let mut counter = Counter::new(1);
+        let mut r = StateReader::new(&state);
+        counter.load_state(&mut r).unwrap();
+        assert_eq!((3, 4), (counter.counter, counter.modulo));
+        // A zero modulo, then a counter past its modulo.
+        assert!(matches!(
+            counter.load_state(&mut r),
+            Err(EmulatorError::BadState(_))
+        ));
+        assert!(matches!(
+            counter.load_state(&mut r),
+            Err(EmulatorError::BadState(_))
+        ));
+        assert_eq!((3, 4), (counter.counter, counter.modulo));
+    }
+
     #[test]
     fn test_is_carry_rot_left_u8() {
         assert!(is_carry_rot_left_u8(0b1100_0000));
@@ -254,6 +318,11 @@ mod tests {
         assert_eq!(0b0110_1001, swap(0b1001_0110));
     }
 
+    #[test]
+    fn test_crc32() {
+        assert_eq!(0xCBF4_3926, crc32(b"123456789"));
+    }
+
     #[test]
     fn test_is_bit() {
         assert!(is_bit(0b0000_1000, 3));
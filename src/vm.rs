@@ -11,15 +11,32 @@ use std::time::Duration;
 use std::time::Instant;
 
 use crate::apu::*;
+use crate::call_stack::{CallFrame, CallStack};
 use crate::cartridge::*;
+use crate::cdl::*;
 use crate::conf::*;
 use crate::cpu::*;
+use crate::dap::{DapServer, DapTarget};
 use crate::debugger::*;
-use crate::joypad::Joypad;
+use crate::disasm::{decode, DisassemblyView, Instruction};
+use crate::dma::OamDma;
+use crate::error::EmulatorError;
+use crate::expr::{ExprContext, NoExprContext, Variable};
+use crate::gamepad::Gamepad;
+use crate::gdb::{GdbStub, GdbTarget, REGISTER_COUNT};
+use crate::input_macro::InputSequencer;
+use crate::joypad::{Joypad, JoypadInputRequest};
 use crate::mmu::*;
+use crate::movie::Movie;
 use crate::ppu::*;
+use crate::profiler::Profiler;
+use crate::rewind::Rewind;
 use crate::serial::Serial;
+use crate::speed::SpeedControl;
+use crate::state::*;
+use crate::timeline::{is_raster_register, EventKind, Timeline, TimelineEvent};
 use crate::timer::*;
+use crate::trace::{TraceEntry, TraceLogger};
 use crate::util::*;
 
 enum DelayedOp {
@@ -45,17 +62,66 @@ impl DelayedCommand {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Copy)]
 enum State {
     Running,
     // Power down CPU until an interrupt occurs. Use this when ever possible to reduce energy consumption.
     Halt,
     // Halt CPU & LCD display until button pressed.
     Stop,
+    // Hung by an illegal opcode until reset or a state load. The PPU, APU and timer keep running.
+    Locked,
 }
 
-#[derive(Debug)]
-enum Interrupt {
+/**
+ * Illegal opcode or bus fault that locked the CPU, shown by the UI.
+ */
+#[derive(Clone, PartialEq, Debug)]
+pub struct CpuLock {
+    pub pc: u16,
+    pub reason: String,
+}
+
+/**
+ * State the VM publishes for the UI thread, created in main.rs.
+ */
+#[derive(Clone, Default)]
+pub struct DebugViews {
+    pub debug_log: Arc<RwLock<Vec<String>>>,
+    pub disassembly: Arc<RwLock<DisassemblyView>>,
+    pub memory: Arc<RwLock<MemoryView>>,
+    pub timeline: Arc<RwLock<Vec<TimelineEvent>>>,
+    pub cpu_lock: Arc<RwLock<Option<CpuLock>>>,
+    // Set by the UI while the debug panel is shown.
+    pub should_generate_debug_log: Arc<AtomicBool>,
+}
+
+/**
+ * Debugger and analysis tools attached to the VM.
+ */
+pub struct DebugTools {
+    pub debugger: Debugger,
+    pub trace: Option<TraceLogger>,
+    pub cdl: Option<CodeDataLog>,
+    pub profiler: Option<Profiler>,
+    // Replace the stdin REPL when set.
+    pub gdb: Option<GdbStub>,
+    pub dap: Option<DapServer>,
+}
+
+/**
+ * Machine options and input sources.
+ */
+pub struct VmOptions {
+    pub disable_sound: bool,
+    pub rewind: Rewind,
+    pub movie: Option<Movie>,
+    pub gamepad: Option<Gamepad>,
+    pub input_sequencer: InputSequencer,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Interrupt {
     VBlank,
     LCD,
     Timer,
@@ -64,6 +130,17 @@ enum Interrupt {
 }
 
 impl Interrupt {
+    pub fn parse(name: &str) -> Option<Interrupt> {
+        match name.to_lowercase().as_str() {
+            "vblank" => Some(Interrupt::VBlank),
+            "lcd" => Some(Interrupt::LCD),
+            "timer" => Some(Interrupt::Timer),
+            "serial" => Some(Interrupt::Serial),
+            "joypad" => Some(Interrupt::Joypad),
+            _ => None,
+        }
+    }
+
     fn addr(&self) -> u16 {
         match self {
             Interrupt::VBlank => 0x40,
@@ -99,30 +176,57 @@ pub struct VM {
     interrupt_master_enable_flag: bool,
     interrupt_enable: u8,
     interrupt_flag: u8,
+    oam_dma: OamDma,
     video: Arc<RwLock<PPU>>,
     op_history: SizedQueue<(u16, u8)>,           // pc + op
     deep_op_history: SizedQueue<(u64, u16, u8)>, // counter + pc + op
     delayed_cmds: Vec<DelayedCommand>,
-    opcode_dump_file: Option<File>,
+    trace: Option<TraceLogger>,
+    cdl: Option<CodeDataLog>,
+    profiler: Option<Profiler>,
     vm_debug_log: Arc<RwLock<Vec<String>>>,
+    vm_disassembly: Arc<RwLock<DisassemblyView>>,
+    vm_memory: Arc<RwLock<MemoryView>>,
+    should_generate_vm_debug_log: Arc<AtomicBool>,
+    timeline: Timeline,
+    cpu_lock: Arc<RwLock<Option<CpuLock>>>,
+    call_stack: CallStack,
+    // Replace the stdin REPL when set.
+    gdb: Option<GdbStub>,
+    dap: Option<DapServer>,
+    // Fallback frame boundary when the LCD is off and there is no VBlank.
+    frame_ticker: Counter,
+    rewind: Rewind,
+    movie: Option<Movie>,
+    gamepad: Option<Gamepad>,
+    input_sequencer: InputSequencer,
 }
 
 impl VM {
     pub fn new(
         global_exit_flag: Arc<AtomicBool>,
         cartridge: Cartridge,
-        debugger: Debugger,
         video: Arc<RwLock<PPU>>,
-        is_opcode_file_dump: bool,
         joypad: Joypad,
-        disable_sound: bool,
-        vm_debug_log: Arc<RwLock<Vec<String>>>,
+        options: VmOptions,
+        tools: DebugTools,
+        views: DebugViews,
     ) -> Result<Self, Error> {
-        let opcode_dump_file = if is_opcode_file_dump {
-            Some(File::create("/tmp/lameboy_dump.txt").unwrap())
-        } else {
-            None
-        };
+        let DebugTools {
+            debugger,
+            trace,
+            cdl,
+            profiler,
+            gdb,
+            dap,
+        } = tools;
+        let VmOptions {
+            disable_sound,
+            rewind,
+            movie,
+            gamepad,
+            input_sequencer,
+        } = options;
 
         Ok(VM {
             global_exit_flag,
@@ -139,12 +243,28 @@ impl VM {
             interrupt_enable: 0,
             // Top 3 bits are unused - BGB reads them as 0b111x_xxxx.
             interrupt_flag: 0xE0,
+            oam_dma: OamDma::new(),
             video,
             op_history: SizedQueue::new(128),
             deep_op_history: SizedQueue::new(128),
             delayed_cmds: vec![],
-            opcode_dump_file,
-            vm_debug_log,
+            trace,
+            cdl,
+            profiler,
+            vm_debug_log: views.debug_log,
+            vm_disassembly: views.disassembly,
+            vm_memory: views.memory,
+            should_generate_vm_debug_log: views.should_generate_debug_log,
+            timeline: Timeline::new(views.timeline),
+            cpu_lock: views.cpu_lock,
+            call_stack: CallStack::new(),
+            gdb,
+            dap,
+            frame_ticker: Counter::new(CLOCKS_PER_FRAME),
+            rewind,
+            movie,
+            gamepad,
+            input_sequencer,
         })
     }
 
@@ -235,21 +355,31 @@ impl VM {
 
     pub fn run(
         &mut self,
-        should_generate_vm_debug_log: Arc<AtomicBool>,
         ignore_speed_limit: bool,
+        speed_control: Arc<SpeedControl>,
     ) -> Result<(), Error> {
         log::info!("VM eval loop start");
 
         let mut vm_timer = Instant::now();
         let mut vm_measured_clocks = 0u128;
+        let mut vm_speed_multiplier = speed_control.multiplier();
+
+        self.start_movie()?;
 
         loop {
-            if should_generate_vm_debug_log.load(std::sync::atomic::Ordering::Relaxed) {
+            self.timeline.is_enabled = self
+                .should_generate_vm_debug_log
+                .load(std::sync::atomic::Ordering::Relaxed);
+            if self.timeline.is_enabled {
                 self.update_vm_debug_log();
+                self.update_disassembly_view();
+                self.update_memory_view();
             }
 
-            if self.debugger.should_stop(self.cpu.pc) {
-                self.print_debug_panel();
+            if self.debugger_should_stop() {
+                if self.gdb.is_none() && self.dap.is_none() {
+                    self.print_debug_panel();
+                }
                 loop {
                     match self.read_repl()? {
                         Some(DebugCmd::Quit) => return Ok(()),
@@ -266,6 +396,57 @@ impl VM {
                         }
                         Some(DebugCmd::PrintOpHistory) => self.dump_op_history(),
                         Some(DebugCmd::PrintOam) => self.debug_oam(),
+                        Some(DebugCmd::Disassemble(from, count)) => {
+                            self.print_disassembly(from.unwrap_or(self.cpu.pc), count);
+                        }
+                        Some(DebugCmd::PrintProfile) => match self.profiler.as_mut() {
+                            Some(profiler) => {
+                                for line in profiler.report(self.debugger.symbols()) {
+                                    println!("{}", line);
+                                }
+                            }
+                            None => println!("Profiler not enabled (--profile)"),
+                        },
+                        Some(DebugCmd::PrintCallStack) => {
+                            for line in self.call_stack_lines() {
+                                println!("{}", line);
+                            }
+                        }
+                        Some(DebugCmd::StepOver) => {
+                            self.debugger
+                                .set_run_until(RunUntil::CallDepth(self.call_stack.depth()));
+                            break;
+                        }
+                        Some(DebugCmd::StepOut) => {
+                            if let Some(frame) = self.call_stack.top() {
+                                println!(
+                                    "Step out of {:04X}{} to {:04X}{}",
+                                    frame.entry,
+                                    self.symbol_suffix(frame.entry),
+                                    frame.return_addr,
+                                    self.symbol_suffix(frame.return_addr)
+                                );
+                                self.debugger.set_run_until(RunUntil::CallDepth(
+                                    self.call_stack.depth() - 1,
+                                ));
+                                break;
+                            }
+                            println!("No call to step out of");
+                        }
+                        Some(DebugCmd::RunTo(bank, addr)) => {
+                            self.debugger.set_run_until(RunUntil::Address(bank, addr));
+                            break;
+                        }
+                        Some(DebugCmd::SetVariable(variable, value)) => {
+                            self.set_variable(variable, value);
+                            self.print_debug_panel();
+                        }
+                        Some(DebugCmd::WriteMemory(from, bytes, raw)) => {
+                            match self.write_memory_range(from, &bytes, raw) {
+                                Ok(()) => self.print_debug_memory(from, bytes.len().min(64)),
+                                Err(err) => println!("Write failed: {}", err),
+                            }
+                        }
                         None => (),
                     };
                 }
@@ -274,86 +455,374 @@ impl VM {
                 vm_measured_clocks = 0;
             }
 
-            let interrupt_mcycles = if self.check_interrupt() { 4 } else { 0 };
+            let (diff_cpu_clocks, is_frame_end) = self.step()?;
+            if is_frame_end {
+                self.on_frame_end(&speed_control)?;
 
-            let pre_exec_tma = self.mem_read(MEM_LOC_TMA)?;
+                if self.wait_while_paused(&speed_control) {
+                    vm_timer = Instant::now();
+                    vm_measured_clocks = 0;
+                }
+            }
 
-            let cpu_mcycles = if self.state == State::Running {
-                self.exec_op()?
-            } else {
-                1
-            };
+            if self
+                .global_exit_flag
+                .load(std::sync::atomic::Ordering::Acquire)
+            {
+                break;
+            }
 
-            let mut delayed_cmds_to_delete = vec![];
-            for (i, delayed_cmd) in self.delayed_cmds.iter_mut().enumerate() {
-                delayed_cmd.dec();
-                if delayed_cmd.is_ready() {
-                    delayed_cmds_to_delete.push(i);
+            let speed_multiplier = speed_control.multiplier();
+            if speed_multiplier != vm_speed_multiplier {
+                vm_speed_multiplier = speed_multiplier;
+                vm_timer = Instant::now();
+                vm_measured_clocks = 0;
+            }
 
-                    match delayed_cmd.op {
-                        DelayedOp::MasterInterruptEnable => {
-                            self.interrupt_master_enable_flag = true;
-                        }
-                    };
+            if let (false, Some(speed_multiplier)) = (ignore_speed_limit, speed_multiplier) {
+                vm_measured_clocks += diff_cpu_clocks as u128;
+                let expected_elapsed_nanos =
+                    (CPU_CLOCK_NANOS * vm_measured_clocks as f64 / speed_multiplier) as u128;
+                let actual_elapsed_nanos = vm_timer.elapsed().as_nanos();
+
+                if actual_elapsed_nanos < expected_elapsed_nanos {
+                    sleep(Duration::from_nanos(
+                        (expected_elapsed_nanos - actual_elapsed_nanos) as u64,
+                    ));
                 }
             }
-            for i in delayed_cmds_to_delete.iter().rev() {
-                self.delayed_cmds.remove(*i);
+        }
+
+        self.dump_op_history();
+
+        if let Some(movie) = self.movie.as_ref() {
+            movie.save()?;
+        }
+        if let Some(cdl) = self.cdl.as_ref() {
+            cdl.save()?;
+        }
+        if let Some(mut profiler) = self.profiler.take() {
+            for line in profiler.report(self.debugger.symbols()) {
+                println!("{}", line);
             }
+            profiler.save(self.debugger.symbols())?;
+        }
 
-            let diff_cpu_clocks: u32 = (interrupt_mcycles + cpu_mcycles as u32) * CYCLE_PER_MCYCLE;
+        Ok(())
+    }
 
-            self.sound.update(diff_cpu_clocks);
+    /**
+     * One instruction (or one M-cycle while halted, stopped or locked) with the interrupt it may serve, and the
+     * hardware ticked along. Returns the CPU clocks spent and whether a frame ended.
+     */
+    fn step(&mut self) -> Result<(u32, bool), Error> {
+        let interrupt_mcycles = if self.check_interrupt() { 4 } else { 0 };
+
+        let pre_exec_tma = self.mem_peek(MEM_LOC_TMA)?;
+
+        // Before the instruction, a CALL is spent in its caller.
+        let profile_step = self.profiler.as_mut().map(|profiler| {
+            let node = profiler.node(&self.call_stack);
+            (node, self.cpu.pc, self.state != State::Running)
+        });
+
+        // Breaking on the interrupt stops on the first instruction of its handler.
+        let is_interrupt_break = interrupt_mcycles > 0 && self.debugger.has_event_hit();
+
+        let cpu_mcycles = if is_interrupt_break {
+            0
+        } else if self.state == State::Running {
+            let op_pc = self.cpu.pc;
+            match self.exec_op() {
+                Ok(mcycles) => mcycles,
+                Err(err) if err.is_fault() => {
+                    self.lock(op_pc, err.to_string());
+                    1
+                }
+                Err(err) => return Err(err),
+            }
+        } else {
+            1
+        };
 
-            let should_call_times_interrupt =
-                self.timer.handle_ticks(diff_cpu_clocks, pre_exec_tma)?;
-            if should_call_times_interrupt {
-                self.interrupt_flag |= 0b0100;
+        if let Some((node, pc, is_halted)) = profile_step {
+            let rom_bank = self.current_rom_bank();
+            let mcycles = (interrupt_mcycles + cpu_mcycles as u32) as u64;
+            if let Some(profiler) = self.profiler.as_mut() {
+                profiler.add(node, pc, rom_bank, mcycles, is_halted);
             }
+        }
 
-            if self.state != State::Stop {
-                let video_interrupt_mask = self.video.write().unwrap().update(diff_cpu_clocks);
-                if video_interrupt_mask & VIDEO_RESULT_MASK_STAT_INTERRUPT > 0 {
-                    self.interrupt_flag |= 0b10;
-                }
-                if video_interrupt_mask & VIDEO_RESULT_MASK_VBLANK_INTERRUPT > 0 {
-                    self.interrupt_flag |= 0b1;
-                }
+        let mut delayed_cmds_to_delete = vec![];
+        for (i, delayed_cmd) in self.delayed_cmds.iter_mut().enumerate() {
+            delayed_cmd.dec();
+            if delayed_cmd.is_ready() {
+                delayed_cmds_to_delete.push(i);
+
+                match delayed_cmd.op {
+                    DelayedOp::MasterInterruptEnable => {
+                        self.interrupt_master_enable_flag = true;
+                    }
+                };
             }
+        }
+        for i in delayed_cmds_to_delete.iter().rev() {
+            self.delayed_cmds.remove(*i);
+        }
+
+        let diff_cpu_clocks: u32 = (interrupt_mcycles + cpu_mcycles as u32) * CYCLE_PER_MCYCLE;
+
+        self.sound.update(diff_cpu_clocks);
+        self.step_oam_dma(interrupt_mcycles + cpu_mcycles as u32);
+
+        let should_call_times_interrupt = self.timer.handle_ticks(diff_cpu_clocks, pre_exec_tma)?;
+        if should_call_times_interrupt {
+            self.request_interrupt(Interrupt::Timer);
+        }
+
+        let mut is_frame_end = false;
+        if self.state != State::Stop {
+            let mut video = self.video.write().unwrap();
+            let prev_stat = video.stat;
+            let video_interrupt_mask = video.update(diff_cpu_clocks);
+            let (stat, ly, dot) = (video.stat, video.ly, video.dot());
+            drop(video);
+            self.record_ppu_events(prev_stat, stat, ly, dot);
+
+            if video_interrupt_mask & VIDEO_RESULT_MASK_STAT_INTERRUPT > 0 {
+                self.request_interrupt(Interrupt::LCD);
+            }
+            if video_interrupt_mask & VIDEO_RESULT_MASK_VBLANK_INTERRUPT > 0 {
+                self.request_interrupt(Interrupt::VBlank);
+                is_frame_end = true;
+            }
+        }
 
-            if self.joypad.consume_interrupt() {
-                self.interrupt_flag |= 0b1_0000;
+        if self.joypad.consume_interrupt() {
+            self.request_interrupt(Interrupt::Joypad);
+
+            // A button press ends STOP mode regardless of IE and IME. HALT is woken up by `check_interrupt`.
+            if self.state == State::Stop {
+                self.state = State::Running;
             }
+        }
+
+        self.counter += 1;
+
+        if is_frame_end {
+            self.frame_ticker.reset();
+        } else {
+            is_frame_end = self.frame_ticker.tick_and_check_overflow(diff_cpu_clocks);
+        }
 
-            self.counter += 1;
+        Ok((diff_cpu_clocks, is_frame_end))
+    }
+
+    fn on_frame_end(&mut self, speed_control: &SpeedControl) -> Result<(), Error> {
+        // Rewinding would desync the recorded inputs.
+        let is_rewinding = self.movie.is_none() && self.rewind.is_rewinding();
+        self.sound
+            .set_muted(is_rewinding || !speed_control.is_natural_speed());
+
+        if is_rewinding {
+            if let Some(snapshot) = self.rewind.pop() {
+                self.load_state(&snapshot)?;
+            }
+        } else if self.rewind.should_capture() {
+            let snapshot = self.save_state();
+            self.rewind.push(snapshot);
+        }
 
+        if self
+            .movie
+            .as_ref()
+            .is_some_and(|movie| movie.is_anchor_requested())
+        {
+            let start_state = self.save_state();
+            if let Some(movie) = self.movie.as_mut() {
+                movie.restart_recording(start_state);
+            }
+        }
+
+        self.latch_input();
+
+        if let Some(trace) = self.trace.as_mut() {
+            trace.next_frame()?;
+        }
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.end_frame();
+        }
+
+        if let Some(mut dap) = self.dap.take() {
+            let result = dap.poll(self);
+            self.dap = Some(dap);
+            result?;
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Input only changes at frame boundaries: either live from the UI or from the movie being played.
+     */
+    fn latch_input(&mut self) {
+        let mut live = self.joypad.live_input();
+        let mut pad_turbo = JoypadInputRequest::new();
+        if let Some(gamepad) = self.gamepad.as_mut() {
+            let (pad, turbo) = gamepad.poll();
+            live = live.merge(pad);
+            pad_turbo = turbo;
+        }
+        let live = self.input_sequencer.next_input(live, pad_turbo);
+        let input = match self.movie.as_mut() {
+            Some(movie) => movie.next_input(live),
+            None => live,
+        };
+        self.joypad.set_input(input);
+    }
+
+    /**
+     * Loads the movie anchor state (if any) and latches the first frame of input.
+     */
+    fn start_movie(&mut self) -> Result<(), Error> {
+        let start_state = match self.movie.as_ref() {
+            Some(movie) => movie.start_state().cloned(),
+            None => return Ok(()),
+        };
+
+        if let Some(start_state) = start_state {
+            self.load_state(&start_state)?;
+        }
+
+        self.latch_input();
+
+        Ok(())
+    }
+
+    /**
+     * Blocks at a frame boundary while paused, unless a single frame advance was requested.
+     * Returns whether it had to wait (so the speed limiter can restart its measurement).
+     */
+    fn wait_while_paused(&mut self, speed_control: &SpeedControl) -> bool {
+        if !speed_control.is_paused() || speed_control.consume_frame_advance() {
+            return false;
+        }
+
+        self.sound.set_muted(true);
+        while speed_control.is_paused() && !speed_control.consume_frame_advance() {
             if self
                 .global_exit_flag
                 .load(std::sync::atomic::Ordering::Acquire)
             {
                 break;
             }
+            sleep(Duration::from_millis(5));
+        }
 
-            if !ignore_speed_limit {
-                vm_measured_clocks += diff_cpu_clocks as u128;
-                let expected_elapsed_nanos = (CPU_CLOCK_NANOS * vm_measured_clocks as f64) as u128;
-                let actual_elapsed_nanos = vm_timer.elapsed().as_nanos();
+        true
+    }
 
-                if actual_elapsed_nanos < expected_elapsed_nanos {
-                    sleep(Duration::from_nanos(
-                        (expected_elapsed_nanos - actual_elapsed_nanos) as u64,
-                    ));
-                }
+    pub fn save_state(&mut self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+
+        self.cpu.save_state(&mut w);
+        w.write_u64(self.counter);
+        w.write_u8(match self.state {
+            State::Running => 0,
+            State::Halt => 1,
+            State::Stop => 2,
+            State::Locked => 3,
+        });
+        match self.cpu_lock.read().unwrap().as_ref() {
+            Some(lock) => {
+                w.write_bool(true);
+                w.write_u16(lock.pc);
+                w.write_bytes(lock.reason.as_bytes());
+            }
+            None => w.write_bool(false),
+        }
+        w.write_bool(self.interrupt_master_enable_flag);
+        w.write_u8(self.interrupt_enable);
+        w.write_u8(self.interrupt_flag);
+        self.oam_dma.save_state(&mut w);
+        w.write_u32(self.delayed_cmds.len() as u32);
+        for delayed_cmd in &self.delayed_cmds {
+            w.write_u32(delayed_cmd.cycle_delay as u32);
+        }
+        self.frame_ticker.save_state(&mut w);
+
+        self.mem.save_state(&mut w);
+        self.timer.save_state(&mut w);
+        self.serial.save_state(&mut w);
+        self.joypad.save_state(&mut w);
+        self.sound.save_state(&mut w);
+        self.video.read().unwrap().save_state(&mut w);
+
+        w.into_inner()
+    }
+
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), Error> {
+        let mut r = StateReader::new(state);
+
+        self.cpu.load_state(&mut r)?;
+        self.counter = r.read_u64()?;
+        self.state = match r.read_u8()? {
+            0 => State::Running,
+            1 => State::Halt,
+            2 => State::Stop,
+            3 => State::Locked,
+            code => {
+                return Err(EmulatorError::BadState(format!(
+                    "Invalid CPU state in machine state: {}",
+                    code
+                )))
             }
+        };
+        let cpu_lock = if r.read_bool()? {
+            let pc = r.read_u16()?;
+            let reason = String::from_utf8(r.read_bytes()?)
+                .map_err(|_| EmulatorError::BadState("Invalid CPU lock reason".to_string()))?;
+            Some(CpuLock { pc, reason })
+        } else {
+            None
+        };
+        self.interrupt_master_enable_flag = r.read_bool()?;
+        self.interrupt_enable = r.read_u8()?;
+        self.interrupt_flag = r.read_u8()?;
+        self.oam_dma.load_state(&mut r)?;
+        let delayed_cmds_len = r.read_u32()?;
+        self.delayed_cmds.clear();
+        for _ in 0..delayed_cmds_len {
+            let cycle_delay = r.read_u32()? as usize;
+            self.delayed_cmds.push(DelayedCommand::new(
+                cycle_delay,
+                DelayedOp::MasterInterruptEnable,
+            ));
         }
+        self.frame_ticker.load_state(&mut r)?;
 
-        self.dump_op_history();
+        self.mem.load_state(&mut r)?;
+        self.timer.load_state(&mut r)?;
+        self.serial.load_state(&mut r)?;
+        self.joypad.load_state(&mut r)?;
+        self.sound.load_state(&mut r)?;
+        self.video.write().unwrap().load_state(&mut r)?;
+
+        if !r.is_finished() {
+            return Err("Machine state has trailing bytes".into());
+        }
+
+        // Not part of the machine state, calls made before the snapshot are lost.
+        self.call_stack.clear();
+
+        *self.cpu_lock.write().unwrap() = cpu_lock;
 
         Ok(())
     }
 
     fn reset(&mut self) -> Result<(), Error> {
         self.mem.reset()?;
+        self.call_stack.clear();
         self.video.write().unwrap().reset();
 
         // Byte 7/6/5: Unused.
@@ -367,7 +836,8 @@ impl VM {
 
     fn exec_op(&mut self) -> Result<u8, Error> {
         let mut is_alternative_mcycle = false;
-        let op = self.read_op()?;
+        let (op_pc, sp) = (self.cpu.pc, self.cpu.sp);
+        let op = self.fetch_op()?;
         let mut iteration_mcycle = 0u8;
 
         self.op_history.push((self.cpu.pc - 1, op));
@@ -376,22 +846,7 @@ impl VM {
                 .push((self.counter, self.cpu.pc - 1, op));
         }
 
-        let ly = self.mem_read(MEM_LOC_LY)?;
-        if let Some(ref mut opcode_dump_file) = self.opcode_dump_file {
-            opcode_dump_file
-                .write_fmt(format_args!(
-                    "PC={:04X} OP={:02X} LY={:02X} AF={:04X} BC={:04X} DE=={:04X} HL={:04X} SP={:04X}\n",
-                    self.cpu.pc - 1,
-                    op,
-                    ly,
-                    self.cpu.af,
-                    self.cpu.bc,
-                    self.cpu.de,
-                    self.cpu.hl,
-                    self.cpu.sp,
-                ))
-                .unwrap();
-        }
+        self.log_trace(op_pc)?;
 
         match op {
             0x00 => {
@@ -510,6 +965,7 @@ impl VM {
             0x10 => {
                 // STOP 0 2 4 | - - - -
                 self.state = State::Stop;
+                self.debugger.check_event(DebugEvent::Stop);
                 self.mem_write(MEM_LOC_DIV, 0)?;
             }
             0x11 => {
@@ -1164,6 +1620,7 @@ impl VM {
             0x76 => {
                 // HALT 1 4 | - - - -
                 self.state = State::Halt;
+                self.debugger.check_event(DebugEvent::Halt);
             }
             0x77 => {
                 // LD (HL),A 1 8 | - - - -
@@ -3264,7 +3721,7 @@ impl VM {
                     is_alternative_mcycle = true;
                 }
             }
-            0xD3 => panic!("Opcode 0xD3 is invalid"),
+            0xD3 => return self.lock_illegal_opcode(op_pc, op),
             0xD4 => {
                 // CALL NC,a16 3 24/12 | - - - -
                 let addr = self.read_op_imm16()?;
@@ -3315,7 +3772,7 @@ impl VM {
                     is_alternative_mcycle = true;
                 }
             }
-            0xDB => panic!("Opcode 0xDB is invalid"),
+            0xDB => return self.lock_illegal_opcode(op_pc, op),
             0xDC => {
                 // CALL C,a16 3 24/12 | - - - -
                 let addr = self.read_op_imm16()?;
@@ -3327,7 +3784,7 @@ impl VM {
                     is_alternative_mcycle = true;
                 }
             }
-            0xDD => panic!("Opcode 0xDD is invalid"),
+            0xDD => return self.lock_illegal_opcode(op_pc, op),
             0xDE => {
                 // SBC A,d8 2 8 | Z 1 H C
                 let byte = self.read_op()?;
@@ -3354,8 +3811,8 @@ impl VM {
                 let word = 0xFF00u16 | self.cpu.get_c() as u16;
                 self.mem_write(word, byte)?;
             }
-            0xE3 => panic!("Opcode 0xE3 is invalid"),
-            0xE4 => panic!("Opcode 0xE4 is invalid"),
+            0xE3 => return self.lock_illegal_opcode(op_pc, op),
+            0xE4 => return self.lock_illegal_opcode(op_pc, op),
             0xE5 => {
                 // PUSH HL 1 16 | - - - -
                 self.push_u16(self.cpu.hl)?;
@@ -3395,9 +3852,9 @@ impl VM {
                 let byte = self.cpu.get_a();
                 self.mem_write(word, byte)?;
             }
-            0xEB => panic!("Opcode 0xEB is invalid"),
-            0xEC => panic!("Opcode 0xEC is invalid"),
-            0xED => panic!("Opcode 0xED is invalid"),
+            0xEB => return self.lock_illegal_opcode(op_pc, op),
+            0xEC => return self.lock_illegal_opcode(op_pc, op),
+            0xED => return self.lock_illegal_opcode(op_pc, op),
             0xEE => {
                 // XOR d8 2 8 | Z 0 0 0
                 let byte = self.read_op()?;
@@ -3429,7 +3886,7 @@ impl VM {
                 // DI 1 4 | - - - -
                 self.interrupt_master_enable_flag = false;
             }
-            0xF4 => panic!("Opcode 0xF4 is invalid"),
+            0xF4 => return self.lock_illegal_opcode(op_pc, op),
             0xF5 => {
                 // PUSH AF 1 16 | - - - -
                 self.push_u16(self.cpu.af & 0xFFF0)?;
@@ -3473,8 +3930,8 @@ impl VM {
                 self.delayed_cmds
                     .push(DelayedCommand::new(2, DelayedOp::MasterInterruptEnable));
             }
-            0xFC => panic!("Opcode 0xFC is invalid"),
-            0xFD => panic!("Opcode 0xFD is invalid"),
+            0xFC => return self.lock_illegal_opcode(op_pc, op),
+            0xFD => return self.lock_illegal_opcode(op_pc, op),
             0xFE => {
                 // CP d8 2 8 | Z 1 H C
                 let byte = self.read_op()?;
@@ -3487,6 +3944,11 @@ impl VM {
             }
         };
 
+        if !is_alternative_mcycle {
+            self.track_call_stack(op, sp, op_pc);
+        }
+        self.call_stack.unwind(self.cpu.sp, op_pc);
+
         if is_alternative_mcycle {
             iteration_mcycle += OPCODE_MCYCLE_ALT[op as usize];
             assert!(OPCODE_MCYCLE_ALT[op as usize] != 0);
@@ -3498,13 +3960,79 @@ impl VM {
         Ok(iteration_mcycle)
     }
 
+    fn log_trace(&mut self, pc: u16) -> Result<(), Error> {
+        let rom_bank = self.current_rom_bank();
+        if !self
+            .trace
+            .as_ref()
+            .is_some_and(|trace| trace.is_enabled(pc, rom_bank))
+        {
+            return Ok(());
+        }
+
+        let mut pcmem = [0u8; 4];
+        for (i, byte) in pcmem.iter_mut().enumerate() {
+            *byte = self.mem_peek(pc.wrapping_add(i as u16)).unwrap_or(0xFF);
+        }
+        let entry = TraceEntry {
+            af: self.cpu.af,
+            bc: self.cpu.bc,
+            de: self.cpu.de,
+            hl: self.cpu.hl,
+            sp: self.cpu.sp,
+            pc,
+            pcmem,
+            ly: self.mem_peek(MEM_LOC_LY)?,
+        };
+        self.trace
+            .as_mut()
+            .map_or(Ok(()), |trace| trace.log(&entry))
+    }
+
+    /**
+     * First byte of an instruction.
+     */
+    fn fetch_op(&mut self) -> Result<u8, Error> {
+        if self.is_dma_blocked(self.cpu.pc) {
+            self.cpu.pc = self.cpu.pc.wrapping_add(1);
+            return Ok(0xFF);
+        }
+        self.mark_cdl(self.cpu.pc, CDL_ROM_CODE | CDL_ROM_OPCODE, 0);
+        let op = self.mem_peek(self.cpu.pc)?;
+        self.cpu.pc = self.cpu.pc.wrapping_add(1);
+
+        Ok(op)
+    }
+
+    /**
+     * Operand byte, including the second byte of CB prefixed instructions.
+     */
     fn read_op(&mut self) -> Result<u8, Error> {
-        let op = self.mem_read(self.cpu.pc)?;
+        if self.is_dma_blocked(self.cpu.pc) {
+            self.cpu.pc = self.cpu.pc.wrapping_add(1);
+            return Ok(0xFF);
+        }
+        self.mark_cdl(self.cpu.pc, CDL_ROM_CODE | CDL_ROM_OPERAND, 0);
+        let op = self.mem_peek(self.cpu.pc)?;
         self.cpu.pc = self.cpu.pc.wrapping_add(1);
 
         Ok(op)
     }
 
+    /**
+     * Records the use of the cartridge byte behind `loc`, with `rom_flags` or `ram_flags` depending on where it is.
+     */
+    fn mark_cdl(&mut self, loc: u16, rom_flags: u8, ram_flags: u8) {
+        let Some(cdl) = self.cdl.as_mut() else {
+            return;
+        };
+        match self.mem.physical_loc(loc) {
+            Some(PhysicalLoc::Rom(addr)) => cdl.mark_rom(addr, rom_flags),
+            Some(PhysicalLoc::Ram(addr)) => cdl.mark_ram(addr, ram_flags),
+            None => (),
+        }
+    }
+
     fn read_hl(&mut self) -> Result<u8, Error> {
         self.mem_read(self.cpu.hl)
     }
@@ -3525,25 +4053,106 @@ impl VM {
         self.mem_write_u16(self.cpu.sp, word)
     }
 
+    /**
+     * Called after a taken CALL / RST / RET / RETI, `sp` is the SP before the instruction at `pc`.
+     */
+    fn track_call_stack(&mut self, op: u8, sp: u16, pc: u16) {
+        match op {
+            // CALL cc,a16 / CALL a16 / RST n
+            0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC | 0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7
+            | 0xFF => {
+                let return_addr = u16::from_le_bytes([
+                    self.mem_peek(self.cpu.sp).unwrap_or(0xFF),
+                    self.mem_peek(self.cpu.sp.wrapping_add(1)).unwrap_or(0xFF),
+                ]);
+                self.call_stack.push(CallFrame {
+                    return_addr,
+                    entry: self.cpu.pc,
+                    sp: self.cpu.sp,
+                    rom_bank: self.current_rom_bank(),
+                    interrupt: None,
+                });
+            }
+            // RET cc / RET / RETI
+            0xC0 | 0xC8 | 0xC9 | 0xD0 | 0xD8 | 0xD9 => self.call_stack.pop(sp, pc),
+            _ => (),
+        }
+    }
+
     fn pop_u16(&mut self) -> Result<u16, Error> {
         let word = self.mem_read_u16(self.cpu.sp)?;
         self.cpu.sp = self.cpu.sp.wrapping_add(2);
         Ok(word)
     }
 
+    /**
+     * Breakpoint conditions are evaluated against the VM, which owns the debugger, so the debugger is lent out for
+     * the check. Only done when there is a condition to evaluate, to keep the common path cheap.
+     */
+    fn debugger_should_stop(&mut self) -> bool {
+        let pc = self.cpu.pc;
+        if self.debugger.has_event_breakpoints() && self.state == State::Running {
+            if let Ok(op) = self.mem_peek(pc) {
+                if OPCODE_NAME[op as usize] == "Invalid" {
+                    self.debugger.check_event(DebugEvent::IllegalOpcode(op));
+                }
+            }
+        }
+        if !self.debugger.has_conditions(pc) {
+            return self
+                .debugger
+                .should_stop(pc, self.call_stack.depth(), &mut NoExprContext);
+        }
+
+        let mut debugger = std::mem::take(&mut self.debugger);
+        let should_stop = debugger.should_stop(pc, self.call_stack.depth(), self);
+        self.debugger = debugger;
+        should_stop
+    }
+
+    /**
+     * ROM bank mapped to 0x4000-0x7FFF, as numbered in symbol files.
+     */
+    fn current_rom_bank(&self) -> u8 {
+        self.mem.rom_bank_selector().max(1)
+    }
+
+    /**
+     * ` <Label>` (or `Label+N`) of an address from the symbol file, empty when unknown.
+     */
+    fn symbol_suffix(&self, addr: u16) -> String {
+        self.debugger
+            .symbols()
+            .describe(addr, self.current_rom_bank())
+            .map(|label| format!(" <{}>", label))
+            .unwrap_or_default()
+    }
+
     fn read_repl(&mut self) -> Result<Option<DebugCmd>, Error> {
-        let next_op = self.mem_read(self.cpu.pc)?;
+        if let Some(mut gdb) = self.gdb.take() {
+            let cmd = gdb.serve(self);
+            self.gdb = Some(gdb);
+            return cmd.map(Some);
+        }
+        if let Some(mut dap) = self.dap.take() {
+            let cmd = dap.serve(self);
+            self.dap = Some(dap);
+            return cmd.map(Some);
+        }
+
+        let next_op = self.mem_peek(self.cpu.pc)?;
+        let label = self.symbol_suffix(self.cpu.pc);
         if next_op == 0xCB {
-            let next_prefix_op = self.mem_read(self.cpu.pc + 1)?;
+            let next_prefix_op = self.mem_peek(self.cpu.pc + 1)?;
 
             print!(
-                "{:>8} | NEXT {:#04X} | {} > ",
-                self.counter, self.cpu.pc, OPCODE_CB_NAME[next_prefix_op as usize]
+                "{:>8} | NEXT {:#04X}{} | {} > ",
+                self.counter, self.cpu.pc, label, OPCODE_CB_NAME[next_prefix_op as usize]
             );
         } else {
             print!(
-                "{:>8} | NEXT {:#04X} | {} > ",
-                self.counter, self.cpu.pc, OPCODE_NAME[next_op as usize]
+                "{:>8} | NEXT {:#04X}{} | {} > ",
+                self.counter, self.cpu.pc, label, OPCODE_NAME[next_op as usize]
             );
         }
 
@@ -3551,7 +4160,8 @@ impl VM {
         stdout().flush()?;
         stdin().read_line(&mut buf)?;
 
-        Ok(self.debugger.parse(buf))
+        let rom_bank = self.current_rom_bank();
+        Ok(self.debugger.parse(buf, rom_bank))
     }
 
     fn print_debug_panel(&mut self) {
@@ -3564,13 +4174,13 @@ impl VM {
             self.cpu.get_fn(),
             self.cpu.get_fh(),
             self.cpu.get_fc(),
-            self.mem_read(MEM_LOC_LCDC).unwrap(),
+            self.mem_peek(MEM_LOC_LCDC).unwrap(),
         );
         println!(
             "\x1B[93mB\x1B[0m {:02X} {:02X} \x1B[93mC\x1B[0m |             | \x1B[93mSTAT\x1B[0m {:02X}",
             self.cpu.get_b(),
             self.cpu.get_c(),
-            self.mem_read(MEM_LOC_STAT).unwrap()
+            self.mem_peek(MEM_LOC_STAT).unwrap()
         );
         println!(
             "\x1B[93mD\x1B[0m {:02X} {:02X} \x1B[93mE\x1B[0m |             | \x1B[93mLY\x1B[0m {:02X}",
@@ -3594,6 +4204,12 @@ impl VM {
             self.mem.boot_lock_reg == 0,
             self.mem.rom_bank_selector()
         );
+        if let Some(lock) = self.cpu_lock.read().unwrap().as_ref() {
+            println!(
+                "\x1B[91mCPU locked\x1B[0m at {:04X}: {}",
+                lock.pc, lock.reason
+            );
+        }
 
         self.timer.dump_debug_panel();
 
@@ -3608,7 +4224,7 @@ impl VM {
 
             print!(
                 " {:02X}",
-                self.mem_read(from + i as u16)
+                self.mem_peek(from + i as u16)
                     .expect("Failed reading memory")
             );
 
@@ -3621,12 +4237,77 @@ impl VM {
     }
 
     fn mem_write(&mut self, loc: u16, byte: u8) -> Result<(), Error> {
-        if loc <= MEM_AREA_ROM_BANK_0_END {
+        self.debugger.check_watchpoints(WatchKind::Write, loc, byte);
+        if loc >= MEM_AREA_IO_START && !(MEM_AREA_HRAM_START..=MEM_AREA_HRAM_END).contains(&loc) {
+            self.debugger.check_event(DebugEvent::IoWrite(loc, byte));
+        }
+        if (MEM_AREA_EXTERNAL_START..=MEM_AREA_EXTERNAL_END).contains(&loc) {
+            self.mark_cdl(loc, 0, CDL_RAM_WRITTEN);
+        }
+        if self.is_dma_blocked(loc) {
+            log::debug!("Write during OAM DMA dropped: {:04X}", loc);
+            return Ok(());
+        }
+        self.check_oam_corruption(loc, true);
+        self.mem_poke(loc, byte)
+    }
+
+    /**
+     * During OAM DMA the CPU only reaches HRAM and the IO registers, everything else reads 0xFF.
+     */
+    fn is_dma_blocked(&self, loc: u16) -> bool {
+        self.oam_dma.is_active() && loc < MEM_AREA_IO_START
+    }
+
+    fn step_oam_dma(&mut self, mcycles: u32) {
+        for _ in 0..mcycles {
+            if let Some((loc, offs)) = self.oam_dma.step() {
+                let byte = self.mem_peek(loc).unwrap_or(0xFF);
+                self.debugger.check_watchpoints(WatchKind::Read, loc, byte);
+                self.mark_cdl(loc, CDL_ROM_DATA | CDL_ROM_DMA, CDL_RAM_READ);
+                self.video.write().unwrap().dma_oam_write(offs, byte);
+            }
+        }
+        self.oam_dma.end_instruction();
+    }
+
+    /**
+     * CPU accesses to 0xFE00-0xFEFF trigger the OAM bug, debugger peeks and pokes do not.
+     */
+    fn check_oam_corruption(&mut self, loc: u16, is_write: bool) {
+        if (MEM_AREA_OAM_START..=MEM_AREA_PROHIBITED_END).contains(&loc) {
+            self.video.write().unwrap().corrupt_oam(is_write);
+        }
+    }
+
+    /**
+     * Bus write without watchpoint checks, for debugger tools. Writes with nothing behind them are dropped.
+     */
+    fn mem_poke(&mut self, loc: u16, byte: u8) -> Result<(), Error> {
+        match self.bus_write(loc, byte) {
+            Err(EmulatorError::OpenBus(_)) => {
+                log::debug!("Open bus write: {:#06X} = {:#04X}", loc, byte);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn bus_write(&mut self, loc: u16, byte: u8) -> Result<(), Error> {
+        if is_raster_register(loc) {
+            self.record_event(EventKind::RegisterWrite(loc, byte));
+        }
+
+        if loc <= MEM_AREA_ROM_BANK_N_END {
+            let rom_bank = self.mem.rom_bank_selector();
             self.mem.write(loc, byte)?;
-        } else if loc <= MEM_AREA_ROM_BANK_N_END {
-            return Err("Cannot write to ROM (N)".into());
+            if self.mem.rom_bank_selector() != rom_bank {
+                self.record_event(EventKind::RomBank(self.mem.rom_bank_selector()));
+                self.debugger
+                    .check_event(DebugEvent::RomBank(self.mem.rom_bank_selector()));
+            }
         } else if loc <= MEM_AREA_VRAM_END {
-            self.video.write().unwrap().write(loc, byte);
+            self.video.write().unwrap().write(loc, byte)?;
         } else if loc <= MEM_AREA_EXTERNAL_END {
             self.mem.write(loc, byte)?;
         } else if loc <= MEM_AREA_WRAM_END {
@@ -3639,7 +4320,7 @@ impl VM {
             // .into());
             self.mem.write(loc - 0x2000, byte)?;
         } else if loc <= MEM_AREA_OAM_END {
-            self.video.write().unwrap().write(loc, byte);
+            self.video.write().unwrap().write(loc, byte)?;
         } else if loc <= MEM_AREA_PROHIBITED_END {
             // Ignore for now. BGB seems to do nothing with these (eg LD (0xFEFF) a).
             // return Err("Write to MEM_AREA_PROHIBITED is not implemented".into());
@@ -3660,61 +4341,97 @@ impl VM {
                     // }
                 }
                 MEM_LOC_IF => self.interrupt_flag = byte | 0xE0,
-                MEM_LOC_NR10..=MEM_LOC_WAVE_PATTERN_END => self.sound.write(loc, byte),
+                MEM_LOC_NR10..=MEM_LOC_WAVE_PATTERN_END => self.sound.write(loc, byte)?,
                 MEM_LOC_LCDC..=MEM_LOC_WX => {
                     if loc == MEM_LOC_DMA {
-                        assert!(byte <= 0xDF);
-                        let addr = (byte as u16) << 8;
-                        let block = (0..0xA0)
-                            .map(|offs| self.mem_read(addr + offs).expect("Cannot read for DMA"))
-                            .collect::<Vec<_>>();
-                        self.video
-                            .write()
-                            .expect("Failed locking for DMA write")
-                            .dma_oam_transfer(block);
-                        // Not sure if we should spend 160 mcycle here.
+                        self.record_event(EventKind::OamDma(byte));
+                        self.oam_dma.request(byte);
                     } else {
-                        self.video.write().unwrap().write(loc, byte);
+                        self.video.write().unwrap().write(loc, byte)?;
                     }
                 }
-                MEM_LOC_KEY1 => unimplemented!("Write to register KEY1 is not implemented"),
-                MEM_LOC_VBK => unimplemented!("Write to register VBK is not implemented"),
                 MEM_LOC_BOOT_LOCK_REG => {
                     // BOOT_OFF can only transition from 0b0 to 0b1, so once 0b1 has been written, the boot ROM is
                     // permanently disabled until the next system reset. Writing 0b0 when BOOT_OFF is 0b0 has no
                     // effect and doesn’t lock the boot ROM.
-                    if byte == 0b1 {
-                        self.mem.boot_lock_reg = byte;
-                    } else {
-                        return Err("Boot lock register must only be set to 1".into());
+                    if byte & 0b1 == 0b1 {
+                        self.mem.boot_lock_reg = 0b1;
                     }
                 }
-                MEM_LOC_HDMA1 => unimplemented!("Write to register HDMA1 is not implemented"),
-                MEM_LOC_HDMA2 => unimplemented!("Write to register HDMA2 is not implemented"),
-                MEM_LOC_HDMA3 => unimplemented!("Write to register HDMA3 is not implemented"),
-                MEM_LOC_HDMA4 => unimplemented!("Write to register HDMA4 is not implemented"),
-                MEM_LOC_HDMA5 => unimplemented!("Write to register HDMA5 is not implemented"),
-                MEM_LOC_RP => unimplemented!("Write to register RP is not implemented"),
-                MEM_LOC_BCPS => unimplemented!("Write to register BCPS is not implemented"),
-                MEM_LOC_BCPD => unimplemented!("Write to register BCPD is not implemented"),
-                MEM_LOC_OCPS => unimplemented!("Write to register OCPS is not implemented"),
-                MEM_LOC_OCPD => unimplemented!("Write to register OCPD is not implemented"),
-                MEM_LOC_SVBK => unimplemented!("Write to register SVBK is not implemented"),
-                _ => {
-                    log::error!("Suspicious IO write to{:#06X}", loc);
-                }
+                _ => return Err(EmulatorError::OpenBus(loc)),
             };
         } else if loc <= MEM_AREA_HRAM_END {
             self.mem.write(loc, byte)?;
         } else if loc == MEM_LOC_IE {
-            self.set_interrupt_enable(byte);
+            self.interrupt_enable = byte;
         } else {
-            return Err("Write outside of memory".into());
+            return Err(EmulatorError::BusFault(
+                loc,
+                "outside of memory".to_string(),
+            ));
         }
 
         Ok(())
     }
 
+    /**
+     * Write patching cartridge ROM / RAM in place instead of setting the MBC registers, for debugger tools.
+     */
+    fn mem_write_raw(&mut self, loc: u16, byte: u8) -> Result<(), Error> {
+        self.debugger.check_watchpoints(WatchKind::Write, loc, byte);
+        if loc <= MEM_AREA_ROM_BANK_N_END
+            || (MEM_AREA_EXTERNAL_START..=MEM_AREA_EXTERNAL_END).contains(&loc)
+        {
+            self.mem.write_raw(loc, byte)
+        } else {
+            self.mem_poke(loc, byte)
+        }
+    }
+
+    /**
+     * Debugger edit of consecutive bytes, stopping at the first failed write or the end of the address space.
+     */
+    fn write_memory_range(&mut self, from: u16, bytes: &[u8], raw: bool) -> Result<(), Error> {
+        for (i, byte) in bytes.iter().enumerate() {
+            let loc = u16::try_from(from as usize + i)
+                .map_err(|_| "Write past the end of the address space")?;
+            if raw {
+                self.mem_write_raw(loc, *byte)?;
+            } else {
+                self.mem_write(loc, *byte)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn set_variable(&mut self, variable: Variable, value: u16) {
+        let byte = value as u8;
+        match variable {
+            Variable::A => self.cpu.set_a(byte),
+            // Lower nibble of F is always 0.
+            Variable::F => self.cpu.af = (self.cpu.af & 0xFF00) | (byte & 0xF0) as u16,
+            Variable::B => self.cpu.set_b(byte),
+            Variable::C => self.cpu.set_c(byte),
+            Variable::D => self.cpu.set_d(byte),
+            Variable::E => self.cpu.set_e(byte),
+            Variable::H => self.cpu.set_h(byte),
+            Variable::L => self.cpu.set_l(byte),
+            Variable::AF => self.cpu.af = value & 0xFFF0,
+            Variable::BC => self.cpu.bc = value,
+            Variable::DE => self.cpu.de = value,
+            Variable::HL => self.cpu.hl = value,
+            Variable::SP => self.cpu.sp = value,
+            Variable::PC => self.cpu.pc = value,
+            Variable::FlagZ => self.cpu.set_fz(value != 0),
+            Variable::FlagN => self.cpu.set_fn(value != 0),
+            Variable::FlagH => self.cpu.set_fh(value != 0),
+            Variable::FlagC => self.cpu.set_fc(value != 0),
+            Variable::Counter | Variable::RomBank => {
+                log::warn!("{:?} cannot be set", variable)
+            }
+        }
+    }
+
     fn mem_write_u16(&mut self, loc: u16, word: u16) -> Result<(), Error> {
         log::debug!("Write: {:#06X} = #{:#06X}", loc, word);
 
@@ -3728,6 +4445,31 @@ impl VM {
     }
 
     fn mem_read(&mut self, loc: u16) -> Result<u8, Error> {
+        let byte = if self.is_dma_blocked(loc) {
+            0xFF
+        } else if loc == MEM_LOC_LY && self.trace.as_ref().is_some_and(|trace| trace.stubs_ly()) {
+            0x90
+        } else {
+            self.check_oam_corruption(loc, false);
+            self.mem_peek(loc)?
+        };
+        self.debugger.check_watchpoints(WatchKind::Read, loc, byte);
+        self.mark_cdl(loc, CDL_ROM_DATA, CDL_RAM_READ);
+        Ok(byte)
+    }
+
+    /**
+     * Reads memory without triggering watchpoints: instruction fetch and internal or debugger reads. Reads with
+     * nothing behind them float to 0xFF.
+     */
+    fn mem_peek(&mut self, loc: u16) -> Result<u8, Error> {
+        match self.bus_read(loc) {
+            Err(EmulatorError::OpenBus(_)) => Ok(0xFF),
+            result => result,
+        }
+    }
+
+    fn bus_read(&mut self, loc: u16) -> Result<u8, Error> {
         match loc {
             // TODO: Add oam/vram read here proxy to video
             MEM_AREA_ROM_BANK_0_START..=MEM_AREA_ROM_BANK_N_END => self.mem.read(loc),
@@ -3735,38 +4477,25 @@ impl VM {
             MEM_AREA_EXTERNAL_START..=MEM_AREA_ECHO_END => self.mem.read(loc),
             MEM_AREA_OAM_START..=MEM_AREA_OAM_END => self.video.read().unwrap().read(loc),
             MEM_AREA_PROHIBITED_START..=MEM_AREA_PROHIBITED_END => {
-                Err(format!("Read from prohibited mem area: {:#06X}", loc).into())
+                Ok(self.video.read().unwrap().read_prohibited())
             }
             MEM_AREA_IO_START..=MEM_AREA_IO_END => match loc {
                 MEM_LOC_P1 => Ok(self.joypad.get_p1()),
-                MEM_LOC_SB => unimplemented!("Read from register SB is not implemented"),
-                MEM_LOC_SC => unimplemented!("Read from register SC is not implemented"),
+                MEM_LOC_SB => Ok(self.serial.sb()),
+                MEM_LOC_SC => Ok(self.serial.sc()),
                 MEM_LOC_DIV => Ok(self.timer.div()),
                 MEM_LOC_TIMA => Ok(self.timer.tima()),
                 MEM_LOC_TMA => Ok(self.timer.tma()),
                 MEM_LOC_TAC => Ok(self.timer.tac()),
                 MEM_LOC_IF => Ok(self.interrupt_flag),
                 MEM_LOC_NR10..=MEM_LOC_WAVE_PATTERN_END => self.sound.read(loc),
+                MEM_LOC_DMA => Ok(self.oam_dma.reg()),
                 MEM_LOC_LCDC..=MEM_LOC_WX => self.video.read().unwrap().read(loc),
-                MEM_LOC_KEY1 => {
-                    // FF4D — KEY1 (CGB Mode only): Prepare speed switch --> ignore.
-                    Ok(0xFF)
-                }
-                MEM_LOC_VBK => unimplemented!("Read from register VBK is not implemented"),
                 MEM_LOC_BOOT_LOCK_REG => Ok(self.mem.boot_lock_reg),
-                MEM_LOC_HDMA1 => unimplemented!("Read from register HDMA1 is not implemented"),
-                MEM_LOC_HDMA2 => unimplemented!("Read from register HDMA2 is not implemented"),
-                MEM_LOC_HDMA3 => unimplemented!("Read from register HDMA3 is not implemented"),
-                MEM_LOC_HDMA4 => unimplemented!("Read from register HDMA4 is not implemented"),
-                MEM_LOC_HDMA5 => unimplemented!("Read from register HDMA5 is not implemented"),
-                MEM_LOC_RP => unimplemented!("Read from register RP is not implemented"),
-                MEM_LOC_BCPS => unimplemented!("Read from register BCPS is not implemented"),
-                MEM_LOC_BCPD => unimplemented!("Read from register BCPD is not implemented"),
-                MEM_LOC_OCPS => unimplemented!("Read from register OCPS is not implemented"),
-                MEM_LOC_OCPD => unimplemented!("Read from register OCPD is not implemented"),
-                MEM_LOC_SVBK => unimplemented!("Read from register SVBK is not implemented"),
-                _ => unimplemented!("Read from MEM_AREA_IO is not implemented"),
-            },
+                // CGB only registers included.
+                _ => Err(EmulatorError::OpenBus(loc)),
+            }
+            .map(|byte| byte | IO_UNUSED_BITS[(loc - MEM_AREA_IO_START) as usize]),
             MEM_AREA_HRAM_START..=MEM_AREA_HRAM_END => self.mem.read(loc),
             MEM_LOC_IE => Ok(self.interrupt_enable),
         }
@@ -3778,11 +4507,6 @@ impl VM {
         Ok(((hi as u16) << 8) | lo as u16)
     }
 
-    fn set_interrupt_enable(&mut self, value: u8) {
-        assert!((0b1110_0000 & value) == 0);
-        self.interrupt_enable = value;
-    }
-
     fn is_vblank_interrupt_enabled(&self) -> bool {
         (self.interrupt_enable & 0b1) > 0
     }
@@ -3804,13 +4528,17 @@ impl VM {
     }
 
     fn check_interrupt(&mut self) -> bool /* Whether interrupt has happened. */ {
+        if self.state == State::Locked {
+            return false;
+        }
+
         if !self.interrupt_master_enable_flag && self.state != State::Halt {
             return false;
         }
 
         // If an interrupt is pending, halt immediately exits, as expected, however the “halt bug”, explained below,
         // is triggered.
-        if self.interrupt_flag & self.interrupt_enable == 0 {
+        if self.interrupt_flag & self.interrupt_enable & 0b1_1111 == 0 {
             return false;
         }
 
@@ -3857,7 +4585,7 @@ impl VM {
     pub fn dump_op_history(&self) {
         println!("Last {} ops (MOD-64):", self.deep_op_history.inner().len());
         for (counter, pc, op) in self.deep_op_history.inner() {
-            println!("\t\x1B[37m#{}\x1B[0m: PC=\x1B[93m{:#06X}\x1B[0m OP=\x1B[95m{:#04X}\x1B[0m -> \x1B[96m{}\x1B[0m", counter, *pc, *op, OPCODE_NAME[*op as usize]);
+            println!("\t\x1B[37m#{}\x1B[0m: PC=\x1B[93m{:#06X}\x1B[0m{} OP=\x1B[95m{:#04X}\x1B[0m -> \x1B[96m{}\x1B[0m", counter, *pc, self.symbol_suffix(*pc), *op, OPCODE_NAME[*op as usize]);
         }
 
         println!("\n---\n");
@@ -3866,26 +4594,155 @@ impl VM {
         println!("Last {} op:", op_count);
         for (i, (pc, op)) in self.op_history.inner().iter().enumerate() {
             println!(
-                "\t\x1B[37m#{}\x1B[0m: PC=\x1B[93m{:#06X}\x1B[0m OP=\x1B[95m{:#04X}\x1B[0m -> \x1B[96m{}\x1B[0m",
+                "\t\x1B[37m#{}\x1B[0m: PC=\x1B[93m{:#06X}\x1B[0m{} OP=\x1B[95m{:#04X}\x1B[0m -> \x1B[96m{}\x1B[0m",
                 self.counter as i64 - (op_count as i64 + 1 - i as i64),
                 *pc,
+                self.symbol_suffix(*pc),
                 *op,
                 OPCODE_NAME[*op as usize]
             );
         }
     }
 
+    fn disassemble_at(&mut self, addr: u16) -> Instruction {
+        let bytes = [0, 1, 2].map(|offs| self.mem_peek(addr.wrapping_add(offs)).unwrap_or(0xFF));
+        let rom_bank = self.current_rom_bank();
+        let symbols = self.debugger.symbols();
+        decode(addr, bytes, &|target| symbols.describe(target, rom_bank))
+    }
+
+    fn disassemble(&mut self, from: u16, count: usize) -> Vec<Instruction> {
+        let mut addr = from;
+        (0..count)
+            .map(|_| {
+                let instruction = self.disassemble_at(addr);
+                addr = instruction.next_addr();
+                instruction
+            })
+            .collect()
+    }
+
+    /**
+     * Instructions before and after `pc`. Decoding backwards is ambiguous, so it decodes forward from the farthest
+     * start that lines up with `pc`.
+     */
+    fn disassemble_around(&mut self, pc: u16, before: usize, after: usize) -> Vec<Instruction> {
+        for distance in (1..=before as u16 * 3).rev() {
+            let from = pc.wrapping_sub(distance);
+            let mut addr = from;
+            let mut instructions = vec![];
+            while addr != pc && pc.wrapping_sub(addr) <= distance {
+                let instruction = self.disassemble_at(addr);
+                addr = instruction.next_addr();
+                instructions.push(instruction);
+            }
+
+            if addr == pc && instructions.len() >= before {
+                instructions.drain(0..instructions.len() - before);
+                instructions.extend(self.disassemble(pc, after));
+                return instructions;
+            }
+        }
+
+        self.disassemble(pc, after)
+    }
+
+    /**
+     * Instructions as lines, with a label line above labelled addresses.
+     */
+    fn disassembly_lines(&self, instructions: &[Instruction]) -> Vec<(Option<u16>, String)> {
+        let rom_bank = self.current_rom_bank();
+        let mut lines = vec![];
+        for instruction in instructions {
+            if let Some(label) = self.debugger.symbols().label_at(instruction.addr, rom_bank) {
+                lines.push((None, format!("{}:", label)));
+            }
+            lines.push((Some(instruction.addr), instruction.format()));
+        }
+        lines
+    }
+
+    fn print_disassembly(&mut self, from: u16, count: usize) {
+        let instructions = self.disassemble(from, count);
+        for (addr, line) in self.disassembly_lines(&instructions) {
+            if addr == Some(self.cpu.pc) {
+                println!("\x1B[93m> {}\x1B[0m", line);
+            } else if addr.is_none() {
+                println!("\x1B[96m{}\x1B[0m", line);
+            } else {
+                println!("  {}", line);
+            }
+        }
+    }
+
+    /**
+     * Only redecoded when PC leaves the current view or the ROM bank changes.
+     */
+    fn update_disassembly_view(&mut self) {
+        let pc = self.cpu.pc;
+        let rom_bank = self.current_rom_bank();
+        {
+            let mut view = self.vm_disassembly.write().unwrap();
+            if view.rom_bank == rom_bank && view.contains(pc) {
+                view.pc = pc;
+                return;
+            }
+        }
+
+        let instructions = self.disassemble_around(pc, 32, 64);
+        let lines = self.disassembly_lines(&instructions);
+
+        let mut view = self.vm_disassembly.write().unwrap();
+        view.lines = lines;
+        view.pc = pc;
+        view.rom_bank = rom_bank;
+    }
+
+    /**
+     * Applies the edits of the hex view, then reads its page again.
+     */
+    fn update_memory_view(&mut self) {
+        let (from, raw, edits) = {
+            let mut view = self.vm_memory.write().unwrap();
+            (view.from, view.raw, std::mem::take(&mut view.edits))
+        };
+
+        for (loc, byte) in edits {
+            if let Err(err) = self.write_memory_range(loc, &[byte], raw) {
+                log::error!("Cannot write {:02X} at {:04X}: {}", byte, loc, err);
+            }
+        }
+
+        let bytes = (0..MEMORY_VIEW_SIZE)
+            .map(|i| {
+                let loc = from.wrapping_add(i as u16);
+                self.mem_peek(loc).unwrap_or(0xFF)
+            })
+            .collect();
+        self.vm_memory.write().unwrap().bytes = bytes;
+    }
+
     fn debug_oam(&self) {
         self.video.read().unwrap().debug_oam();
     }
 
     fn update_vm_debug_log(&mut self) {
-        let opcode = self.mem_read(self.cpu.pc).unwrap() as usize;
+        let opcode = self.mem_peek(self.cpu.pc).unwrap() as usize;
+        let label = self.symbol_suffix(self.cpu.pc);
         let mut log = self.vm_debug_log.write().unwrap();
         log.clear();
 
-        log.push(format!("Next {:04X} -> {}", self.cpu.pc, OPCODE_NAME[opcode]).into());
+        log.push(
+            format!(
+                "Next {:04X}{} -> {}",
+                self.cpu.pc, label, OPCODE_NAME[opcode]
+            )
+            .into(),
+        );
 
+        if let Some(lock) = self.cpu_lock.read().unwrap().as_ref() {
+            log.push(format!("CPU locked at {:04X}: {}", lock.pc, lock.reason));
+        }
         log.push(format!("AF {:04X}", self.cpu.af).into());
         log.push(format!("BC {:04X}", self.cpu.bc).into());
         log.push(format!("DE {:04X}", self.cpu.de).into());
@@ -3930,13 +4787,333 @@ impl VM {
             )
             .into(),
         );
+
+        log.push("Call stack".into());
+        log.extend(self.call_stack_lines());
+    }
+
+    /**
+     * Innermost frame first, with the current PC on top and the last desync at the bottom.
+     */
+    fn call_stack_lines(&self) -> Vec<String> {
+        let symbols = self.debugger.symbols();
+        let describe = |addr: u16, rom_bank: u8| {
+            symbols
+                .describe(addr, rom_bank)
+                .map(|label| format!(" <{}>", label))
+                .unwrap_or_default()
+        };
+        let describe_frame = |frame: &CallFrame| {
+            let kind = match frame.interrupt {
+                Some(interrupt) => format!("{:?} interrupt", interrupt),
+                None => "call".to_string(),
+            };
+            format!(
+                "{:04X}{} | ret {:04X}{} | ROM {:02X} | {}",
+                frame.entry,
+                describe(frame.entry, frame.rom_bank),
+                frame.return_addr,
+                describe(frame.return_addr, frame.rom_bank),
+                frame.rom_bank,
+                kind
+            )
+        };
+
+        let mut lines = vec![format!(
+            "#0 PC {:04X}{}",
+            self.cpu.pc,
+            self.symbol_suffix(self.cpu.pc)
+        )];
+        for (i, frame) in self.call_stack.frames().enumerate() {
+            lines.push(format!("#{} {}", i + 1, describe_frame(frame)));
+        }
+
+        if let Some(desync) = self.call_stack.desync() {
+            lines.push(format!(
+                "! SP {:04X} at {:04X}{} dropped {} frame(s) without RET:",
+                desync.sp,
+                desync.pc,
+                self.symbol_suffix(desync.pc),
+                desync.frames.len()
+            ));
+            for frame in desync.frames.iter().rev() {
+                lines.push(format!("!  {}", describe_frame(frame)));
+            }
+        }
+
+        lines
+    }
+
+    fn request_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt_flag |= 1 << interrupt.bit();
+        self.record_event(EventKind::InterruptRequest(interrupt));
+    }
+
+    fn record_event(&mut self, kind: EventKind) {
+        if self.timeline.is_enabled {
+            let video = self.video.read().unwrap();
+            let (ly, dot) = (video.ly, video.dot());
+            drop(video);
+            self.timeline.record(ly, dot, kind);
+        }
+    }
+
+    /**
+     * STAT mode changes and LYC matches of the last PPU update, at the position after it.
+     */
+    fn record_ppu_events(&mut self, prev_stat: u8, stat: u8, ly: u8, dot: u16) {
+        if prev_stat & 0b11 != stat & 0b11 {
+            self.timeline
+                .record(ly, dot, EventKind::StatMode(stat & 0b11));
+        }
+        if prev_stat & 0b100 == 0 && stat & 0b100 != 0 {
+            self.timeline.record(ly, dot, EventKind::LycMatch);
+        }
+        self.timeline.advance(ly);
+    }
+
+    /**
+     * Illegal opcodes hang the CPU: no more instructions or interrupts, the rest of the hardware keeps running.
+     * Bus faults do the same, instead of aborting the emulator.
+     */
+    fn lock(&mut self, pc: u16, reason: String) {
+        log::error!("CPU locked at {:04X}: {}", pc, reason);
+        self.state = State::Locked;
+        *self.cpu_lock.write().unwrap() = Some(CpuLock { pc, reason });
+    }
+
+    /**
+     * Returns before the cycle table lookup of `exec_op`: illegal opcodes have no entry, the CPU just stops.
+     */
+    fn lock_illegal_opcode(&mut self, pc: u16, op: u8) -> Result<u8, Error> {
+        self.lock(pc, format!("Illegal opcode {:02X}", op));
+        Ok(1)
     }
 
     fn interrupt(&mut self, interrupt: Interrupt) {
         self.interrupt_master_enable_flag = false;
+        self.record_event(EventKind::InterruptService(interrupt));
+        self.debugger
+            .check_event(DebugEvent::InterruptService(interrupt));
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.count_interrupt(interrupt);
+        }
 
         self.interrupt_flag &= !(1u8 << interrupt.bit());
         self.push_u16(self.cpu.pc).expect("Failed stacking PC");
+        self.call_stack.push(CallFrame {
+            return_addr: self.cpu.pc,
+            entry: interrupt.addr(),
+            sp: self.cpu.sp,
+            rom_bank: self.current_rom_bank(),
+            interrupt: Some(interrupt),
+        });
         self.cpu.pc = interrupt.addr();
     }
 }
+
+impl GdbTarget for VM {
+    fn registers(&self) -> [u16; REGISTER_COUNT] {
+        [
+            self.cpu.af,
+            self.cpu.bc,
+            self.cpu.de,
+            self.cpu.hl,
+            self.cpu.sp,
+            self.cpu.pc,
+        ]
+    }
+
+    fn set_register(&mut self, register: usize, value: u16) {
+        match register {
+            // Lower nibble of F is always 0.
+            0 => self.cpu.af = value & 0xFFF0,
+            1 => self.cpu.bc = value,
+            2 => self.cpu.de = value,
+            3 => self.cpu.hl = value,
+            4 => self.cpu.sp = value,
+            _ => self.cpu.pc = value,
+        }
+    }
+
+    fn read_memory(&mut self, addr: u16) -> u8 {
+        self.mem_peek(addr).unwrap_or(0xFF)
+    }
+
+    fn write_memory(&mut self, addr: u16, byte: u8) -> Result<(), Error> {
+        self.mem_poke(addr, byte)
+    }
+
+    fn debugger(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+}
+
+impl DapTarget for VM {
+    fn stack(&self) -> Vec<(u16, u8)> {
+        // Each caller is at the return address of the frame above it.
+        let mut stack = vec![(self.cpu.pc, self.current_rom_bank())];
+        stack.extend(
+            self.call_stack
+                .frames()
+                .map(|frame| (frame.return_addr, frame.rom_bank)),
+        );
+        stack
+    }
+
+    fn call_depth(&self) -> usize {
+        self.call_stack.depth()
+    }
+
+    fn debugger(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+}
+
+impl ExprContext for VM {
+    fn variable(&mut self, variable: Variable) -> i64 {
+        let flag = |bit: u8| (self.cpu.get_f() >> bit) as i64 & 1;
+
+        match variable {
+            Variable::A => self.cpu.get_a() as i64,
+            Variable::F => self.cpu.get_f() as i64,
+            Variable::B => self.cpu.get_b() as i64,
+            Variable::C => self.cpu.get_c() as i64,
+            Variable::D => self.cpu.get_d() as i64,
+            Variable::E => self.cpu.get_e() as i64,
+            Variable::H => self.cpu.get_h() as i64,
+            Variable::L => self.cpu.get_l() as i64,
+            Variable::AF => self.cpu.af as i64,
+            Variable::BC => self.cpu.bc as i64,
+            Variable::DE => self.cpu.de as i64,
+            Variable::HL => self.cpu.hl as i64,
+            Variable::SP => self.cpu.sp as i64,
+            Variable::PC => self.cpu.pc as i64,
+            Variable::FlagZ => flag(7),
+            Variable::FlagN => flag(6),
+            Variable::FlagH => flag(5),
+            Variable::FlagC => flag(4),
+            Variable::Counter => self.counter as i64,
+            Variable::RomBank => self.current_rom_bank() as i64,
+        }
+    }
+
+    fn read_memory(&mut self, loc: u16) -> u8 {
+        self.mem_peek(loc).unwrap_or(0xFF)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::trace::{TraceFilter, TraceFormat};
+    use crate::vm::*;
+
+    fn vm_with_rom(rom: &[u8]) -> VM {
+        let mut data = vec![0; 0x8000];
+        data[0x0100..0x0100 + rom.len()].copy_from_slice(rom);
+        let path = std::env::temp_dir().join(format!("lameboy_vm_{}.gb", std::process::id()));
+        std::fs::write(&path, data).unwrap();
+        let cartridge = Cartridge::new(path.to_string_lossy().into_owned()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let options = VmOptions {
+            disable_sound: true,
+            rewind: Rewind::new(1, 0, Arc::new(AtomicBool::new(false))),
+            movie: None,
+            gamepad: None,
+            input_sequencer: InputSequencer::new(Arc::new(Default::default()), 1, vec![]),
+        };
+        let tools = DebugTools {
+            debugger: Debugger::new(Arc::new(AtomicBool::new(false))),
+            trace: None,
+            cdl: None,
+            profiler: None,
+            gdb: None,
+            dap: None,
+        };
+        let joypad = Joypad::new(Arc::new(RwLock::new(JoypadInputRequest::new())));
+
+        let mut vm = VM::new(
+            Arc::new(AtomicBool::new(false)),
+            cartridge,
+            Arc::new(RwLock::new(PPU::new())),
+            joypad,
+            options,
+            tools,
+            DebugViews::default(),
+        )
+        .unwrap();
+        vm.setup(true).unwrap();
+        vm
+    }
+
+    #[test]
+    fn test_illegal_opcode_locks() {
+        let mut vm = vm_with_rom(&[0xD3]);
+        // Timer on, overflowing every 256 * 4 M-cycles.
+        vm.mem_write(MEM_LOC_TAC, 0b101).unwrap();
+
+        // A frame and a bit, the timer overflowing many times over.
+        let mut clocks = 0;
+        while clocks < CLOCKS_PER_FRAME + 1000 {
+            clocks += vm.step().unwrap().0;
+        }
+
+        assert!(vm.state == State::Locked);
+        assert_eq!(0x0101, vm.cpu.pc);
+        assert_eq!(
+            Some(CpuLock {
+                pc: 0x0100,
+                reason: "Illegal opcode D3".to_string()
+            }),
+            *vm.cpu_lock.read().unwrap()
+        );
+        // Interrupts are requested but not served while locked.
+        assert!(is_bit(vm.interrupt_flag, Interrupt::Timer.bit()));
+        assert!(vm
+            .video
+            .read()
+            .unwrap()
+            .display_finished
+            .load(std::sync::atomic::Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_trace_ly_stub() {
+        let mut vm = vm_with_rom(&[]);
+        let path = std::env::temp_dir().join(format!("lameboy_trace_{}.log", std::process::id()));
+        vm.trace = Some(
+            TraceLogger::new(
+                &path.to_string_lossy(),
+                TraceFormat::Doctor,
+                TraceFilter::default(),
+                true,
+            )
+            .unwrap(),
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(0x90, vm.mem_read(MEM_LOC_LY).unwrap());
+        // Only CPU reads are stubbed.
+        assert_eq!(0x00, vm.mem_peek(MEM_LOC_LY).unwrap());
+    }
+
+    #[test]
+    fn test_cpu_lock_in_state() {
+        let mut vm = vm_with_rom(&[]);
+        vm.lock(0x1234, "Bus fault".to_string());
+        let state = vm.save_state();
+
+        *vm.cpu_lock.write().unwrap() = None;
+        vm.state = State::Running;
+        vm.load_state(&state).unwrap();
+        assert!(vm.state == State::Locked);
+        assert_eq!(
+            Some(CpuLock {
+                pc: 0x1234,
+                reason: "Bus fault".to_string()
+            }),
+            *vm.cpu_lock.read().unwrap()
+        );
+    }
+}